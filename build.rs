@@ -0,0 +1,38 @@
+//! Records how wsh was built, for the `version` builtin
+
+use std::env;
+use std::process::Command;
+
+fn main() {
+    let rustc = env::var("RUSTC").unwrap_or_else(|_| "rustc".to_string());
+    let rustc_version = Command::new(rustc)
+        .arg("--version")
+        .output()
+        .ok()
+        .and_then(|output| String::from_utf8(output.stdout).ok())
+        .map(|version| version.trim().to_string())
+        .filter(|version| !version.is_empty())
+        .unwrap_or_else(|| "unknown".to_string());
+
+    // Cargo tells build scripts about each enabled feature as
+    // CARGO_FEATURE_<NAME>, upper-cased with `-` turned into `_`
+    let mut features: Vec<String> = env::vars()
+        .filter_map(|(key, _)| {
+            key.strip_prefix("CARGO_FEATURE_")
+                .map(|name| name.to_lowercase().replace('_', "-"))
+        })
+        .collect();
+    features.sort();
+
+    println!("cargo:rustc-env=WSH_RUSTC_VERSION={}", rustc_version);
+    println!("cargo:rustc-env=WSH_FEATURES={}", features.join(" "));
+    println!(
+        "cargo:rustc-env=WSH_TARGET={}",
+        env::var("TARGET").unwrap_or_default()
+    );
+    println!(
+        "cargo:rustc-env=WSH_PROFILE={}",
+        env::var("PROFILE").unwrap_or_default()
+    );
+    println!("cargo:rerun-if-changed=build.rs");
+}
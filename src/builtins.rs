@@ -0,0 +1,348 @@
+use crate::shell::Shell;
+use anyhow::Result;
+use std::collections::HashMap;
+use std::io::{BufRead, BufReader, Cursor, Read, Write};
+use std::sync::OnceLock;
+
+/// A command the shell runs itself rather than finding in `$PATH`. Every
+/// builtin is listed once in `BUILTINS`, which dispatch, `help` and
+/// completion all read. Input comes from `input` and output goes to `out`
+/// and `err` rather than straight to the shell's own streams, so a builtin
+/// can be redirected or feed a pipeline.
+pub trait Builtin: Sync {
+    fn name(&self) -> &'static str;
+    fn run(
+        &self,
+        shell: &mut Shell,
+        args: &[String],
+        input: &mut dyn Input,
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> Result<i32>;
+    fn help(&self) -> &BuiltinHelp;
+}
+
+/// Where a builtin reads from: the shell's own stdin, a `<` file or
+/// here-document, or the previous stage of a pipeline
+pub trait Input: BufRead {
+    /// Whether this is a terminal, which `read` prompts on
+    fn is_terminal(&self) -> bool {
+        false
+    }
+}
+
+impl<R: Read> Input for BufReader<R> {}
+
+impl<T: AsRef<[u8]>> Input for Cursor<T> {}
+
+/// A builtin's `run` method
+type Run = fn(&mut Shell, &[String], &mut dyn Input, &mut dyn Write, &mut dyn Write) -> Result<i32>;
+
+/// What `help` says about a builtin
+pub struct BuiltinHelp {
+    pub name: &'static str,
+    pub usage: &'static str,
+    /// One line for the `help` listing
+    pub summary: &'static str,
+    /// The rest of `help <name>`, possibly several lines
+    pub details: &'static str,
+}
+
+/// A builtin whose work is done by a `Shell` method
+struct Command {
+    run: Run,
+    help: BuiltinHelp,
+}
+
+impl Builtin for Command {
+    fn name(&self) -> &'static str {
+        self.help.name
+    }
+
+    fn run(
+        &self,
+        shell: &mut Shell,
+        args: &[String],
+        input: &mut dyn Input,
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> Result<i32> {
+        (self.run)(shell, args, input, out, err)
+    }
+
+    fn help(&self) -> &BuiltinHelp {
+        &self.help
+    }
+}
+
+/// Look a builtin up by name, with `.` found under `source`
+pub fn find(name: &str) -> Option<&'static dyn Builtin> {
+    static REGISTRY: OnceLock<HashMap<&str, &dyn Builtin>> = OnceLock::new();
+    let registry = REGISTRY.get_or_init(|| {
+        BUILTINS
+            .iter()
+            .map(|&builtin| (builtin.name(), builtin))
+            .collect()
+    });
+    let name = if name == "." { "source" } else { name };
+    registry.get(name).copied()
+}
+
+/// Whether `name` runs a builtin
+pub fn is_builtin(name: &str) -> bool {
+    find(name).is_some()
+}
+
+/// Every builtin, in the order `help` lists them
+pub static BUILTINS: &[&dyn Builtin] = &[
+    &Command {
+        run: Shell::builtin_cd,
+        help: BuiltinHelp {
+            name: "cd",
+            usage: "cd [dir|-]",
+            summary: "Change directory",
+            details: "With no dir, go home; with -, go back to the previous directory and\n\
+                      print it. A relative dir not found here is looked for in each\n\
+                      directory of $CDPATH.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_pwd,
+        help: BuiltinHelp {
+            name: "pwd",
+            usage: "pwd",
+            summary: "Print the working directory",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_pushd,
+        help: BuiltinHelp {
+            name: "pushd",
+            usage: "pushd [dir]",
+            summary: "Change directory, saving the old one on the stack",
+            details: "With no dir, swap the current directory with the top of the stack.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_popd,
+        help: BuiltinHelp {
+            name: "popd",
+            usage: "popd",
+            summary: "Return to the directory on top of the stack",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_dirs,
+        help: BuiltinHelp {
+            name: "dirs",
+            usage: "dirs",
+            summary: "Show the directory stack",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_history,
+        help: BuiltinHelp {
+            name: "history",
+            usage: "history [N]",
+            summary: "Show, search or edit command history",
+            details: "history N shows the last N entries.\n\
+                      history search <term>... lists entries containing every term.\n\
+                      history clear (or -c) empties the history.\n\
+                      history -d N deletes entry N.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_alias,
+        help: BuiltinHelp {
+            name: "alias",
+            usage: "alias [name[=value]]",
+            summary: "Create or show aliases",
+            details: "With no arguments, list every alias as name='value'; each line can\n\
+                      be given back to alias. `alias ll=ls -la` or `alias ll ls -la`\n\
+                      makes ll run `ls -la`, followed by any arguments given to it.\n\
+                      With just a name, show that alias.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_unalias,
+        help: BuiltinHelp {
+            name: "unalias",
+            usage: "unalias <name>...",
+            summary: "Remove aliases",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_export,
+        help: BuiltinHelp {
+            name: "export",
+            usage: "export [name[=value]]...",
+            summary: "Set or list environment variables",
+            details: "With no arguments, list the environment. A bare name exports the\n\
+                      variable as it is.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_unset,
+        help: BuiltinHelp {
+            name: "unset",
+            usage: "unset <name>...",
+            summary: "Remove shell and environment variables",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_read,
+        help: BuiltinHelp {
+            name: "read",
+            usage: "read [-p prompt] [name...]",
+            summary: "Read a line of input into variables",
+            details: "The line is split into words on $IFS, one per name, with the last\n\
+                      name taking the rest; with no names it all goes into $REPLY. The\n\
+                      variables are the shell's own, not exported. Exits 1 at end of input.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_echo,
+        help: BuiltinHelp {
+            name: "echo",
+            usage: "echo [-neE] [text]...",
+            summary: "Print text",
+            details: "-n leaves off the newline, -e interprets backslash escapes like \\t\n\
+                      and -E (the default) doesn't.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_which,
+        help: BuiltinHelp {
+            name: "which",
+            usage: "which <name>...",
+            summary: "Show how each command resolves",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_type,
+        help: BuiltinHelp {
+            name: "type",
+            usage: "type <name>...",
+            summary: "Describe each name as alias, builtin or file",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_source,
+        help: BuiltinHelp {
+            name: "source",
+            usage: "source [-e] <file>",
+            summary: "Run commands from a file",
+            details: "Each command runs as if typed, and a failing one is reported before\n\
+                      going on; -e stops at the first error instead. Also spelled '.'.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_prefix,
+        help: BuiltinHelp {
+            name: "builtin",
+            usage: "builtin <name> [args]",
+            summary: "Run a builtin, ignoring aliases",
+            details: "Runs the builtin even when an alias of the same name shadows it.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_prefix,
+        help: BuiltinHelp {
+            name: "command",
+            usage: "command <name> [args]",
+            summary: "Run a command, ignoring aliases",
+            details: "Runs name as a builtin or from PATH, skipping alias lookup.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_set,
+        help: BuiltinHelp {
+            name: "set",
+            usage: "set [-+ex] [-+o name]",
+            summary: "Toggle errexit (-e) and xtrace (-x)",
+            details: "-e stops a script at the first failing command and -x prints each\n\
+                      command before it runs; + turns an option off. With no arguments,\n\
+                      show both.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_true,
+        help: BuiltinHelp {
+            name: "true",
+            usage: "true",
+            summary: "Do nothing, successfully",
+            details: "Exits with status 0, ignoring any arguments.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_false,
+        help: BuiltinHelp {
+            name: "false",
+            usage: "false",
+            summary: "Do nothing, unsuccessfully",
+            details: "Exits with status 1, ignoring any arguments.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_rehash,
+        help: BuiltinHelp {
+            name: "rehash",
+            usage: "rehash",
+            summary: "Rescan PATH for completion",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_reload,
+        help: BuiltinHelp {
+            name: "reload",
+            usage: "reload",
+            summary: "Re-read the config file",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_clear,
+        help: BuiltinHelp {
+            name: "clear",
+            usage: "clear",
+            summary: "Clear the screen",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_version,
+        help: BuiltinHelp {
+            name: "version",
+            usage: "version",
+            summary: "Show the wsh version and build details",
+            details: "Prints the version, the Rust compiler wsh was built with, its\n\
+                      target and profile and any enabled features, for bug reports.",
+        },
+    },
+    &Command {
+        run: Shell::builtin_help,
+        help: BuiltinHelp {
+            name: "help",
+            usage: "help [name]",
+            summary: "Show this help, or more about one builtin",
+            details: "",
+        },
+    },
+    &Command {
+        run: Shell::builtin_exit,
+        help: BuiltinHelp {
+            name: "exit",
+            usage: "exit [N]",
+            summary: "Exit the shell",
+            details: "Exit with status N, or that of the last command.",
+        },
+    },
+];
@@ -1,17 +1,270 @@
-use crate::config::Config;
-use crate::utils::Utils;
+use crate::builtins::{self, BUILTINS};
+use crate::config::{CompletionMode, Config};
+use crate::utils::{HistoryEntry, Utils};
 use anyhow::Result;
-use crossterm::{execute, style::Print};
-use std::collections::{HashSet, VecDeque};
-use std::io::stdout;
+use crossterm::{
+    cursor,
+    event::{self, Event, KeyCode, KeyEvent},
+    queue,
+    style::{Attribute, Print, ResetColor, SetAttribute, SetForegroundColor},
+    terminal,
+};
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::fs::FileType;
+use std::io::{Read, Write, stdout};
 use std::path::Path;
+use std::process::{Command, Stdio};
+use std::sync::{Arc, Mutex, MutexGuard, PoisonError, mpsc};
+use std::time::{Duration, Instant};
+use unicode_segmentation::UnicodeSegmentation;
+
+/// A completion candidate: the text Tab inserts, and an optional hint shown
+/// after it in the menu, like an alias's expansion
+#[derive(Debug, Clone, PartialEq)]
+pub struct Candidate {
+    pub text: String,
+    pub hint: Option<String>,
+    /// A file type mark shown after the text in the menu but not inserted,
+    /// like `ls -F`'s `@` for symlinks and `*` for executables
+    pub mark: Option<char>,
+}
+
+impl Candidate {
+    pub fn new(text: impl Into<String>) -> Self {
+        Self {
+            text: text.into(),
+            hint: None,
+            mark: None,
+        }
+    }
+
+    /// How the candidate appears in the menu
+    fn label(&self) -> String {
+        let mut label = self.text.clone();
+        label.extend(self.mark);
+        if let Some(hint) = &self.hint {
+            label = format!("{} → {}", label, hint);
+        }
+        label
+    }
+}
 
 pub struct Completion {
-    pub completions: Vec<String>,
+    pub completions: Vec<Candidate>,
     pub completion_index: Option<usize>,
     pub completion_prefix: String,
     pub original_input_before_completion: String,
     pub completion_start_pos: usize,
+    /// Why there may be fewer candidates than expected, like a directory
+    /// that couldn't be read
+    pub notice: Option<String>,
+    /// Where candidates come from, shared with the threads that generate
+    /// them
+    sources: Arc<Mutex<Sources>>,
+    /// A completion still being generated, if any
+    pending: Option<Pending>,
+}
+
+/// Where candidates come from, with the caches that make asking again
+/// cheap. Only one completion is generated from them at a time.
+#[derive(Default)]
+struct Sources {
+    /// Executables found in `$PATH`, kept across completions
+    path_cache: Option<PathCache>,
+    /// Options scraped from each command's `--help`, by command name
+    flag_cache: HashMap<String, Vec<String>>,
+    /// Why the completion being generated may have fewer candidates than
+    /// expected
+    notice: Option<String>,
+    /// The environment the completion being generated was asked for in
+    env: Environment,
+}
+
+/// The variables completion needs, read on the main thread when it's asked
+/// for. The threads generating completions use this copy and never read
+/// `std::env`, which the shell changes as commands run.
+#[derive(Clone, Default)]
+struct Environment {
+    path: Option<String>,
+    home: Option<String>,
+}
+
+impl Environment {
+    fn current() -> Self {
+        Self {
+            path: std::env::var("PATH").ok(),
+            home: std::env::var("HOME").ok(),
+        }
+    }
+}
+
+/// The candidates for the word before the cursor
+struct Generated {
+    /// The word as typed, which a candidate replaces
+    prefix: String,
+    candidates: Vec<Candidate>,
+    notice: Option<String>,
+}
+
+/// A completion being generated on another thread
+struct Pending {
+    /// The line and cursor it was asked for. If they've changed by the time
+    /// it arrives, it's out of date and dropped.
+    input: String,
+    cursor_pos: usize,
+    receiver: mpsc::Receiver<Generated>,
+}
+
+/// How long a command's `--help` may run when completing its options
+const HELP_TIMEOUT: Duration = Duration::from_millis(500);
+
+/// The most `--help` output read when completing a command's options
+const HELP_MAX_BYTES: u64 = 256 * 1024;
+
+/// The most directory entries looked at when completing a path, so Tab in
+/// a huge directory doesn't hold up the line editor
+const MAX_SCANNED_ENTRIES: usize = 5000;
+
+/// Where a command's arguments are completed from
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum Arguments {
+    /// Files and directories, or `--long` options scraped from `--help`
+    Paths,
+    /// Like `Paths`, leaving out anything that isn't a directory
+    Directories,
+    Aliases,
+    Builtins,
+    /// Anything that runs: builtins, aliases and `$PATH` executables
+    Commands,
+}
+
+/// Commands whose arguments complete from something other than any path
+const ARGUMENT_SPECS: &[(&str, Arguments)] = &[
+    ("cd", Arguments::Directories),
+    ("pushd", Arguments::Directories),
+    ("rmdir", Arguments::Directories),
+    ("alias", Arguments::Aliases),
+    ("unalias", Arguments::Aliases),
+    ("help", Arguments::Builtins),
+    ("which", Arguments::Commands),
+    ("type", Arguments::Commands),
+];
+
+impl Arguments {
+    fn of(command: &str) -> Self {
+        ARGUMENT_SPECS
+            .iter()
+            .find(|(name, _)| *name == command)
+            .map_or(Self::Paths, |&(_, arguments)| arguments)
+    }
+}
+
+/// Subcommands completed for the first argument of these commands. The
+/// config's `subcommands` table adds to them and covers other commands.
+const SUBCOMMAND_SPECS: &[(&str, &[&str])] = &[
+    (
+        "git",
+        &[
+            "add",
+            "bisect",
+            "blame",
+            "branch",
+            "checkout",
+            "cherry-pick",
+            "clean",
+            "clone",
+            "commit",
+            "config",
+            "diff",
+            "fetch",
+            "grep",
+            "init",
+            "log",
+            "merge",
+            "mv",
+            "pull",
+            "push",
+            "rebase",
+            "reflog",
+            "remote",
+            "reset",
+            "restore",
+            "revert",
+            "rm",
+            "show",
+            "stash",
+            "status",
+            "switch",
+            "tag",
+            "worktree",
+        ],
+    ),
+    (
+        "cargo",
+        &[
+            "add",
+            "bench",
+            "build",
+            "check",
+            "clean",
+            "clippy",
+            "doc",
+            "fetch",
+            "fix",
+            "fmt",
+            "init",
+            "install",
+            "new",
+            "publish",
+            "remove",
+            "run",
+            "search",
+            "test",
+            "tree",
+            "uninstall",
+            "update",
+        ],
+    ),
+    (
+        "docker",
+        &[
+            "build",
+            "compose",
+            "container",
+            "cp",
+            "exec",
+            "image",
+            "images",
+            "inspect",
+            "kill",
+            "login",
+            "logs",
+            "network",
+            "ps",
+            "pull",
+            "push",
+            "restart",
+            "rm",
+            "rmi",
+            "run",
+            "start",
+            "stop",
+            "system",
+            "tag",
+            "volume",
+        ],
+    ),
+];
+
+/// How long a `$PATH` scan is trusted before completion rescans it
+const PATH_CACHE_TTL: Duration = Duration::from_secs(60);
+
+/// A scan of the executables in `$PATH`
+struct PathCache {
+    /// The `$PATH` value that was scanned
+    path_var: String,
+    scanned_at: Instant,
+    commands: Vec<String>,
 }
 
 impl Completion {
@@ -22,6 +275,9 @@ impl Completion {
             completion_prefix: String::new(),
             original_input_before_completion: String::new(),
             completion_start_pos: 0,
+            notice: None,
+            sources: Arc::default(),
+            pending: None,
         }
     }
 
@@ -31,63 +287,173 @@ impl Completion {
         self.completion_prefix.clear();
         self.original_input_before_completion.clear();
         self.completion_start_pos = 0;
+        self.notice = None;
+        // Its result will find nobody listening
+        self.pending = None;
     }
 
     pub fn is_empty(&self) -> bool {
         self.completions.is_empty()
     }
 
+    /// Work out the candidates for the word before the cursor, waiting
+    /// for them. The line editor uses `request` instead.
+    #[cfg(test)]
     pub fn generate(
         &mut self,
         input: &str,
         cursor_pos: usize,
         config: &Config,
-        history: &VecDeque<String>,
+        history: &VecDeque<HistoryEntry>,
     ) {
-        let input_before_cursor = &input[..cursor_pos];
-        let tokens = Utils::parse_command(input_before_cursor);
-        let first_token = tokens.first().map(|s| s.as_str()).unwrap_or("");
+        let env = Environment::current();
+        let generated = Self::lock(&self.sources).generate(input, cursor_pos, config, history, env);
+        self.accept(generated);
+    }
 
-        if tokens.is_empty() || (tokens.len() == 1 && !input_before_cursor.ends_with(' ')) {
-            // Command name completion
-            let prefix = first_token;
-            self.completion_prefix = prefix.to_string();
-            self.completions = self.get_command_completions(prefix, config, history);
-        } else {
-            // Argument (path) completion
-            let directories_only = first_token == "cd"; // only dirs for cd
-            let last_token = if input_before_cursor.ends_with(' ') {
-                ""
-            } else {
-                tokens.last().map(|s| s.as_str()).unwrap_or("")
-            };
-            self.completion_prefix = last_token.to_string();
-            self.completions = self.get_path_completions(last_token, directories_only);
+    /// Like `generate`, but on another thread, so a slow `$PATH` or
+    /// directory scan doesn't hold up typing. `poll` picks up the result.
+    pub fn request(
+        &mut self,
+        input: &str,
+        cursor_pos: usize,
+        config: &Config,
+        history: &VecDeque<HistoryEntry>,
+    ) {
+        let (sender, receiver) = mpsc::channel();
+        let sources = Arc::clone(&self.sources);
+        let line = input.to_string();
+        let config = config.clone();
+        let history = history.clone();
+        let env = Environment::current();
+        std::thread::spawn(move || {
+            let generated =
+                Self::lock(&sources).generate(&line, cursor_pos, &config, &history, env);
+            // If the request was dropped meanwhile, nobody is listening
+            let _ = sender.send(generated);
+        });
+        self.pending = Some(Pending {
+            input: input.to_string(),
+            cursor_pos,
+            receiver,
+        });
+    }
+
+    /// Whether a requested completion hasn't arrived yet
+    pub fn is_pending(&self) -> bool {
+        self.pending.is_some()
+    }
+
+    /// Wait up to `timeout` for the requested completion, returning true
+    /// once its candidates are in place. One asked for with a different
+    /// line or cursor than `input` and `cursor_pos` is dropped.
+    pub fn poll(&mut self, input: &str, cursor_pos: usize, timeout: Duration) -> bool {
+        let Some(pending) = self.pending.take() else {
+            return false;
+        };
+        match pending.receiver.recv_timeout(timeout) {
+            Ok(generated) if pending.input == input && pending.cursor_pos == cursor_pos => {
+                self.accept(generated);
+                true
+            }
+            Err(mpsc::RecvTimeoutError::Timeout) => {
+                self.pending = Some(pending);
+                false
+            }
+            // Out of date, or the thread generating it panicked
+            _ => false,
         }
     }
 
-    pub fn apply(&mut self, input: &mut String, cursor_pos: &mut usize) -> Result<()> {
-        if let Some(index) = self.completion_index {
-            if let Some(completion) = self.completions.get(index) {
-                // Restore original input and apply the selected completion
-                *input = self.original_input_before_completion.clone();
+    /// Offer generated candidates
+    fn accept(&mut self, generated: Generated) {
+        self.completion_prefix = generated.prefix;
+        self.completions = generated.candidates;
+        self.notice = generated.notice;
+    }
+
+    /// Forget the cached `$PATH` scan so the next completion rescans
+    pub fn rehash(&mut self) {
+        Self::lock(&self.sources).path_cache = None;
+    }
 
+    /// Lock the sources, even if a thread panicked holding them: the
+    /// caches are only ever replaced whole
+    fn lock(sources: &Mutex<Sources>) -> MutexGuard<'_, Sources> {
+        sources.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn apply(&mut self, input: &mut String, cursor_pos: &mut usize) -> Result<()> {
+        let Some(index) = self.completion_index else {
+            return Ok(());
+        };
+        // Start again from the original input
+        let mut line = self.original_input_before_completion.clone();
+        let range =
+            self.completion_start_pos..self.completion_start_pos + self.completion_prefix.len();
+        match self.completions.get(index) {
+            Some(Candidate {
+                text: completion, ..
+            }) if line.get(range.clone()).is_some() => {
                 // Replace the prefix with the completion
-                let end_pos = self.completion_start_pos + self.completion_prefix.len();
-                input.replace_range(self.completion_start_pos..end_pos, completion);
+                line.replace_range(range, completion);
                 *cursor_pos = self.completion_start_pos + completion.len();
+                *input = line;
             }
+            // The candidates changed under the selection, so there's
+            // nothing valid to apply; drop the completion and keep the line
+            _ => self.reset(),
         }
         Ok(())
     }
 
-    pub fn cycle_next(&mut self) {
-        if let Some(current_index) = self.completion_index {
-            let next_index = (current_index + 1) % self.completions.len();
-            self.completion_index = Some(next_index);
+    /// The longest prefix shared by every candidate
+    pub fn common_prefix(&self) -> String {
+        let Some((first, rest)) = self.completions.split_first() else {
+            return String::new();
+        };
+        let first = &first.text;
+        let mut len = first.len();
+        for Candidate {
+            text: completion, ..
+        } in rest
+        {
+            len = first
+                .char_indices()
+                .zip(completion.chars())
+                .find(|((_, a), b)| a != b)
+                .map(|((i, _), _)| i)
+                .unwrap_or_else(|| first.len().min(completion.len()))
+                .min(len);
         }
+        first[..len].to_string()
     }
 
+    /// Replace the prefix being completed with the candidates' common
+    /// prefix. Returns `false`, leaving the input alone, if that wouldn't
+    /// add anything.
+    pub fn apply_common_prefix(&self, input: &mut String, cursor_pos: &mut usize) -> bool {
+        let common = self.common_prefix();
+        if common.len() <= self.completion_prefix.len() {
+            return false;
+        }
+        let range =
+            self.completion_start_pos..self.completion_start_pos + self.completion_prefix.len();
+        if input.get(range.clone()).is_none() {
+            return false;
+        }
+        input.replace_range(range, &common);
+        *cursor_pos = self.completion_start_pos + common.len();
+        true
+    }
+
+    pub fn cycle_next(&mut self) {
+        self.step(1);
+    }
+
+    /// Begin cycling through the candidates. Only the word before the
+    /// cursor is replaced, so text after the cursor, even in the same
+    /// word, is left as it is.
     pub fn start(&mut self, input: &str, cursor_pos: usize) {
         self.original_input_before_completion = input.to_string();
         let prefix_len = self.completion_prefix.len();
@@ -95,184 +461,1274 @@ impl Completion {
         self.completion_index = Some(0);
     }
 
+    pub fn cycle_prev(&mut self) {
+        self.step(-1);
+    }
+
+    /// Move the selection by `delta` places in the menu. Left and right
+    /// wrap around; a move up or down off the grid stays put. With no
+    /// candidates left to move through, completion starts over.
+    pub fn step(&mut self, delta: isize) {
+        let Some(index) = self.completion_index else {
+            return;
+        };
+        if self.completions.is_empty() {
+            self.reset();
+            return;
+        }
+        let len = self.completions.len() as isize;
+        let target = index as isize + delta;
+        self.completion_index = Some(if delta.abs() == 1 {
+            target.rem_euclid(len) as usize
+        } else if (0..len).contains(&target) {
+            target as usize
+        } else {
+            index
+        });
+    }
+
+    /// Put back the input as it was before completion started
+    pub fn restore(&self, input: &mut String, cursor_pos: &mut usize) {
+        *input = self.original_input_before_completion.clone();
+        *cursor_pos = self.completion_start_pos + self.completion_prefix.len();
+    }
+
+    /// Whether the candidate menu is open: there's a choice to cycle through
     pub fn should_show_info(&self) -> bool {
-        self.completions.len() > 1
+        self.completions.len() > 1 && self.completion_index.is_some()
     }
 
-    pub fn show_info(&self) -> Result<()> {
-        if self.completions.len() <= 1 {
-            return Ok(());
+    /// Whether the menu is drawn under the input, which it is while open
+    /// unless the config turns it off
+    pub fn shows_menu(&self, config: &Config) -> bool {
+        config.completion_menu && self.should_show_info()
+    }
+
+    /// How many columns the menu grid has at the current terminal width
+    pub fn menu_columns(&self) -> Result<usize> {
+        let (width, _) = terminal::size()?;
+        Ok(Self::grid_columns(&self.completions, width as usize))
+    }
+
+    /// Lay candidates out in as many equal columns as fit in `width`,
+    /// filled row by row
+    fn grid_columns(candidates: &[Candidate], width: usize) -> usize {
+        let cell = Self::cell_width(candidates);
+        (width / cell).clamp(1, candidates.len().max(1))
+    }
+
+    /// The widest candidate plus two columns of padding
+    fn cell_width(candidates: &[Candidate]) -> usize {
+        candidates
+            .iter()
+            .map(|candidate| Utils::display_width(&candidate.label()))
+            .max()
+            .unwrap_or(0)
+            + 2
+    }
+
+    /// Why the last `generate` may have missed something, like a directory
+    /// too big to scan in full
+    pub fn notice(&self) -> Option<&str> {
+        self.notice.as_deref()
+    }
+
+    /// Show a notice from `generate` on the line below the input
+    pub fn show_notice(config: &Config, notice: &str) -> Result<()> {
+        Self::show_below(config, &format!("({})", notice))
+    }
+
+    /// Whether there are too many candidates to list in the menu without
+    /// asking first
+    pub fn exceeds_limit(&self, config: &Config) -> bool {
+        config.completion_menu
+            && config.completion_limit > 0
+            && self.completions.len() > config.completion_limit
+    }
+
+    /// Ask whether to list all the candidates, like bash, and wait for the
+    /// answer. Anything but `y` or space declines.
+    pub fn confirm_long_list(&self, config: &Config) -> Result<bool> {
+        let question = format!(
+            "Display all {} possibilities? (y/n)",
+            self.completions.len()
+        );
+        Self::show_below(config, &question)?;
+        loop {
+            if let Event::Key(KeyEvent { code, .. }) = event::read()? {
+                return Ok(matches!(code, KeyCode::Char('y' | 'Y' | ' ')));
+            }
         }
+    }
 
-        execute!(
-            stdout(),
-            Print(format!(
-                "\nCompletions ({}/{}):\n",
-                self.completion_index.map(|i| i + 1).unwrap_or(0),
-                self.completions.len()
-            ))
+    /// Write a dimmed line of text under the input line, leaving the
+    /// cursor where it was. The next redraw clears it.
+    fn show_below(config: &Config, text: &str) -> Result<()> {
+        let mut out = stdout();
+        queue!(
+            out,
+            Print("\n"),
+            cursor::MoveUp(1),
+            cursor::SavePosition,
+            Print("\r\n")
         )?;
+        if config.use_colors() {
+            queue!(out, SetForegroundColor(config.colors.suggestion()))?;
+        }
+        queue!(out, Print(text), ResetColor, cursor::RestorePosition)?;
+        out.flush()?;
+        Ok(())
+    }
 
-        let max_display = 10;
-        let start_idx = if self.completions.len() <= max_display {
-            0
-        } else {
-            let current = self.completion_index.unwrap_or(0);
-            if current < max_display / 2 {
-                0
-            } else if current > self.completions.len() - max_display / 2 {
-                self.completions.len() - max_display
-            } else {
-                current - max_display / 2
-            }
-        };
+    /// Draw the candidates in a grid under the input line, highlighting the
+    /// selected one, then put the cursor back where it was. Long lists
+    /// show the rows around the selection, and a notice goes under them.
+    /// The next `redraw_line` clears the menu away.
+    ///
+    /// Room for the menu is made first with bare line feeds, which in raw
+    /// mode move down without leaving the cursor's column. Coming back up,
+    /// the saved position then can't be thrown off by the screen
+    /// scrolling, and the terminal never has to be asked where the cursor is.
+    pub fn show_info(&self, config: &Config) -> Result<()> {
+        if !self.shows_menu(config) {
+            return Ok(());
+        }
 
-        for (i, completion) in self
-            .completions
-            .iter()
-            .enumerate()
-            .skip(start_idx)
-            .take(max_display)
-        {
-            let marker = if Some(i) == self.completion_index {
-                ">"
-            } else {
-                " "
-            };
-            execute!(stdout(), Print(format!("  {}{}\n", marker, completion)))?;
+        let (width, height) = terminal::size()?;
+        let width = width as usize;
+        // Some terminals (and ptys nobody sized) report no size at all
+        if width < 2 || height == 0 {
+            return Ok(());
         }
+        let columns = Self::grid_columns(&self.completions, width);
+        let cell = Self::cell_width(&self.completions).min(width);
+        let rows = self.completions.len().div_ceil(columns);
+
+        // Keep the menu to half the screen, scrolled to the selection
+        let max_rows = (height as usize / 2).max(1);
+        let selected = self.completion_index.unwrap_or(0);
+        let first_row = (selected / columns).saturating_sub(max_rows - 1);
+        let shown_rows = rows.min(max_rows);
+        let lines =
+            shown_rows + usize::from(rows > shown_rows) + usize::from(self.notice.is_some());
 
-        if self.completions.len() > max_display {
-            execute!(
-                stdout(),
+        let mut out = stdout();
+        queue!(
+            out,
+            Print("\n".repeat(lines)),
+            cursor::MoveUp(lines as u16),
+            cursor::SavePosition
+        )?;
+        for row in first_row..first_row + shown_rows {
+            queue!(out, Print("\r\n"))?;
+            for column in 0..columns {
+                let index = row * columns + column;
+                let Some(candidate) = self.completions.get(index) else {
+                    break;
+                };
+                let text = Self::fit(&candidate.label(), cell - 1);
+                let padding = " ".repeat(cell - 1 - Utils::display_width(&text));
+                if index == selected {
+                    if config.use_colors() {
+                        queue!(out, SetForegroundColor(config.colors.completion_marker()))?;
+                    }
+                    queue!(
+                        out,
+                        SetAttribute(Attribute::Reverse),
+                        Print(&text),
+                        SetAttribute(Attribute::Reset),
+                        ResetColor
+                    )?;
+                } else {
+                    queue!(out, Print(&text))?;
+                }
+                // The last column's padding would wrap a full-width row
+                if column + 1 < columns {
+                    queue!(out, Print(format!("{} ", padding)))?;
+                }
+            }
+        }
+        if rows > shown_rows {
+            queue!(
+                out,
                 Print(format!(
-                    "  ... ({} more)\n",
-                    self.completions.len() - max_display
+                    "\r\n... ({}/{})",
+                    selected + 1,
+                    self.completions.len()
                 ))
             )?;
         }
+        if let Some(notice) = &self.notice {
+            queue!(out, Print("\r\n"))?;
+            if config.use_colors() {
+                queue!(out, SetForegroundColor(config.colors.suggestion()))?;
+            }
+            queue!(
+                out,
+                Print(Self::fit(&format!("({})", notice), width)),
+                ResetColor
+            )?;
+        }
 
+        queue!(out, cursor::RestorePosition)?;
+        out.flush()?;
         Ok(())
     }
 
-    fn get_command_completions(
-        &self,
+    /// Cut `text` down to at most `width` columns
+    fn fit(text: &str, width: usize) -> String {
+        let mut fitted = String::new();
+        for grapheme in text.graphemes(true) {
+            if Utils::display_width(&fitted) + Utils::display_width(grapheme) > width {
+                break;
+            }
+            fitted.push_str(grapheme);
+        }
+        fitted
+    }
+
+    /// List the executables in each directory of a `$PATH` value. What
+    /// keeps this quick is that a name already found earlier in `$PATH` is
+    /// skipped before it's stat'ed; every new name still costs one `stat`,
+    /// following the link for symlinks. Names that aren't valid UTF-8 are
+    /// converted lossily so they still show up, though one with a
+    /// replacement character won't run as completed.
+    fn scan_path(path_var: &str) -> Vec<String> {
+        let mut commands = Vec::new();
+        let mut seen = HashSet::new();
+        for path_dir in path_var.split(':') {
+            let Ok(entries) = std::fs::read_dir(path_dir) else {
+                continue;
+            };
+            for entry in entries.flatten() {
+                let name = entry.file_name().to_string_lossy().into_owned();
+                if seen.contains(&name) {
+                    continue;
+                }
+                let metadata = match entry.file_type() {
+                    Ok(file_type) if file_type.is_symlink() => std::fs::metadata(entry.path()),
+                    Ok(file_type) if file_type.is_file() => entry.metadata(),
+                    _ => continue,
+                };
+                if let Ok(metadata) = metadata
+                    && metadata.is_file()
+                    && Utils::has_execute_bit(&metadata)
+                {
+                    seen.insert(name.clone());
+                    commands.push(name);
+                }
+            }
+        }
+        commands
+    }
+
+    /// Builtin names matching `prefix`, scored
+    fn builtin_candidates(prefix: &str, mode: CompletionMode) -> Vec<(usize, Candidate)> {
+        BUILTINS
+            .iter()
+            .filter_map(|builtin| {
+                let score = Self::match_score(mode, prefix, builtin.name())?;
+                Some((score, Candidate::new(builtin.name())))
+            })
+            .collect()
+    }
+
+    /// Alias names matching `prefix`, scored, with what they expand to
+    fn alias_candidates(prefix: &str, config: &Config) -> Vec<(usize, Candidate)> {
+        config
+            .aliases
+            .iter()
+            .filter_map(|(alias, expansion)| {
+                let score = Self::match_score(config.completion_mode, prefix, alias)?;
+                let candidate = Candidate {
+                    hint: Some(expansion.clone()),
+                    ..Candidate::new(alias.clone())
+                };
+                Some((score, candidate))
+            })
+            .collect()
+    }
+
+    /// Subcommands of `command` matching `prefix`, scored, from
+    /// `SUBCOMMAND_SPECS` and the config's `subcommands`
+    fn subcommand_candidates(
+        command: &str,
         prefix: &str,
         config: &Config,
-        history: &VecDeque<String>,
-    ) -> Vec<String> {
-        let mut completions = Vec::new();
+    ) -> Vec<(usize, Candidate)> {
+        let built_in = SUBCOMMAND_SPECS
+            .iter()
+            .filter(|(name, _)| *name == command)
+            .flat_map(|(_, subcommands)| subcommands.iter().copied());
+        let configured = config
+            .subcommands
+            .get(command)
+            .into_iter()
+            .flatten()
+            .map(String::as_str);
+        built_in
+            .chain(configured)
+            .filter_map(|subcommand| {
+                let score = Self::match_score(config.completion_mode, prefix, subcommand)?;
+                Some((score, Candidate::new(subcommand)))
+            })
+            .collect()
+    }
+
+    /// Run `command --help` and capture its output, giving up if it takes
+    /// longer than `HELP_TIMEOUT` and keeping at most `HELP_MAX_BYTES`
+    fn read_help(command: &str, env: &Environment) -> Option<String> {
+        let path = Utils::find_in_path_from(command, env.path.as_deref(), env.home.as_deref())?;
+        let mut child = Command::new(path)
+            .arg("--help")
+            .stdin(Stdio::null())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::null())
+            .spawn()
+            .ok()?;
+
+        // Read on another thread so a hung command (or a pager it started
+        // that holds the pipe open) can't block us; once the cap is reached
+        // the pipe is dropped and the command gets SIGPIPE
+        let stdout = child.stdout.take()?;
+        let (sender, receiver) = mpsc::channel();
+        std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = stdout.take(HELP_MAX_BYTES).read_to_end(&mut output);
+            let _ = sender.send(output);
+        });
+
+        let output = receiver.recv_timeout(HELP_TIMEOUT);
+        let _ = child.kill();
+        let _ = child.wait();
+        Some(String::from_utf8_lossy(&output.ok()?).into_owned())
+    }
 
-        // Built-in commands
-        let builtins = ["cd", "pwd", "exit", "help", "alias", "history"];
-        for builtin in &builtins {
-            if builtin.starts_with(prefix) {
-                completions.push(builtin.to_string());
+    /// Pull the `--long-option` names out of help text
+    fn scrape_flags(help: &str) -> Vec<String> {
+        let mut flags: Vec<String> = help
+            .split(|c: char| !(c.is_ascii_alphanumeric() || c == '-' || c == '_'))
+            .filter_map(|word| {
+                let name = word.strip_prefix("--")?.trim_end_matches('-');
+                name.starts_with(|c: char| c.is_ascii_alphanumeric())
+                    .then(|| format!("--{}", name))
+            })
+            .collect();
+        flags.sort();
+        flags.dedup();
+        flags
+    }
+
+    /// How well `candidate` matches the typed text under `mode`, or `None`
+    /// if it doesn't match at all. Lower scores are better matches.
+    fn match_score(mode: CompletionMode, typed: &str, candidate: &str) -> Option<usize> {
+        match mode {
+            CompletionMode::Prefix => candidate.starts_with(typed).then_some(0),
+            CompletionMode::Ci => candidate
+                .to_lowercase()
+                .starts_with(&typed.to_lowercase())
+                .then_some(0),
+            CompletionMode::Fuzzy => {
+                // Score by how spread out the matched characters are, so
+                // `dl` ranks `dl.sh` above `Downloads`
+                let mut score = 0;
+                let mut candidate_chars = candidate.chars().flat_map(char::to_lowercase);
+                for typed_char in typed.chars().flat_map(char::to_lowercase) {
+                    let skipped = candidate_chars.position(|c| c == typed_char)?;
+                    score += skipped;
+                }
+                Some(score)
             }
         }
+    }
 
-        // Aliases
-        for alias in config.aliases.keys() {
-            if alias.starts_with(prefix) {
-                completions.push(alias.clone());
+    /// Order scored candidates best match first, then alphabetically,
+    /// dropping duplicates
+    fn rank(mut completions: Vec<(usize, Candidate)>) -> Vec<Candidate> {
+        // Of duplicates, the one with a hint (an alias shadowing a command
+        // of the same name) sorts first and is kept
+        completions.sort_by(|(a_score, a), (b_score, b)| {
+            (a_score, &a.text, a.hint.is_none()).cmp(&(b_score, &b.text, b.hint.is_none()))
+        });
+        let mut ranked: Vec<Candidate> = Vec::with_capacity(completions.len());
+        let mut seen = HashSet::new();
+        for (_, completion) in completions {
+            if seen.insert(completion.text.clone()) {
+                ranked.push(completion);
             }
         }
+        ranked
+    }
+}
 
-        // Commands in PATH
-        if let Ok(path_var) = std::env::var("PATH") {
-            let mut seen = HashSet::new();
-            for path_dir in path_var.split(':') {
-                if let Ok(entries) = std::fs::read_dir(path_dir) {
-                    for entry in entries.flatten() {
-                        if let Ok(file_type) = entry.file_type() {
-                            if file_type.is_file() {
-                                if let Some(name) = entry.file_name().to_str() {
-                                    if name.starts_with(prefix) && !seen.contains(name) {
-                                        // Check if file is executable
-                                        if Utils::is_executable(&entry.path()) {
-                                            completions.push(name.to_string());
-                                            seen.insert(name.to_string());
-                                        }
-                                    }
-                                }
-                            }
-                        }
-                    }
-                }
+impl Sources {
+    /// Work out the candidates for the word before the cursor in `input`
+    fn generate(
+        &mut self,
+        input: &str,
+        cursor_pos: usize,
+        config: &Config,
+        history: &VecDeque<HistoryEntry>,
+        env: Environment,
+    ) -> Generated {
+        let input_before_cursor = &input[..cursor_pos];
+        // The word as typed is what gets replaced; it's matched against
+        // candidates with its quotes and escapes removed
+        let word_start = Utils::current_word_start(input_before_cursor);
+        let raw_word = &input_before_cursor[word_start..];
+        let word = Utils::parse_command(raw_word).pop().unwrap_or_default();
+        let tokens = Utils::parse_command(&input_before_cursor[..word_start]);
+        self.notice = None;
+        self.env = env;
+
+        let completions = match tokens.first() {
+            // Command name completion
+            None => self.get_command_completions(&word, config, history),
+            Some(first_token) => {
+                self.get_argument_completions(first_token, tokens.len(), &word, config)
             }
+        };
+        // Candidates go in escaped so the line still parses to the same words
+        let candidates = completions
+            .into_iter()
+            .map(|candidate| Candidate {
+                text: Utils::escape_word(&candidate.text),
+                ..candidate
+            })
+            .collect();
+        Generated {
+            prefix: raw_word.to_string(),
+            candidates,
+            notice: self.notice.take(),
         }
+    }
+
+    /// Executable names found in `$PATH`, rescanned only when `$PATH`
+    /// changes, the scan is older than `PATH_CACHE_TTL`, or after `rehash`
+    fn path_commands(&mut self) -> &[String] {
+        let path_var = self.env.path.clone().unwrap_or_default();
+        let stale = match &self.path_cache {
+            Some(cache) => {
+                cache.path_var != path_var || cache.scanned_at.elapsed() > PATH_CACHE_TTL
+            }
+            None => true,
+        };
+        if stale {
+            self.path_cache = Some(PathCache {
+                commands: Completion::scan_path(&path_var),
+                path_var,
+                scanned_at: Instant::now(),
+            });
+        }
+        self.path_cache
+            .as_ref()
+            .map(|cache| cache.commands.as_slice())
+            .unwrap_or_default()
+    }
+
+    fn get_command_completions(
+        &mut self,
+        prefix: &str,
+        config: &Config,
+        history: &VecDeque<HistoryEntry>,
+    ) -> Vec<Candidate> {
+        let mode = config.completion_mode;
+        let mut completions = Completion::builtin_candidates(prefix, mode);
+        completions.extend(Completion::alias_candidates(prefix, config));
+        completions.extend(self.path_candidates(prefix, mode));
 
         // History-based completions
-        for cmd in history {
-            let cmd_tokens = Utils::parse_command(cmd);
-            if let Some(first_token) = cmd_tokens.first() {
-                if first_token.starts_with(prefix) && !completions.contains(first_token) {
-                    completions.push(first_token.clone());
-                }
+        for entry in history {
+            let cmd_tokens = Utils::parse_command(&entry.command);
+            if let Some(first_token) = cmd_tokens.first()
+                && let Some(score) = Completion::match_score(mode, prefix, first_token)
+            {
+                completions.push((score, Candidate::new(first_token.clone())));
+            }
+        }
+
+        Completion::rank(completions)
+    }
+
+    /// Complete argument number `position` of `command`: the first from
+    /// its subcommands, if any match, and otherwise from the source its
+    /// entry in `ARGUMENT_SPECS` names, or paths if it has none
+    fn get_argument_completions(
+        &mut self,
+        command: &str,
+        position: usize,
+        word: &str,
+        config: &Config,
+    ) -> Vec<Candidate> {
+        if position == 1 && !word.starts_with('-') {
+            let subcommands = Completion::subcommand_candidates(command, word, config);
+            if !subcommands.is_empty() {
+                return Completion::rank(subcommands);
             }
         }
 
-        completions.sort();
-        completions.dedup();
+        let mode = config.completion_mode;
+        let directories_only = match Arguments::of(command) {
+            Arguments::Paths => false,
+            Arguments::Directories => true,
+            Arguments::Aliases => {
+                return Completion::rank(Completion::alias_candidates(word, config));
+            }
+            Arguments::Builtins => {
+                return Completion::rank(Completion::builtin_candidates(word, mode));
+            }
+            Arguments::Commands => {
+                let mut completions = Completion::builtin_candidates(word, mode);
+                completions.extend(Completion::alias_candidates(word, config));
+                completions.extend(self.path_candidates(word, mode));
+                return Completion::rank(completions);
+            }
+        };
+
+        let mut completions = if word.starts_with('-') {
+            self.get_flag_completions(command, word, mode)
+        } else {
+            Vec::new()
+        };
+        // Fall back to paths when no flags are known, e.g. `rm -- -file`
+        if completions.is_empty() {
+            completions = self.get_path_completions(word, directories_only, config);
+        }
         completions
     }
 
-    fn get_path_completions(&self, prefix: &str, directories_only: bool) -> Vec<String> {
+    /// Executables in `$PATH` matching `prefix`, scored
+    fn path_candidates(&mut self, prefix: &str, mode: CompletionMode) -> Vec<(usize, Candidate)> {
+        self.path_commands()
+            .iter()
+            .filter_map(|name| {
+                let score = Completion::match_score(mode, prefix, name)?;
+                Some((score, Candidate::new(name.clone())))
+            })
+            .collect()
+    }
+
+    /// Complete `--long` options for `command`, scraped from its `--help`
+    fn get_flag_completions(
+        &mut self,
+        command: &str,
+        prefix: &str,
+        mode: CompletionMode,
+    ) -> Vec<Candidate> {
+        if builtins::is_builtin(command) {
+            return Vec::new();
+        }
+        let flags = self
+            .flag_cache
+            .entry(command.to_string())
+            .or_insert_with(|| {
+                Completion::read_help(command, &self.env)
+                    .map(|help| Completion::scrape_flags(&help))
+                    .unwrap_or_default()
+            });
+
+        let completions = flags
+            .iter()
+            .filter_map(|flag| {
+                Some((
+                    Completion::match_score(mode, prefix, flag)?,
+                    Candidate::new(flag.clone()),
+                ))
+            })
+            .collect();
+        Completion::rank(completions)
+    }
+
+    fn get_path_completions(
+        &mut self,
+        prefix: &str,
+        directories_only: bool,
+        config: &Config,
+    ) -> Vec<Candidate> {
+        let mode = config.completion_mode;
         let mut completions = Vec::new();
-        let expanded_prefix = Utils::expand_path(prefix);
+        let expanded_prefix = Utils::expand_path_from(prefix, self.env.home.as_deref());
 
-        let (dir_path, file_prefix) = if expanded_prefix.ends_with('/') {
-            (expanded_prefix.as_str(), "")
-        } else {
-            let path = Path::new(&expanded_prefix);
-            if let Some(parent) = path.parent() {
-                let parent_str = parent.to_str().unwrap_or(".");
-                // If parent is empty string, use current directory
-                let dir_path = if parent_str.is_empty() {
-                    "."
-                } else {
-                    parent_str
-                };
-                (
-                    dir_path,
-                    path.file_name().and_then(|n| n.to_str()).unwrap_or(""),
-                )
-            } else {
-                (".", expanded_prefix.as_str())
-            }
+        // Split at the last slash by hand: `Path` would drop a trailing
+        // `/.`, which is how hidden entries in a subdirectory are asked for
+        let (shown_dir, file_prefix) = match expanded_prefix.rfind('/') {
+            Some(i) => expanded_prefix.split_at(i + 1),
+            None => ("", expanded_prefix.as_str()),
         };
+        let dir_path = if shown_dir.is_empty() { "." } else { shown_dir };
 
-        if let Ok(entries) = std::fs::read_dir(dir_path) {
-            for entry in entries.flatten() {
-                if let Some(name) = entry.file_name().to_str() {
-                    let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
-                    if directories_only && !is_dir {
-                        continue;
-                    }
-                    // Show hidden files only if prefix starts with dot
-                    if name.starts_with(file_prefix)
-                        && (!name.starts_with('.') || file_prefix.starts_with('.'))
-                    {
-                        let mut completion = if dir_path == "." {
-                            name.to_string()
-                        } else if dir_path.ends_with('/') {
-                            format!("{}{}", dir_path, name)
-                        } else {
-                            format!("{}/{}", dir_path, name)
-                        };
-
-                        // Add trailing slash for directories
-                        if is_dir {
-                            completion.push('/');
-                        }
-
-                        completions.push(completion);
-                    }
+        let entries = match std::fs::read_dir(dir_path) {
+            Ok(entries) => entries,
+            Err(e) => {
+                // A missing directory is just a word that matches nothing
+                if e.kind() != std::io::ErrorKind::NotFound {
+                    self.notice = Some(format!("can't read {}: {}", dir_path, e.kind()));
                 }
+                return Vec::new();
+            }
+        };
+        let mut truncated = false;
+        for (scanned, entry) in entries.flatten().enumerate() {
+            if scanned == MAX_SCANNED_ENTRIES {
+                self.notice = Some(format!(
+                    "only the first {} entries of {} were searched",
+                    MAX_SCANNED_ENTRIES, dir_path
+                ));
+                truncated = true;
+                break;
             }
+            let file_name = entry.file_name();
+            let Some(name) = file_name.to_str() else {
+                continue;
+            };
+            // Show hidden files only if prefix starts with dot
+            let Some(score) = Completion::match_score(mode, file_prefix, name)
+                .filter(|_| !name.starts_with('.') || file_prefix.starts_with('.'))
+            else {
+                continue;
+            };
+            let candidate = Self::path_candidate(
+                shown_dir,
+                name,
+                &entry.path(),
+                entry.file_type().ok(),
+                directories_only,
+                config,
+            );
+            completions.extend(candidate.map(|candidate| (score, candidate)));
         }
 
-        completions.sort();
-        completions
+        // A name typed out in full picks out its entry without a scan, so
+        // it still completes when the scan stopped before reaching it
+        let full_name = format!("{}{}", shown_dir, file_prefix);
+        if truncated
+            && !file_prefix.is_empty()
+            && !completions
+                .iter()
+                .any(|(_, candidate)| candidate.text.trim_end_matches('/') == full_name)
+        {
+            let path = Path::new(dir_path).join(file_prefix);
+            if let Ok(metadata) = std::fs::symlink_metadata(&path) {
+                let candidate = Self::path_candidate(
+                    shown_dir,
+                    file_prefix,
+                    &path,
+                    Some(metadata.file_type()),
+                    directories_only,
+                    config,
+                );
+                completions.extend(candidate.map(|candidate| (0, candidate)));
+            }
+        }
+
+        Completion::rank(completions)
+    }
+
+    /// The candidate for the entry `name` at `path`, or `None` if only
+    /// directories are wanted and it isn't one
+    fn path_candidate(
+        shown_dir: &str,
+        name: &str,
+        path: &Path,
+        file_type: Option<FileType>,
+        directories_only: bool,
+        config: &Config,
+    ) -> Option<Candidate> {
+        // Only matches are stat'ed, which is what's slow in a big
+        // directory. Follow symlinks, so a link to a directory counts
+        // as one; a link that dangles or loops back on itself fails to
+        // stat and is left as a plain file, never descended into.
+        let is_link = file_type.is_some_and(|ft| ft.is_symlink());
+        let is_dir = file_type.is_some_and(|ft| ft.is_dir())
+            || std::fs::metadata(path).is_ok_and(|m| m.is_dir());
+        if directories_only && !is_dir {
+            return None;
+        }
+        let mut candidate = Candidate::new(format!("{}{}", shown_dir, name));
+
+        // Add trailing slash for directories
+        if is_dir {
+            candidate.text.push('/');
+        } else if config.completion_marks {
+            if is_link {
+                candidate.mark = Some('@');
+            } else if Utils::is_executable(path) {
+                candidate.mark = Some('*');
+            }
+        }
+        Some(candidate)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Candidate, Completion, Environment, Generated, MAX_SCANNED_ENTRIES, Sources};
+    use crate::config::Config;
+    use crate::utils::Utils;
+    use std::collections::VecDeque;
+    use std::time::Duration;
+
+    fn with_candidates(candidates: &[&str]) -> Completion {
+        let mut completion = Completion::new();
+        completion.completions = candidates.iter().map(|c| Candidate::new(*c)).collect();
+        completion
+    }
+
+    #[test]
+    fn menu_grid_fits_the_terminal_width() {
+        let candidates = with_candidates(&["a", "bb", "cccc", "d", "e"]).completions;
+        // Cells are the widest candidate plus two columns of padding
+        assert_eq!(Completion::grid_columns(&candidates, 80), 5);
+        assert_eq!(Completion::grid_columns(&candidates, 13), 2);
+        assert_eq!(Completion::grid_columns(&candidates, 3), 1);
+        assert_eq!(Completion::fit("completion", 4), "comp");
+        assert_eq!(Completion::fit("日本語", 5), "日本");
+    }
+
+    #[test]
+    fn menu_selection_moves_through_the_grid() {
+        let mut completion = with_candidates(&["a", "b", "c", "d", "e"]);
+        completion.completion_index = Some(0);
+        completion.step(-1);
+        assert_eq!(completion.completion_index, Some(4));
+        completion.step(1);
+        assert_eq!(completion.completion_index, Some(0));
+        // With two columns, down moves two places and stops at the last row
+        completion.step(2);
+        completion.step(2);
+        assert_eq!(completion.completion_index, Some(4));
+        completion.step(2);
+        assert_eq!(completion.completion_index, Some(4));
+        completion.cycle_prev();
+        assert_eq!(completion.completion_index, Some(3));
+    }
+
+    #[test]
+    fn common_prefix_of_single_candidate_is_the_candidate() {
+        assert_eq!(with_candidates(&["cargo"]).common_prefix(), "cargo");
+    }
+
+    #[test]
+    fn common_prefix_of_identical_candidates() {
+        assert_eq!(with_candidates(&["src/", "src/"]).common_prefix(), "src/");
+    }
+
+    #[test]
+    fn common_prefix_stops_at_first_difference() {
+        let completion = with_candidates(&["cargo", "cat", "cal"]);
+        assert_eq!(completion.common_prefix(), "ca");
+        assert_eq!(with_candidates(&["ls", "cd"]).common_prefix(), "");
+        assert_eq!(with_candidates(&["échec", "étude"]).common_prefix(), "é");
+    }
+
+    #[test]
+    fn apply_common_prefix_extends_the_word() {
+        let mut completion = with_candidates(&["history", "hist_tool"]);
+        completion.completion_prefix = "hi".to_string();
+        let mut input = "hi".to_string();
+        let mut cursor_pos = 2;
+        completion.start(&input, cursor_pos);
+
+        assert!(completion.apply_common_prefix(&mut input, &mut cursor_pos));
+        assert_eq!(input, "hist");
+        assert_eq!(cursor_pos, 4);
+
+        completion.completion_prefix = "hist".to_string();
+        assert!(!completion.apply_common_prefix(&mut input, &mut cursor_pos));
+    }
+
+    #[test]
+    fn path_completions_are_escaped_to_round_trip() {
+        let dir = std::env::temp_dir().join(format!("wsh-complete-{}", std::process::id()));
+        let names = ["My Documents", "it's \"here\"", "a(b)&c;d"];
+        for name in names {
+            std::fs::create_dir_all(dir.join(name)).unwrap();
+        }
+        let base = dir.display().to_string();
+        let config = Config::default();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+
+        for name in names {
+            let first = &name[..1];
+            let input = format!("cd {}/{}", base, Utils::escape_word(first));
+            completion.generate(&input, input.len(), &config, &history);
+            let expected = format!("{}/{}/", base, name);
+            assert_eq!(
+                completion.completions,
+                [Candidate::new(Utils::escape_word(&expected))]
+            );
+            assert_eq!(
+                Utils::parse_command(&completion.completions[0].text),
+                [expected.as_str()]
+            );
+        }
+
+        // A word typed with an escaped space or in quotes is one prefix
+        for typed in ["My\\ D", "'My D", "\"My D"] {
+            let input = format!("ls {}/{}", Utils::escape_word(&base), typed);
+            completion.generate(&input, input.len(), &config, &history);
+            assert_eq!(completion.completion_prefix.len(), input.len() - 3);
+            assert_eq!(completion.completions.len(), 1);
+
+            completion.start(&input, input.len());
+            let mut line = input.clone();
+            let mut cursor = line.len();
+            completion.apply(&mut line, &mut cursor).unwrap();
+            assert_eq!(
+                Utils::parse_command(&line),
+                ["ls".to_string(), format!("{}/My Documents/", base)]
+            );
+        }
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn directory_commands_only_complete_directories() {
+        let dir = std::env::temp_dir().join(format!("wsh-dirs-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("sub")).unwrap();
+        std::fs::create_dir_all(dir.join(".hidden")).unwrap();
+        std::fs::write(dir.join("file"), "").unwrap();
+        #[cfg(unix)]
+        std::os::unix::fs::symlink(dir.join("sub"), dir.join("link")).unwrap();
+        let base = dir.display().to_string();
+        let config = Config::default();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+        let mut complete = |input: String| {
+            completion.generate(&input, input.len(), &config, &history);
+            let mut names: Vec<String> = completion
+                .completions
+                .iter()
+                .map(|c| c.text[base.len() + 1..].to_string())
+                .collect();
+            names.sort();
+            names
+        };
+
+        let mut dirs = vec!["sub/"];
+        #[cfg(unix)]
+        dirs.insert(0, "link/");
+        assert_eq!(complete(format!("cd {}/", base)), dirs);
+        assert_eq!(complete(format!("pushd {}/", base)), dirs);
+        assert_eq!(complete(format!("cd {}/.", base)), [".hidden/"]);
+        assert!(complete(format!("cat {}/", base)).contains(&"file".to_string()));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn large_directories_are_scanned_up_to_a_limit() {
+        let dir = std::env::temp_dir().join(format!("wsh-large-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        for n in 0..MAX_SCANNED_ENTRIES + 100 {
+            std::fs::write(dir.join(format!("file{}", n)), "").unwrap();
+        }
+        let config = Config::default();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+
+        let input = format!("cat {}/file", dir.display());
+        completion.generate(&input, input.len(), &config, &history);
+        assert_eq!(completion.completions.len(), MAX_SCANNED_ENTRIES);
+        assert!(
+            completion
+                .notice
+                .as_ref()
+                .unwrap()
+                .contains("only the first")
+        );
+
+        // A name typed out in full completes even if the scan stopped
+        // before reaching it. Four digits make a name no other one starts with.
+        let unscanned = (1000..MAX_SCANNED_ENTRIES + 100)
+            .map(|n| format!("{}/file{}", dir.display(), n))
+            .find(|path| !completion.completions.iter().any(|c| &c.text == path))
+            .unwrap();
+        let input = format!("cat {}", unscanned);
+        completion.generate(&input, input.len(), &config, &history);
+        let texts: Vec<_> = completion.completions.iter().map(|c| &c.text).collect();
+        assert_eq!(texts, [&unscanned]);
+        assert!(completion.notice.is_some());
+
+        // A word that can't be in a directory listing matches nothing, but
+        // isn't a reason for a notice
+        let input = format!("cat {}/missing/x", dir.display());
+        completion.generate(&input, input.len(), &config, &history);
+        assert!(completion.is_empty());
+        assert_eq!(completion.notice, None);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn symlinks_to_directories_get_a_slash_and_others_a_mark() {
+        use std::os::unix::fs::{PermissionsExt, symlink};
+
+        let dir = std::env::temp_dir().join(format!("wsh-symlinks-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("real")).unwrap();
+        std::fs::write(dir.join("plain"), "").unwrap();
+        std::fs::write(dir.join("run"), "").unwrap();
+        std::fs::set_permissions(dir.join("run"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        symlink(dir.join("real"), dir.join("to-dir")).unwrap();
+        symlink(dir.join("plain"), dir.join("to-file")).unwrap();
+        symlink(dir.join("loop"), dir.join("loop")).unwrap();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+        let prefix = format!("{}/", dir.display());
+        let shown = |completion: &Completion| -> Vec<String> {
+            completion
+                .completions
+                .iter()
+                .map(|c| c.label().strip_prefix(&prefix).unwrap().to_string())
+                .collect()
+        };
+
+        let input = format!("cd {}", prefix);
+        completion.generate(&input, input.len(), &Config::default(), &history);
+        assert_eq!(shown(&completion), ["real/", "to-dir/"]);
+
+        let input = format!("cat {}", prefix);
+        completion.generate(&input, input.len(), &Config::default(), &history);
+        assert_eq!(
+            shown(&completion),
+            ["loop", "plain", "real/", "run", "to-dir/", "to-file"]
+        );
+
+        let config = Config {
+            completion_marks: true,
+            ..Config::default()
+        };
+        completion.generate(&input, input.len(), &config, &history);
+        assert_eq!(
+            shown(&completion),
+            ["loop@", "plain", "real/", "run*", "to-dir/", "to-file@"]
+        );
+        // The marks are only shown, never inserted
+        assert!(
+            completion
+                .completions
+                .iter()
+                .all(|c| !c.text.ends_with(['@', '*']))
+        );
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn path_scan_handles_many_entries_and_odd_names() {
+        use std::ffi::OsStr;
+        use std::os::unix::ffi::OsStrExt;
+        use std::os::unix::fs::{PermissionsExt, symlink};
+        use std::time::{Duration, Instant};
+
+        let dir = std::env::temp_dir().join(format!("wsh-path-scan-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("subdir")).unwrap();
+        let executable = std::fs::Permissions::from_mode(0o755);
+        for i in 0..2000 {
+            let path = dir.join(format!("cmd{}", i));
+            std::fs::write(&path, "").unwrap();
+            if i % 2 == 0 {
+                std::fs::set_permissions(&path, executable.clone()).unwrap();
+            }
+        }
+        let odd = dir.join(OsStr::from_bytes(b"odd\xff"));
+        std::fs::write(&odd, "").unwrap();
+        std::fs::set_permissions(&odd, executable.clone()).unwrap();
+        std::fs::set_permissions(dir.join("subdir"), executable).unwrap();
+        symlink(dir.join("cmd0"), dir.join("linked")).unwrap();
+        symlink(dir.join("cmd1"), dir.join("linked-plain")).unwrap();
+
+        // The second copy of the directory is all names seen already
+        let path_var = format!("{0}:{0}", dir.display());
+        let start = Instant::now();
+        let mut commands = Completion::scan_path(&path_var);
+        assert!(start.elapsed() < Duration::from_secs(5));
+
+        commands.sort();
+        assert_eq!(commands.len(), 1000 + 2);
+        assert!(commands.iter().all(|name| {
+            name.strip_prefix("cmd")
+                .and_then(|n| n.parse::<usize>().ok())
+                .is_none_or(|n| n % 2 == 0)
+        }));
+        assert!(commands.iter().any(|name| name == "linked"));
+        assert!(commands.iter().any(|name| name == "odd\u{fffd}"));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn arguments_complete_from_the_commands_spec() {
+        let mut config = Config::default();
+        for (alias, expansion) in [("ll", "ls -la"), ("la", "ls -A"), ("g", "git")] {
+            config
+                .aliases
+                .insert(alias.to_string(), expansion.to_string());
+        }
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+        let mut complete = |input: &str| -> Vec<String> {
+            completion.generate(input, input.len(), &config, &history);
+            completion.completions.iter().map(|c| c.label()).collect()
+        };
+
+        assert_eq!(complete("unalias l"), ["la → ls -A", "ll → ls -la"]);
+        assert_eq!(complete("unalias ll g"), ["g → git"]);
+        assert_eq!(complete("alias "), ["g → git", "la → ls -A", "ll → ls -la"]);
+        assert_eq!(complete("help pu"), ["pushd"]);
+        // `which` takes anything that runs, but not files
+        assert!(complete("which l").contains(&"ll → ls -la".to_string()));
+        let file = std::env::temp_dir().join(format!("wsh-spec-{}", std::process::id()));
+        std::fs::write(&file, "").unwrap();
+        assert!(complete(&format!("which {}", file.display())).is_empty());
+        assert_eq!(complete(&format!("cat {}", file.display())).len(), 1);
+        std::fs::remove_file(&file).unwrap();
+    }
+
+    #[test]
+    fn completing_mid_line_keeps_the_text_after_the_cursor() {
+        let dir = std::env::temp_dir().join(format!("wsh-midline-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("file1"), "").unwrap();
+        std::fs::write(dir.join("file2"), "").unwrap();
+        let base = dir.display().to_string();
+        let config = Config::default();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+
+        // Tab after `fi` in `cat <dir>/fi bar`
+        let mut input = format!("cat {}/fi bar", base);
+        let mut cursor = input.len() - " bar".len();
+        completion.generate(&input, cursor, &config, &history);
+        completion.start(&input, cursor);
+        assert!(completion.apply_common_prefix(&mut input, &mut cursor));
+        assert_eq!(input, format!("cat {}/file bar", base));
+        assert_eq!(&input[..cursor], format!("cat {}/file", base));
+
+        // Cycling replaces only the word before the cursor each time
+        completion.reset();
+        completion.generate(&input, cursor, &config, &history);
+        completion.start(&input, cursor);
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, format!("cat {}/file1 bar", base));
+        completion.cycle_next();
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, format!("cat {}/file2 bar", base));
+        assert_eq!(&input[cursor..], " bar");
+
+        // Inside a word, what's after the cursor stays put too
+        let mut input = format!("cat {}/file1x bar", base);
+        let mut cursor = input.len() - "x bar".len();
+        completion.reset();
+        completion.generate(&input, cursor, &config, &history);
+        completion.start(&input, cursor);
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, format!("cat {}/file1x bar", base));
+        assert_eq!(&input[cursor..], "x bar");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn empty_or_stale_candidates_reset_instead_of_panicking() {
+        let mut completion = with_candidates(&["alpha", "alps"]);
+        completion.completion_prefix = "al".to_string();
+        let mut input = "ls al".to_string();
+        let mut cursor = input.len();
+        completion.start(&input, cursor);
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, "ls alpha");
+
+        // The selection points past what's left
+        completion.completions.truncate(1);
+        completion.completion_index = Some(1);
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, "ls alpha");
+        assert!(completion.is_empty());
+        assert_eq!(completion.completion_index, None);
+
+        // Nothing left at all
+        let mut completion = with_candidates(&["alpha", "alps"]);
+        completion.start("ls al", 5);
+        completion.completions.clear();
+        completion.cycle_next();
+        assert_eq!(completion.completion_index, None);
+        completion.completion_index = Some(0);
+        completion.cycle_prev();
+        completion.completion_index = Some(0);
+        completion.step(4);
+        assert_eq!(completion.completion_index, None);
+        completion.apply(&mut input, &mut cursor).unwrap();
+        assert_eq!(input, "ls alpha");
+
+        // A line shorter than the one completion started from
+        let mut completion = with_candidates(&["alpha", "alpine"]);
+        completion.completion_prefix = "al".to_string();
+        completion.start("ls al", 5);
+        let mut short = "ls".to_string();
+        let mut cursor = short.len();
+        assert!(!completion.apply_common_prefix(&mut short, &mut cursor));
+        assert_eq!(short, "ls");
+    }
+
+    #[test]
+    fn long_lists_ask_before_opening_the_menu() {
+        let mut config = Config {
+            completion_limit: 3,
+            ..Config::default()
+        };
+        let completion = with_candidates(&["a", "b", "c"]);
+        assert!(!completion.exceeds_limit(&config));
+        let completion = with_candidates(&["a", "b", "c", "d"]);
+        assert!(completion.exceeds_limit(&config));
+
+        // No limit, or no menu to fill, means nothing to ask
+        config.completion_limit = 0;
+        assert!(!completion.exceeds_limit(&config));
+        config.completion_limit = 3;
+        config.completion_menu = false;
+        assert!(!completion.exceeds_limit(&config));
+    }
+
+    #[test]
+    fn match_modes() {
+        use crate::config::CompletionMode::*;
+        assert_eq!(Completion::match_score(Prefix, "Doc", "Documents"), Some(0));
+        assert_eq!(Completion::match_score(Prefix, "doc", "Documents"), None);
+        assert_eq!(Completion::match_score(Ci, "doc", "Documents"), Some(0));
+        assert_eq!(Completion::match_score(Ci, "dwn", "Downloads"), None);
+        assert!(Completion::match_score(Fuzzy, "dwn", "Downloads").is_some());
+        assert_eq!(Completion::match_score(Fuzzy, "nwd", "Downloads"), None);
+    }
+
+    #[test]
+    fn fuzzy_ranks_tighter_matches_first() {
+        use crate::config::CompletionMode::Fuzzy;
+        let scored = ["Downloads", "dl.sh", "cd"]
+            .iter()
+            .filter_map(|c| Some((Completion::match_score(Fuzzy, "dl", c)?, Candidate::new(*c))))
+            .collect();
+        assert_eq!(
+            Completion::rank(scored),
+            [Candidate::new("dl.sh"), Candidate::new("Downloads")]
+        );
+    }
+
+    #[test]
+    fn aliases_carry_their_expansion_as_a_hint() {
+        let alias = Candidate {
+            hint: Some("ls --color".to_string()),
+            ..Candidate::new("ls")
+        };
+        let scored = vec![
+            (0, Candidate::new("ls")),
+            (0, alias.clone()),
+            (0, Candidate::new("lsblk")),
+        ];
+        assert_eq!(
+            Completion::rank(scored),
+            [alias.clone(), Candidate::new("lsblk")]
+        );
+        assert_eq!(alias.label(), "ls → ls --color");
+        assert_eq!(Candidate::new("lsblk").label(), "lsblk");
+    }
+
+    #[test]
+    fn scrape_flags_from_help_text() {
+        let help = "Usage: ls [OPTION]... [FILE]...\n  \
+                    -a, --all                  do not ignore entries starting with .\n  \
+                    --color[=WHEN]             color the output; see --color below\n  \
+                    --block-size=SIZE          with -l, scale sizes by SIZE\n  \
+                    --                         end of options";
+        assert_eq!(
+            Completion::scrape_flags(help),
+            ["--all", "--block-size", "--color"]
+        );
+    }
+
+    #[test]
+    fn first_argument_completes_from_subcommand_specs() {
+        let mut config = Config::default();
+        config
+            .subcommands
+            .insert("git".to_string(), vec!["stage".to_string()]);
+        config
+            .subcommands
+            .insert("kubectl".to_string(), vec!["get".to_string()]);
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+        let mut complete = |input: &str| {
+            completion.generate(input, input.len(), &config, &history);
+            completion
+                .completions
+                .iter()
+                .map(|c| c.text.clone())
+                .collect::<Vec<_>>()
+        };
+
+        assert_eq!(complete("git st"), ["stage", "stash", "status"]);
+        assert_eq!(complete("cargo cl"), ["clean", "clippy"]);
+        assert_eq!(complete("kubectl g"), ["get"]);
+        // Past the subcommand, or when none matches, paths take over
+        assert_eq!(complete("git status /"), complete("ls /"));
+        assert_eq!(complete("git /"), complete("ls /"));
+        assert!(!complete("git /").is_empty());
+    }
+
+    #[test]
+    fn requested_completions_arrive_only_for_the_same_line() {
+        let config = Config::default();
+        let history = VecDeque::new();
+        let mut completion = Completion::new();
+        let wait = Duration::from_secs(5);
+
+        completion.request("cd /", 4, &config, &history);
+        assert!(completion.is_pending());
+        assert!(completion.poll("cd /", 4, wait));
+        assert!(!completion.is_pending());
+        assert!(!completion.is_empty());
+
+        // Typing on after Tab makes the result out of date
+        completion.reset();
+        completion.request("cd /", 4, &config, &history);
+        assert!(!completion.poll("cd /u", 5, wait));
+        assert!(!completion.is_pending());
+        assert!(completion.is_empty());
+    }
+
+    #[test]
+    #[cfg(unix)]
+    fn generating_reads_the_environment_it_was_given() {
+        use std::os::unix::fs::PermissionsExt;
+
+        let dir = std::env::temp_dir().join(format!("wsh-given-env-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("bin")).unwrap();
+        std::fs::write(dir.join("bin/wsh-given-cmd"), "").unwrap();
+        std::fs::set_permissions(
+            dir.join("bin/wsh-given-cmd"),
+            std::fs::Permissions::from_mode(0o755),
+        )
+        .unwrap();
+        std::fs::write(dir.join("notes"), "").unwrap();
+        let env = Environment {
+            path: Some(dir.join("bin").display().to_string()),
+            home: Some(dir.display().to_string()),
+        };
+        let texts = |generated: Generated| -> Vec<String> {
+            generated.candidates.into_iter().map(|c| c.text).collect()
+        };
+        let mut sources = Sources::default();
+        let (config, history) = (Config::default(), VecDeque::new());
+
+        let generated = sources.generate("wsh-given", 9, &config, &history, env.clone());
+        assert_eq!(texts(generated), ["wsh-given-cmd"]);
+        let generated = sources.generate("cat ~/no", 8, &config, &history, env);
+        assert_eq!(texts(generated), [format!("{}/notes", dir.display())]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }
@@ -1,55 +1,440 @@
-use anyhow::Result;
+use crate::keybindings::Action;
+use anyhow::{Result, anyhow};
+use crossterm::style::Color;
 use serde::{Deserialize, Serialize};
-use std::path::Path;
+use std::io::IsTerminal;
+use std::path::{Path, PathBuf};
+
+/// Key binding style for the line editor
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum EditMode {
+    #[default]
+    Emacs,
+    Vi,
+}
+
+/// How Tab completion matches what's been typed against candidates
+#[derive(Debug, Clone, Copy, Default, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum CompletionMode {
+    /// Candidates must start with the typed text
+    #[default]
+    Prefix,
+    /// Like `Prefix`, ignoring case
+    Ci,
+    /// The typed characters must appear in order, e.g. `dwn` for `Downloads`
+    Fuzzy,
+}
+
+/// Which commands are kept out of history, like bash's HISTCONTROL
+#[derive(Debug, Clone, Copy, PartialEq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum HistoryControl {
+    /// Skip a command that repeats the previous one
+    IgnoreDups,
+    /// Skip commands typed with a leading space
+    IgnoreSpace,
+    /// Drop older copies of a command when it's added again
+    EraseDups,
+}
+
+/// Colors for each UI role, as names (`"green"`, `"bright_blue"`) or hex
+/// (`"#ff8800"`). Unset or unrecognised roles keep their default color.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default)]
+pub struct Colors {
+    pub prompt: Option<String>,
+    pub command: Option<String>,
+    pub error: Option<String>,
+    #[serde(alias = "completion-marker")]
+    pub completion_marker: Option<String>,
+    #[serde(alias = "match-highlight")]
+    pub match_highlight: Option<String>,
+    pub suggestion: Option<String>,
+}
+
+impl Colors {
+    pub fn prompt(&self) -> Color {
+        Self::pick(&self.prompt, Color::Green)
+    }
+
+    pub fn command(&self) -> Color {
+        Self::pick(&self.command, Color::Blue)
+    }
+
+    pub fn error(&self) -> Color {
+        Self::pick(&self.error, Color::Red)
+    }
+
+    pub fn completion_marker(&self) -> Color {
+        Self::pick(&self.completion_marker, Color::Cyan)
+    }
+
+    pub fn match_highlight(&self) -> Color {
+        Self::pick(&self.match_highlight, Color::Yellow)
+    }
+
+    pub fn suggestion(&self) -> Color {
+        Self::pick(&self.suggestion, Color::DarkGrey)
+    }
+
+    fn pick(name: &Option<String>, default: Color) -> Color {
+        name.as_deref().and_then(parse_color).unwrap_or(default)
+    }
+}
+
+/// Parse a color name like `"red"` or `"bright_red"` (the standard and
+/// bright ANSI colors) or a `"#rrggbb"` hex value
+pub fn parse_color(name: &str) -> Option<Color> {
+    let name = name.trim().to_lowercase();
+    if let Some(hex) = name.strip_prefix('#') {
+        if hex.len() != 6 {
+            return None;
+        }
+        let channel = |i: usize| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok();
+        return Some(Color::Rgb {
+            r: channel(0)?,
+            g: channel(2)?,
+            b: channel(4)?,
+        });
+    }
+
+    // crossterm names the bright variants plainly and the standard ones "Dark"
+    let color = match name.replace('-', "_").as_str() {
+        "black" => Color::Black,
+        "red" => Color::DarkRed,
+        "green" => Color::DarkGreen,
+        "yellow" => Color::DarkYellow,
+        "blue" => Color::DarkBlue,
+        "magenta" => Color::DarkMagenta,
+        "cyan" => Color::DarkCyan,
+        "white" | "grey" | "gray" => Color::Grey,
+        "bright_black" | "dark_grey" | "dark_gray" => Color::DarkGrey,
+        "bright_red" => Color::Red,
+        "bright_green" => Color::Green,
+        "bright_yellow" => Color::Yellow,
+        "bright_blue" => Color::Blue,
+        "bright_magenta" => Color::Magenta,
+        "bright_cyan" => Color::Cyan,
+        "bright_white" => Color::White,
+        _ => return None,
+    };
+    Some(color)
+}
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct Config {
     pub prompt: String,
+    /// Right-aligned prompt segment, like zsh's RPROMPT
+    #[serde(default)]
+    pub rprompt: String,
+    /// Prompt for continuation lines, like bash's PS2
+    #[serde(default = "default_prompt2")]
+    pub prompt2: String,
+    /// The `{duration}` placeholder shows only commands that took at least
+    /// this many milliseconds
+    #[serde(default = "default_duration_threshold_ms")]
+    pub duration_threshold_ms: u64,
+    /// How many history entries to keep; 0 means unlimited
     pub history_size: usize,
+    #[serde(default = "default_history_control")]
+    pub history_control: Vec<HistoryControl>,
+    /// Show when each command ran in `history`
+    #[serde(default)]
+    pub show_timestamps: bool,
     pub enable_colors: bool,
+    #[serde(default)]
+    pub colors: Colors,
+    /// Color command names in the input line as it's typed, red when they
+    /// don't resolve to anything
+    #[serde(default = "default_syntax_highlight")]
+    pub syntax_highlight: bool,
+    #[serde(default)]
+    pub edit_mode: EditMode,
+    #[serde(default)]
+    pub completion_mode: CompletionMode,
+    /// List the candidates under the input line when Tab finds several
+    #[serde(default = "default_completion_menu")]
+    pub completion_menu: bool,
+    /// Ask before listing more candidates than this in the menu; 0 never
+    /// asks
+    #[serde(default = "default_completion_limit")]
+    pub completion_limit: usize,
+    /// Mark symlinks with `@` and executables with `*` in the completion
+    /// menu, like `ls -F`
+    #[serde(default)]
+    pub completion_marks: bool,
     pub aliases: std::collections::HashMap<String, String>,
+    /// Extra or replacement line editor keys, e.g. `"ctrl+t" = "clear_screen"`
+    #[serde(default)]
+    pub keybindings: std::collections::HashMap<String, Action>,
+    /// Subcommands Tab offers after a command, e.g. `kubectl = ["get"]`,
+    /// on top of the built-in ones for git, cargo and docker
+    #[serde(default)]
+    pub subcommands: std::collections::HashMap<String, Vec<String>>,
+    /// Environment variables exported at startup, e.g.
+    /// `EDITOR = "vim"`; values expand `~` and `$VAR`
+    #[serde(default)]
+    pub env: std::collections::HashMap<String, String>,
+    /// Startup script run by interactive shells, `~/.wshrc` if unset
+    #[serde(default)]
+    pub rcfile: Option<String>,
+    /// Write the config back to disk when `alias`/`unalias` change it
+    #[serde(default)]
+    pub save_aliases: bool,
+    /// Where this config was loaded from, and where it's saved to
+    #[serde(skip)]
+    pub path: Option<PathBuf>,
+}
+
+fn default_prompt2() -> String {
+    "> ".to_string()
+}
+
+fn default_duration_threshold_ms() -> u64 {
+    2000
+}
+
+fn default_history_control() -> Vec<HistoryControl> {
+    vec![HistoryControl::IgnoreDups]
+}
+
+fn default_syntax_highlight() -> bool {
+    true
+}
+
+fn default_completion_menu() -> bool {
+    true
+}
+
+fn default_completion_limit() -> usize {
+    100
 }
 
 impl Default for Config {
     fn default() -> Self {
         Self {
             prompt: "➜ {cwd} $ ".to_string(),
+            rprompt: String::new(),
+            prompt2: default_prompt2(),
+            duration_threshold_ms: default_duration_threshold_ms(),
             history_size: 1000,
+            history_control: default_history_control(),
+            show_timestamps: false,
             enable_colors: true,
+            colors: Colors::default(),
+            syntax_highlight: true,
+            edit_mode: EditMode::default(),
+            completion_mode: CompletionMode::default(),
+            completion_menu: true,
+            completion_limit: default_completion_limit(),
+            completion_marks: false,
             aliases: std::collections::HashMap::new(),
+            keybindings: std::collections::HashMap::new(),
+            subcommands: std::collections::HashMap::new(),
+            env: std::collections::HashMap::new(),
+            rcfile: None,
+            save_aliases: false,
+            path: None,
         }
     }
 }
 
 impl Config {
-    pub fn load(path: Option<&Path>) -> Result<Self> {
+    /// Load the config from `path` or the default location. A file that's
+    /// missing, unreadable or invalid gets the defaults instead, so a typo
+    /// in it can't keep the shell from starting.
+    pub fn load(path: Option<&Path>) -> Self {
         if let Some(config_path) = path {
             if config_path.exists() {
-                let content = std::fs::read_to_string(config_path)?;
-                let config: Config = toml::from_str(&content)?;
-                Ok(config)
+                Self::read_or_default(config_path)
             } else {
                 eprintln!("Config file not found at {:?}, using defaults", config_path);
-                Ok(Config::default())
+                Self::default_at(config_path)
             }
         } else {
             // Try to load from default locations
-            let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
-            let default_config = Path::new(&home_dir).join(".wsh.toml");
+            let default_config = Self::default_path();
 
             if default_config.exists() {
-                let content = std::fs::read_to_string(&default_config)?;
-                let config: Config = toml::from_str(&content)?;
-                Ok(config)
+                Self::read_or_default(&default_config)
             } else {
-                Ok(Config::default())
+                Config::default()
             }
         }
     }
 
-    /* pub fn save(&self, path: &Path) -> Result<()> {  // for future -__-
-        let content = toml::to_string_pretty(self)?;
+    /// Read a config file that exists, warning and using the defaults if
+    /// it can't be read or parsed
+    fn read_or_default(path: &Path) -> Self {
+        Self::read(path).unwrap_or_else(|e| {
+            eprintln!("Can't load config from {:?}, using defaults: {}", path, e);
+            Self::default_at(path)
+        })
+    }
+
+    /// The defaults, remembering `path` so `reload` picks the file up once
+    /// it exists or is fixed. Nothing is saved back over a broken file, as
+    /// `save_aliases` is off by default.
+    fn default_at(path: &Path) -> Self {
+        Config {
+            path: Some(path.to_path_buf()),
+            ..Config::default()
+        }
+    }
+
+    /// Read and parse a config file, remembering where it came from. The
+    /// format is picked from the extension, defaulting to TOML.
+    pub fn read(path: &Path) -> Result<Self> {
+        let content = std::fs::read_to_string(path)?;
+        let format = ConfigFormat::from_path(path);
+        let parsed = match format {
+            ConfigFormat::Toml => toml::from_str(&content).map_err(anyhow::Error::from),
+            ConfigFormat::Json => serde_json::from_str(&content).map_err(anyhow::Error::from),
+            ConfigFormat::Yaml => serde_yaml::from_str(&content).map_err(anyhow::Error::from),
+        };
+        let mut config: Config =
+            parsed.map_err(|e| anyhow!("invalid {} config: {}", format.name(), e))?;
+        config.path = Some(path.to_path_buf());
+        Ok(config)
+    }
+
+    /// The config file used when none is given on the command line
+    pub fn default_path() -> PathBuf {
+        let home_dir = std::env::var("HOME").unwrap_or_else(|_| ".".to_string());
+        Path::new(&home_dir).join(".wsh.toml")
+    }
+
+    pub fn save(&self, path: &Path) -> Result<()> {
+        let content = match ConfigFormat::from_path(path) {
+            ConfigFormat::Toml => toml::to_string_pretty(self)?,
+            ConfigFormat::Json => serde_json::to_string_pretty(self)?,
+            ConfigFormat::Yaml => serde_yaml::to_string(self)?,
+        };
         std::fs::write(path, content)?;
         Ok(())
-    } */
+    }
+
+    /// Whether to color output: `enable_colors` is on, `NO_COLOR` isn't set
+    /// (see no-color.org) and stdout is a terminal rather than a file or pipe
+    pub fn use_colors(&self) -> bool {
+        self.colors_on(std::io::stdout().is_terminal())
+    }
+
+    fn colors_on(&self, is_terminal: bool) -> bool {
+        let no_color = std::env::var_os("NO_COLOR").is_some_and(|value| !value.is_empty());
+        self.enable_colors && !no_color && is_terminal
+    }
+
+    /// Persist alias changes if `save_aliases` is enabled, writing back to
+    /// the file the config came from (or the default location)
+    pub fn persist_aliases(&self) -> Result<()> {
+        if !self.save_aliases {
+            return Ok(());
+        }
+        let path = self.path.clone().unwrap_or_else(Self::default_path);
+        self.save(&path)
+    }
+}
+
+/// File formats a config can be written in
+#[derive(Debug, Clone, Copy, PartialEq)]
+enum ConfigFormat {
+    Toml,
+    Json,
+    Yaml,
+}
+
+impl ConfigFormat {
+    /// Pick the format from a file's extension, defaulting to TOML
+    fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("json") => Self::Json,
+            Some("yaml" | "yml") => Self::Yaml,
+            _ => Self::Toml,
+        }
+    }
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Toml => "TOML",
+            Self::Json => "JSON",
+            Self::Yaml => "YAML",
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Config, parse_color};
+    use crate::utils::lock_env;
+    use crossterm::style::Color;
+
+    #[test]
+    fn colors_need_a_terminal_and_no_no_color() {
+        let _env = lock_env();
+        let config = Config::default();
+        // SAFETY: the env lock is held
+        unsafe { std::env::remove_var("NO_COLOR") };
+        assert!(config.colors_on(true));
+        assert!(!config.colors_on(false));
+
+        // SAFETY: as above
+        unsafe { std::env::set_var("NO_COLOR", "1") };
+        assert!(!config.colors_on(true));
+        // SAFETY: as above
+        unsafe { std::env::set_var("NO_COLOR", "") };
+        assert!(config.colors_on(true));
+        // SAFETY: as above
+        unsafe { std::env::remove_var("NO_COLOR") };
+
+        let config = Config {
+            enable_colors: false,
+            ..Config::default()
+        };
+        assert!(!config.colors_on(true));
+    }
+
+    #[test]
+    fn parse_color_names_and_hex() {
+        assert_eq!(parse_color("green"), Some(Color::DarkGreen));
+        assert_eq!(parse_color("Bright_Blue"), Some(Color::Blue));
+        assert_eq!(parse_color("bright-red"), Some(Color::Red));
+        assert_eq!(
+            parse_color("#ff8800"),
+            Some(Color::Rgb {
+                r: 0xff,
+                g: 0x88,
+                b: 0x00
+            })
+        );
+        assert_eq!(parse_color("#ff88"), None);
+        assert_eq!(parse_color("#gg8800"), None);
+        assert_eq!(parse_color("chartreuse"), None);
+    }
+
+    #[test]
+    fn broken_config_falls_back_to_defaults() {
+        let dir = std::env::temp_dir().join(format!("wsh-broken-config-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        std::fs::write(&path, "prompt = \"unterminated\nhistory_size = [").unwrap();
+
+        assert!(Config::read(&path).is_err());
+        let config = Config::load(Some(&path));
+        assert_eq!(config.prompt, Config::default().prompt);
+        assert_eq!(config.path.as_deref(), Some(path.as_path()));
+        assert!(crate::shell::Shell::new(config).is_ok());
+
+        // Once fixed, the same path loads as written
+        std::fs::write(
+            &path,
+            "prompt = \"ok $ \"\nhistory_size = 5\nenable_colors = false\naliases = {}\n",
+        )
+        .unwrap();
+        assert_eq!(Config::load(Some(&path)).prompt, "ok $ ");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
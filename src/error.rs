@@ -0,0 +1,81 @@
+use std::fmt;
+use std::io;
+
+/// Errors whose kind matters to the shell, mostly for the exit status `$?`
+/// gets. They travel inside `anyhow::Error` like any other error and are
+/// picked back out with `status_of`.
+#[derive(Debug)]
+pub enum ShellError {
+    /// No command by this name in `$PATH`
+    CommandNotFound(String),
+    /// A command given as a path that doesn't exist
+    NoSuchFile(String),
+    /// A command that exists but can't be run
+    NotExecutable { command: String, reason: String },
+    /// An operator where a command or word should be
+    UnexpectedToken(String),
+    /// Any other malformed input, like a line ending in `&&`
+    Parse(String),
+    /// `exit` inside a `$(...)`, which ends the substitution rather than
+    /// the shell
+    Exit(i32),
+}
+
+impl ShellError {
+    /// Classify a failure to start `command`
+    pub fn spawn(command: &str, error: io::Error) -> Self {
+        let command = command.to_string();
+        match error.kind() {
+            io::ErrorKind::NotFound if !command.contains('/') => Self::CommandNotFound(command),
+            io::ErrorKind::NotFound => Self::NoSuchFile(command),
+            io::ErrorKind::PermissionDenied => Self::NotExecutable {
+                command,
+                reason: "permission denied".to_string(),
+            },
+            _ => Self::NotExecutable {
+                command,
+                reason: error.to_string(),
+            },
+        }
+    }
+
+    /// The exit status for this error, as in POSIX shells: 127 when there's
+    /// no such command, 126 when it can't be run and 2 for syntax errors
+    pub fn exit_code(&self) -> i32 {
+        match self {
+            Self::CommandNotFound(_) | Self::NoSuchFile(_) => 127,
+            Self::NotExecutable { .. } => 126,
+            Self::UnexpectedToken(_) | Self::Parse(_) => 2,
+            Self::Exit(code) => *code,
+        }
+    }
+
+    /// The exit status for a failed command: the shell error's code, or 1
+    /// for any other error
+    pub fn status_of(error: &anyhow::Error) -> i32 {
+        error.downcast_ref::<Self>().map_or(1, Self::exit_code)
+    }
+
+    /// Whether `error` is an `exit`, which stops everything up to the
+    /// `$(...)` it's in
+    pub fn is_exit(error: &anyhow::Error) -> bool {
+        matches!(error.downcast_ref::<Self>(), Some(Self::Exit(_)))
+    }
+}
+
+impl fmt::Display for ShellError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::CommandNotFound(command) => write!(f, "{}: command not found", command),
+            Self::NoSuchFile(command) => write!(f, "{}: no such file or directory", command),
+            Self::NotExecutable { command, reason } => write!(f, "{}: {}", command, reason),
+            Self::UnexpectedToken(token) => {
+                write!(f, "syntax error near unexpected token '{}'", token)
+            }
+            Self::Parse(message) => write!(f, "syntax error: {}", message),
+            Self::Exit(code) => write!(f, "exit {}", code),
+        }
+    }
+}
+
+impl std::error::Error for ShellError {}
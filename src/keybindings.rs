@@ -0,0 +1,297 @@
+use anyhow::{Result, anyhow};
+use crossterm::event::{KeyCode, KeyModifiers};
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+
+/// A line editing action a key can be bound to
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Action {
+    /// Run the line, or start a continuation line if it's unfinished
+    AcceptLine,
+    /// Throw the line away and start over, like Ctrl+C
+    CancelLine,
+    /// Leave the shell if the line is empty, otherwise delete forwards
+    ExitOrDeleteChar,
+    BackwardDeleteChar,
+    DeleteChar,
+    BackwardChar,
+    /// Move right, or take the history suggestion at the end of the line
+    ForwardChar,
+    BackwardWord,
+    ForwardWord,
+    BeginningOfLine,
+    EndOfLine,
+    AcceptSuggestion,
+    PreviousHistory,
+    NextHistory,
+    ReverseSearch,
+    Complete,
+    BackwardKillWord,
+    BackwardKillLine,
+    KillLine,
+    Yank,
+    ClearScreen,
+}
+
+/// A key and the modifiers held with it
+type Chord = (KeyCode, KeyModifiers);
+
+/// Which action each key runs in the line editor. The defaults match the
+/// keys listed in the README; the config's `[keybindings]` table adds to
+/// them or overrides them.
+#[derive(Debug, Clone)]
+pub struct Keymap {
+    bindings: HashMap<Chord, Action>,
+}
+
+impl Keymap {
+    /// Build the default bindings with `overrides` (chord name to action)
+    /// applied on top
+    pub fn new(overrides: &HashMap<String, Action>) -> Result<Self> {
+        let mut keymap = Self::default();
+        for (chord, &action) in overrides {
+            let chord = parse_chord(chord)
+                .ok_or_else(|| anyhow!("keybindings: unrecognised key '{}'", chord))?;
+            keymap.bindings.insert(chord, action);
+        }
+        Ok(keymap)
+    }
+
+    /// The action bound to a key event, if any. A key other than a
+    /// character that isn't bound with these modifiers does what it does
+    /// alone, so Shift+Enter still accepts the line and Alt+Backspace still
+    /// deletes, as they did before keys were configurable.
+    pub fn action(&self, code: KeyCode, modifiers: KeyModifiers) -> Option<Action> {
+        let (code, modifiers) = normalize(code, modifiers);
+        let alone = match code {
+            KeyCode::Char(_) => None,
+            // Shift+Tab arrives as BackTab; unbound, it's Tab
+            KeyCode::BackTab => Some(KeyCode::Tab),
+            code => Some(code),
+        };
+        self.bindings
+            .get(&(code, modifiers))
+            .or_else(|| self.bindings.get(&(alone?, KeyModifiers::NONE)))
+            .copied()
+    }
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        use Action::*;
+        let defaults = [
+            ("enter", AcceptLine),
+            ("ctrl+c", CancelLine),
+            ("ctrl+d", ExitOrDeleteChar),
+            ("backspace", BackwardDeleteChar),
+            ("delete", DeleteChar),
+            ("left", BackwardChar),
+            ("right", ForwardChar),
+            ("ctrl+f", ForwardChar),
+            // Terminals report Alt+Left/Right as Alt+B/F, so bind both
+            ("alt+left", BackwardWord),
+            ("ctrl+left", BackwardWord),
+            ("alt+b", BackwardWord),
+            ("alt+right", ForwardWord),
+            ("ctrl+right", ForwardWord),
+            ("alt+f", ForwardWord),
+            ("home", BeginningOfLine),
+            ("ctrl+a", BeginningOfLine),
+            ("end", EndOfLine),
+            ("ctrl+e", EndOfLine),
+            ("up", PreviousHistory),
+            ("down", NextHistory),
+            ("ctrl+r", ReverseSearch),
+            ("tab", Complete),
+            ("ctrl+w", BackwardKillWord),
+            ("ctrl+u", BackwardKillLine),
+            ("ctrl+k", KillLine),
+            ("ctrl+y", Yank),
+            ("ctrl+l", ClearScreen),
+        ];
+        let bindings = defaults
+            .into_iter()
+            .map(|(chord, action)| (parse_chord(chord).expect("valid default chord"), action))
+            .collect();
+        Self { bindings }
+    }
+}
+
+/// Parse a chord like `"ctrl+l"`, `"alt+left"` or `"shift+tab"`: any of
+/// `ctrl`, `alt` and `shift` joined with `+` to a key name or a single
+/// character
+fn parse_chord(chord: &str) -> Option<Chord> {
+    let mut modifiers = KeyModifiers::NONE;
+    let mut parts: Vec<&str> = chord.split('+').collect();
+    // "ctrl++" binds the plus key
+    if chord.ends_with("++") {
+        parts.truncate(parts.len() - 2);
+        parts.push("+");
+    }
+    let key = parts.pop()?;
+    for modifier in parts {
+        modifiers |= match modifier.trim().to_lowercase().as_str() {
+            "ctrl" | "control" => KeyModifiers::CONTROL,
+            "alt" | "meta" => KeyModifiers::ALT,
+            "shift" => KeyModifiers::SHIFT,
+            _ => return None,
+        };
+    }
+
+    let code = if key.chars().count() == 1 {
+        let c = key.chars().next()?;
+        // Control and Alt chords arrive as the lowercase letter
+        if modifiers.is_empty() {
+            KeyCode::Char(c)
+        } else {
+            KeyCode::Char(c.to_ascii_lowercase())
+        }
+    } else {
+        let key = key.trim().to_lowercase();
+        match key.as_str() {
+            "enter" | "return" => KeyCode::Enter,
+            "tab" => KeyCode::Tab,
+            "backtab" => KeyCode::BackTab,
+            "backspace" => KeyCode::Backspace,
+            "delete" | "del" => KeyCode::Delete,
+            "insert" => KeyCode::Insert,
+            "esc" | "escape" => KeyCode::Esc,
+            "space" => KeyCode::Char(' '),
+            "left" => KeyCode::Left,
+            "right" => KeyCode::Right,
+            "up" => KeyCode::Up,
+            "down" => KeyCode::Down,
+            "home" => KeyCode::Home,
+            "end" => KeyCode::End,
+            "pageup" => KeyCode::PageUp,
+            "pagedown" => KeyCode::PageDown,
+            _ => {
+                let number = key.strip_prefix('f')?.parse().ok()?;
+                KeyCode::F(number)
+            }
+        }
+    };
+    Some(normalize(code, modifiers))
+}
+
+/// Put a chord in the form used as a lookup key. Shift is part of the
+/// character for printable keys, and Shift+Tab comes in as BackTab.
+fn normalize(code: KeyCode, modifiers: KeyModifiers) -> Chord {
+    match code {
+        KeyCode::Char(_) | KeyCode::BackTab => (code, modifiers - KeyModifiers::SHIFT),
+        KeyCode::Tab if modifiers.contains(KeyModifiers::SHIFT) => {
+            (KeyCode::BackTab, modifiers - KeyModifiers::SHIFT)
+        }
+        _ => (code, modifiers),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Action, Keymap, parse_chord};
+    use crossterm::event::{KeyCode, KeyModifiers};
+    use std::collections::HashMap;
+
+    #[test]
+    fn chords_parse_modifiers_and_key_names() {
+        assert_eq!(
+            parse_chord("ctrl+l"),
+            Some((KeyCode::Char('l'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(
+            parse_chord("Alt+Left"),
+            Some((KeyCode::Left, KeyModifiers::ALT))
+        );
+        assert_eq!(
+            parse_chord("shift+tab"),
+            Some((KeyCode::BackTab, KeyModifiers::NONE))
+        );
+        assert_eq!(
+            parse_chord("ctrl++"),
+            Some((KeyCode::Char('+'), KeyModifiers::CONTROL))
+        );
+        assert_eq!(parse_chord("f5"), Some((KeyCode::F(5), KeyModifiers::NONE)));
+        assert_eq!(parse_chord("hyper+x"), None);
+        assert_eq!(parse_chord("ctrl+nope"), None);
+    }
+
+    #[test]
+    fn modified_keys_fall_back_to_the_key_alone() {
+        let keymap = Keymap::default();
+        let cases = [
+            (KeyCode::Enter, KeyModifiers::SHIFT, Action::AcceptLine),
+            (
+                KeyCode::Backspace,
+                KeyModifiers::ALT,
+                Action::BackwardDeleteChar,
+            ),
+            (
+                KeyCode::Backspace,
+                KeyModifiers::CONTROL,
+                Action::BackwardDeleteChar,
+            ),
+            (KeyCode::Delete, KeyModifiers::CONTROL, Action::DeleteChar),
+            (KeyCode::Up, KeyModifiers::SHIFT, Action::PreviousHistory),
+            (KeyCode::Down, KeyModifiers::ALT, Action::NextHistory),
+            (KeyCode::Home, KeyModifiers::SHIFT, Action::BeginningOfLine),
+            (KeyCode::End, KeyModifiers::CONTROL, Action::EndOfLine),
+            (KeyCode::Tab, KeyModifiers::SHIFT, Action::Complete),
+            (KeyCode::BackTab, KeyModifiers::SHIFT, Action::Complete),
+            // Modified bindings of their own still win
+            (KeyCode::Left, KeyModifiers::CONTROL, Action::BackwardWord),
+            (KeyCode::Left, KeyModifiers::SHIFT, Action::BackwardChar),
+        ];
+        for (code, modifiers, action) in cases {
+            assert_eq!(
+                keymap.action(code, modifiers),
+                Some(action),
+                "{:?} {:?}",
+                modifiers,
+                code
+            );
+        }
+        // Characters are left to the line editor, which types them
+        assert_eq!(
+            keymap.action(KeyCode::Char('g'), KeyModifiers::CONTROL),
+            None
+        );
+    }
+
+    #[test]
+    fn overrides_replace_and_add_bindings() {
+        let overrides = HashMap::from([
+            ("ctrl+l".to_string(), Action::KillLine),
+            ("alt+s".to_string(), Action::AcceptSuggestion),
+        ]);
+        let keymap = Keymap::new(&overrides).unwrap();
+        assert_eq!(
+            keymap.action(KeyCode::Char('l'), KeyModifiers::CONTROL),
+            Some(Action::KillLine)
+        );
+        assert_eq!(
+            keymap.action(KeyCode::Char('s'), KeyModifiers::ALT),
+            Some(Action::AcceptSuggestion)
+        );
+        assert_eq!(
+            keymap.action(KeyCode::Char('a'), KeyModifiers::CONTROL),
+            Some(Action::BeginningOfLine)
+        );
+        assert_eq!(keymap.action(KeyCode::Char('a'), KeyModifiers::NONE), None);
+
+        // Binding a modified key leaves the key alone as it was
+        let overrides = HashMap::from([("shift+enter".to_string(), Action::Yank)]);
+        let keymap = Keymap::new(&overrides).unwrap();
+        assert_eq!(
+            keymap.action(KeyCode::Enter, KeyModifiers::SHIFT),
+            Some(Action::Yank)
+        );
+        assert_eq!(
+            keymap.action(KeyCode::Enter, KeyModifiers::NONE),
+            Some(Action::AcceptLine)
+        );
+
+        let bad = HashMap::from([("ctrl+nope".to_string(), Action::Yank)]);
+        assert!(Keymap::new(&bad).is_err());
+    }
+}
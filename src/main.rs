@@ -1,11 +1,16 @@
+mod builtins;
 mod completion;
 mod config;
+mod error;
+mod keybindings;
+mod redirect;
 mod shell;
 mod ui;
 mod utils;
 
 use anyhow::Result;
 use clap::Parser;
+use error::ShellError;
 
 #[derive(Parser)]
 #[command(name = "rsh")]
@@ -20,18 +25,74 @@ struct Cli {
 
     #[arg(short = 'c', long)]
     command: Option<String>,
+
+    /// Script file to run instead of starting interactively
+    #[arg(conflicts_with = "command")]
+    script: Option<std::path::PathBuf>,
+
+    /// Startup script to run instead of ~/.wshrc
+    #[arg(long)]
+    rcfile: Option<std::path::PathBuf>,
+
+    /// Don't run a startup script
+    #[arg(long)]
+    norc: bool,
+
+    /// Act as a login shell, running ~/.wsh_profile before anything else
+    #[arg(short, long)]
+    login: bool,
 }
 
 fn main() -> Result<()> {
     let cli = Cli::parse();
     env_logger::init();
+    restore_terminal_on_panic();
 
-    let config = config::Config::load(cli.config.as_deref())?;
+    let config = config::Config::load(cli.config.as_deref());
     let mut shell = shell::Shell::new(config)?;
 
-    if let Some(cmd) = cli.command {
-        shell.execute_command(&cmd)
-    } else {
-        shell.run_interactive()
-    }
+    // login(1) marks a login shell with a leading '-' in argv[0]
+    let login = cli.login
+        || std::env::args_os()
+            .next()
+            .is_some_and(|arg0| arg0.to_string_lossy().starts_with('-'));
+    let result = if login { shell.load_profile() } else { Ok(()) };
+    let result = result.and_then(|()| match (&cli.command, &cli.script) {
+        (Some(cmd), _) => shell.execute_command(cmd),
+        (None, Some(script)) => shell.run_script(script),
+        (None, None) => {
+            // Like bash, only interactive shells read the rc file
+            if !cli.norc {
+                shell.load_rc(cli.rcfile.as_deref())?;
+            }
+            shell.run_interactive()
+        }
+    });
+    // Exit with the command's own status so scripts can check it, or with
+    // the one given to `exit`
+    let code = match result {
+        Ok(code) => code,
+        Err(e) => {
+            if !ShellError::is_exit(&e) {
+                eprintln!("Error: {}", e);
+            }
+            shell.last_exit_code()
+        }
+    };
+    std::process::exit(code)
+}
+
+/// Leave raw mode before a panic message is printed, so it comes out
+/// readable and the terminal isn't left without echo when the shell dies
+fn restore_terminal_on_panic() {
+    let default_hook = std::panic::take_hook();
+    std::panic::set_hook(Box::new(move |info| {
+        if crossterm::terminal::is_raw_mode_enabled().unwrap_or(false) {
+            let _ = crossterm::execute!(std::io::stdout(), crossterm::event::DisableBracketedPaste);
+            let _ = crossterm::terminal::disable_raw_mode();
+            // Start below whatever was on the input line
+            eprintln!();
+        }
+        default_hook(info);
+    }));
 }
@@ -0,0 +1,280 @@
+use crate::builtins::Input;
+use crate::error::ShellError;
+use crate::utils::{Token, Utils};
+use anyhow::{Result, anyhow};
+use std::cell::RefCell;
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufReader, Cursor, PipeWriter, Write};
+use std::process::Stdio;
+
+/// A file that a stream is redirected to
+#[derive(Debug, Clone, PartialEq)]
+pub struct RedirectTarget {
+    pub path: String,
+    pub append: bool,
+}
+
+impl RedirectTarget {
+    /// Open the target file with truncate or append semantics
+    pub fn open(&self) -> Result<File> {
+        OpenOptions::new()
+            .write(true)
+            .create(true)
+            .append(self.append)
+            .truncate(!self.append)
+            .open(&self.path)
+            .map_err(|e| anyhow!("{}: {}", self.path, e))
+    }
+}
+
+/// Where one of a command's output streams ends up
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Output {
+    /// Where stdout goes without redirection: the shell's stdout, or the
+    /// pipe to the next stage of a pipeline
+    Stdout,
+    /// The shell's stderr
+    Stderr,
+    /// The file at this index in `Redirections::files`
+    File(usize),
+}
+
+/// Where a command's stdin is redirected from
+#[derive(Debug, Clone, PartialEq)]
+pub enum StdinSource {
+    /// A file, with `<`
+    File(String),
+    /// A here-document's body, with `<<`
+    Text(String),
+}
+
+/// Redirections attached to a single command
+#[derive(Debug)]
+pub struct Redirections {
+    pub stdin: Option<StdinSource>,
+    /// Every file named by `>`, `>>`, `2>` or `2>>`, in order. Like bash,
+    /// all of them are created even if a later redirection replaces one.
+    pub files: Vec<RedirectTarget>,
+    pub stdout: Output,
+    pub stderr: Output,
+}
+
+impl Default for Redirections {
+    fn default() -> Self {
+        Self {
+            stdin: None,
+            files: Vec::new(),
+            stdout: Output::Stdout,
+            stderr: Output::Stderr,
+        }
+    }
+}
+
+impl Redirections {
+    /// Open every output file, in the order of `files`. Each is opened
+    /// once, so `>out 2>&1` writes both streams through the same handle
+    /// instead of clobbering each other.
+    pub fn open_files(&self) -> Result<Vec<File>> {
+        self.files.iter().map(RedirectTarget::open).collect()
+    }
+
+    /// Open the output files and return what the command's stdout and
+    /// stderr should be set to, `None` leaving a stream as it is. `pipe`
+    /// is where stdout goes in a pipeline.
+    pub fn open_outputs(
+        &self,
+        pipe: Option<&PipeWriter>,
+    ) -> Result<(Option<Stdio>, Option<Stdio>)> {
+        let files = self.open_files()?;
+        let open = |output: Output, own: Output| -> Result<Option<Stdio>> {
+            match output {
+                Output::File(index) => Ok(Some(Stdio::from(files[index].try_clone()?))),
+                Output::Stdout => match pipe {
+                    Some(pipe) => Ok(Some(Stdio::from(pipe.try_clone()?))),
+                    None if own == Output::Stdout => Ok(None),
+                    None => shell_stream(Output::Stdout).map(Some),
+                },
+                Output::Stderr if own == Output::Stderr => Ok(None),
+                Output::Stderr => shell_stream(Output::Stderr).map(Some),
+            }
+        };
+        Ok((
+            open(self.stdout, Output::Stdout)?,
+            open(self.stderr, Output::Stderr)?,
+        ))
+    }
+
+    /// Open the redirected stdin, if there is one
+    pub fn open_stdin(&self) -> Result<Option<Stdio>> {
+        match &self.stdin {
+            None => Ok(None),
+            Some(StdinSource::File(path)) => {
+                let file = File::open(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+                Ok(Some(Stdio::from(file)))
+            }
+            Some(StdinSource::Text(text)) => {
+                let (reader, mut writer) = std::io::pipe()?;
+                // Feed the pipe from a thread so a body bigger than the pipe
+                // buffer can't block the shell before the command reads it.
+                // If the command exits without reading, the write just fails.
+                let text = text.clone();
+                std::thread::spawn(move || {
+                    let _ = writer.write_all(text.as_bytes());
+                });
+                Ok(Some(Stdio::from(reader)))
+            }
+        }
+    }
+
+    /// Open the redirected stdin for a builtin to read, if there is one
+    pub fn open_input(&self) -> Result<Option<Box<dyn Input>>> {
+        match &self.stdin {
+            None => Ok(None),
+            Some(StdinSource::File(path)) => {
+                let file = File::open(path).map_err(|e| anyhow!("{}: {}", path, e))?;
+                Ok(Some(Box::new(BufReader::new(file))))
+            }
+            Some(StdinSource::Text(text)) => Ok(Some(Box::new(Cursor::new(text.clone())))),
+        }
+    }
+
+    /// Split redirection operators and their targets out of the arguments,
+    /// returning the remaining words and the parsed redirections. Quoted
+    /// operators are already plain words, so `echo ">"` prints a `>`.
+    pub fn extract(args: &[Token]) -> Result<(Vec<String>, Self)> {
+        let mut remaining = Vec::new();
+        let mut redirections = Self::default();
+        let mut iter = args.iter();
+
+        while let Some(arg) = iter.next() {
+            let operator = match arg {
+                Token::Word(word, _) => {
+                    remaining.push(word.clone());
+                    continue;
+                }
+                Token::Op(operator) => *operator,
+            };
+
+            if operator == ">&" || operator == "2>&" {
+                // `2>&1` points stderr at wherever stdout goes at this point
+                // in the line, so `>out 2>&1` and `2>&1 >out` differ
+                let source = match iter.next() {
+                    Some(Token::Word(fd, _)) if fd == "1" => redirections.stdout,
+                    Some(Token::Word(fd, _)) if fd == "2" => redirections.stderr,
+                    _ => {
+                        return Err(ShellError::Parse(format!(
+                            "expected 1 or 2 after '{}'",
+                            operator
+                        ))
+                        .into());
+                    }
+                };
+                if operator == ">&" {
+                    redirections.stdout = source;
+                } else {
+                    redirections.stderr = source;
+                }
+                continue;
+            }
+
+            if operator == "<<" {
+                // The here-document body was inlined as the next word
+                let Some(Token::Word(text, _)) = iter.next() else {
+                    return Err(ShellError::Parse(
+                        "expected a here-document after '<<'".to_string(),
+                    )
+                    .into());
+                };
+                redirections.stdin = Some(StdinSource::Text(text.clone()));
+                continue;
+            }
+
+            let path = match iter.next() {
+                Some(Token::Word(target, _)) => Utils::expand_path(target),
+                _ => {
+                    return Err(ShellError::Parse(format!(
+                        "expected a file name after '{}'",
+                        operator
+                    ))
+                    .into());
+                }
+            };
+            let target = RedirectTarget {
+                path,
+                append: operator.ends_with(">>"),
+            };
+            let file = Output::File(redirections.files.len());
+            match operator {
+                "<" => redirections.stdin = Some(StdinSource::File(target.path)),
+                ">" | ">>" => {
+                    redirections.files.push(target);
+                    redirections.stdout = file;
+                }
+                "2>" | "2>>" => {
+                    redirections.files.push(target);
+                    redirections.stderr = file;
+                }
+                _ => return Err(ShellError::UnexpectedToken(operator.to_string()).into()),
+            }
+        }
+
+        Ok((remaining, redirections))
+    }
+}
+
+/// One of a builtin's output streams, sent where its redirections say.
+/// Both streams share the builtin's stdout, so `2>&1` interleaves them.
+pub struct BuiltinOutput<'a, 'b> {
+    target: Output,
+    stdout: &'a RefCell<&'b mut dyn Write>,
+    files: &'a [File],
+}
+
+impl<'a, 'b> BuiltinOutput<'a, 'b> {
+    /// A stream going to `target`, with `stdout` where unredirected output
+    /// goes and `files` opened by `Redirections::open_files`
+    pub fn new(target: Output, stdout: &'a RefCell<&'b mut dyn Write>, files: &'a [File]) -> Self {
+        Self {
+            target,
+            stdout,
+            files,
+        }
+    }
+}
+
+impl Write for BuiltinOutput<'_, '_> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self.target {
+            Output::Stdout => self.stdout.borrow_mut().write(buf),
+            Output::Stderr => io::stderr().write(buf),
+            Output::File(index) => (&self.files[index]).write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self.target {
+            Output::Stdout => self.stdout.borrow_mut().flush(),
+            Output::Stderr => io::stderr().flush(),
+            Output::File(index) => (&self.files[index]).flush(),
+        }
+    }
+}
+
+/// A handle to the shell's own stdout or stderr, for a command stream sent
+/// to the other one with `2>&1` or `>&2`
+#[cfg(unix)]
+fn shell_stream(output: Output) -> Result<Stdio> {
+    use std::os::fd::AsFd;
+    let fd = match output {
+        Output::Stderr => std::io::stderr().as_fd().try_clone_to_owned()?,
+        _ => std::io::stdout().as_fd().try_clone_to_owned()?,
+    };
+    Ok(Stdio::from(fd))
+}
+
+/// Without a portable way to duplicate the shell's streams, both stay
+/// where they are
+#[cfg(not(unix))]
+fn shell_stream(_output: Output) -> Result<Stdio> {
+    Ok(Stdio::inherit())
+}
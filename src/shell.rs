@@ -1,318 +1,3681 @@
+use crate::builtins::{self, Input};
 use crate::completion::Completion;
-use crate::config::Config;
+use crate::config::{Config, EditMode, HistoryControl};
+use crate::error::ShellError;
+use crate::keybindings::{Action, Keymap};
+use crate::redirect::{BuiltinOutput, Output, Redirections};
 use crate::ui::UI;
-use crate::utils::Utils;
+use crate::utils::{
+    Connector, Continuation, Expander, HistoryEntry, PromptState, Token, Utils, ViMode,
+};
 use anyhow::{Result, anyhow};
 use crossterm::{
-    cursor,
-    event::{self, Event, KeyCode, KeyEvent, KeyModifiers},
+    QueueableCommand, cursor,
+    event::{
+        self, DisableBracketedPaste, EnableBracketedPaste, Event, KeyCode, KeyEvent, KeyModifiers,
+    },
     execute,
     style::Print,
     terminal,
 };
 
-use std::collections::VecDeque;
-use std::io::stdout;
-use std::process::Command;
+use std::cell::RefCell;
+use std::collections::{HashMap, VecDeque};
+use std::io::{self, BufRead, BufReader, IsTerminal, PipeReader, PipeWriter, Read, Write, stdout};
+use std::ops::Range;
+#[cfg(unix)]
+use std::os::unix::process::CommandExt;
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::thread::JoinHandle;
+use std::time::{Duration, Instant};
+
+/// How long Tab waits for candidates before going back to reading keys.
+/// Most completions are ready well within it, so the list shows without
+/// a flicker.
+const COMPLETION_WAIT: Duration = Duration::from_millis(50);
+
+/// How often to check for candidates still being generated while waiting
+/// for keys
+const COMPLETION_POLL: Duration = Duration::from_millis(20);
 
 pub struct Shell {
     config: Config,
-    history: VecDeque<String>,
+    history: VecDeque<HistoryEntry>,
     current_input: String,
+    /// Earlier lines of a command that's still incomplete, e.g. one with an
+    /// unterminated quote
+    continuation: String,
+    /// Byte offset into `current_input`, always on a grapheme boundary
     cursor_pos: usize,
     history_index: Option<usize>,
+    /// Text removed by the last Ctrl+W/U/K, pasted back with Ctrl+Y
+    kill_buffer: String,
+    /// Current mode when `edit_mode` is vi
+    vi_mode: ViMode,
+    /// A vi operator such as `d` waiting for its motion
+    vi_pending: Option<char>,
     completion: Completion,
+    /// Line editor keys, from the defaults and the config's `keybindings`
+    keymap: Keymap,
+    last_exit_code: i32,
+    /// How long the last line from `execute_command` took to run
+    last_duration: Option<Duration>,
+    /// Variables set in the shell itself, like `read`'s. Expansion sees
+    /// them ahead of the environment, but child processes don't.
+    variables: HashMap<String, String>,
+    /// Exit status of the last `$(...)` to run, which an assignment on
+    /// its own exits with
+    substitution_status: Option<i32>,
+    previous_dir: Option<PathBuf>,
+    /// Directories saved by `pushd`, most recent last
+    dir_stack: Vec<PathBuf>,
+    /// Background jobs started with a trailing `&`
+    jobs: Vec<Child>,
+    /// Aliases whose expansion is running, innermost last
+    expanding_aliases: Vec<String>,
+    /// Set once the line editor is running
+    interactive: bool,
+    /// `set -e`: a failing command stops a sourced script
+    errexit: bool,
+    /// Whether the last status came from a command before the end of an
+    /// `&&`/`||` list, which `set -e` ignores as in bash
+    errexit_exempt: bool,
+    /// `set -x`: commands are echoed to stderr before they run
+    xtrace: bool,
+    /// Where stdout goes while a `$(...)` runs, in place of the terminal
+    capture: Option<PipeWriter>,
 }
 
 impl Shell {
     pub fn new(config: Config) -> Result<Self> {
+        Self::apply_config_env(&config.env);
         Ok(Self {
+            keymap: Keymap::new(&config.keybindings)?,
             config,
             history: VecDeque::new(),
             current_input: String::new(),
+            continuation: String::new(),
             cursor_pos: 0,
             history_index: None,
+            kill_buffer: String::new(),
+            vi_mode: ViMode::Insert,
+            vi_pending: None,
             completion: Completion::new(),
+            last_exit_code: 0,
+            last_duration: None,
+            variables: HashMap::new(),
+            substitution_status: None,
+            previous_dir: None,
+            dir_stack: Vec::new(),
+            jobs: Vec::new(),
+            expanding_aliases: Vec::new(),
+            interactive: false,
+            errexit: false,
+            errexit_exempt: false,
+            xtrace: false,
+            capture: None,
         })
     }
 
-    pub fn execute_command(&mut self, command: &str) -> Result<()> {
-        let trimmed = command.trim();
-        if trimmed.is_empty() {
+    /// Export the config's `[env]` table. Values expand `~` and `$VAR`
+    /// against the environment wsh started with, so they don't depend on
+    /// the order the entries are set in; `$(...)` is left as written.
+    fn apply_config_env(env: &HashMap<String, String>) {
+        let mut lookup = |name: &str| std::env::var(name).ok();
+        let values: Vec<(&String, String)> = env
+            .iter()
+            .filter(|(name, value)| {
+                let valid = Utils::is_valid_identifier(name) && !value.contains('\0');
+                if !valid {
+                    eprintln!("Ignoring config env entry {:?}", name);
+                }
+                valid
+            })
+            .map(|(name, value)| {
+                let value = Utils::expand_variables(value, &mut lookup);
+                (name, Utils::expand_path(&value))
+            })
+            .collect();
+        for (name, value) in values {
+            // SAFETY: this runs before the shell starts, when nothing else
+            // is reading the environment
+            unsafe { std::env::set_var(name, value) };
+        }
+    }
+
+    /// Run a line typed by the user or passed with `-c`, returning the
+    /// exit status of the last command that ran
+    pub fn execute_command(&mut self, command: &str) -> Result<i32> {
+        let line = Utils::trim_line(command);
+        if line.is_empty() {
+            // Nothing ran, so the prompt has no time to show
+            self.last_duration = None;
+            return Ok(self.last_exit_code);
+        }
+
+        self.add_to_history(command);
+
+        // Errors found before anything runs, like a stray `;`, set the
+        // status too
+        let started = Instant::now();
+        let result = self.run_line(line);
+        self.last_duration = Some(started.elapsed());
+        result.inspect_err(|e| self.last_exit_code = ShellError::status_of(e))?;
+        Ok(self.last_exit_code)
+    }
+
+    /// Exit status of the last command, as `$?` reports it
+    pub fn last_exit_code(&self) -> i32 {
+        self.last_exit_code
+    }
+
+    /// Run a command line of lists separated by `;` or `&`, each in the
+    /// background if it ends with `&`. A failing list doesn't stop the ones after it.
+    fn run_line(&mut self, line: &str) -> Result<()> {
+        let line = self.inline_heredocs(line)?;
+        let line = Utils::strip_comments(&line);
+        let lists = Utils::split_sequence(&line)?;
+        let count = lists.len();
+
+        for (i, (list, background)) in lists.iter().enumerate() {
+            let result = self.execute_line(list, *background);
+            if let Err(e) = result {
+                // Like execute_line, only the last error goes to the caller
+                if i + 1 == count || ShellError::is_exit(&e) {
+                    return Err(e);
+                }
+                self.report_error(&e.to_string())?;
+            }
+            if self.errexit_stops() {
+                break;
+            }
+        }
+        Ok(())
+    }
+
+    /// Whether `set -e` stops here, the last command having failed
+    fn errexit_stops(&self) -> bool {
+        self.errexit && self.last_exit_code != 0 && !self.errexit_exempt
+    }
+
+    /// Run the startup script from `path`, the config's `rcfile`, or
+    /// `~/.wshrc`. A missing default rc file is fine; anything else that
+    /// goes wrong is reported without stopping startup, though an `exit`
+    /// still exits.
+    pub fn load_rc(&mut self, path: Option<&Path>) -> Result<()> {
+        let explicit = path
+            .map(|p| p.display().to_string())
+            .or_else(|| self.config.rcfile.clone());
+        let rcfile = match explicit {
+            Some(path) => path,
+            None => {
+                let default = Utils::expand_path("~/.wshrc");
+                if !Path::new(&default).exists() {
+                    return Ok(());
+                }
+                default
+            }
+        };
+
+        match self.source_file(&rcfile, false) {
+            Err(e) if ShellError::is_exit(&e) => Err(e),
+            Err(e) => self.report_error(&e.to_string()),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Run `~/.wsh_profile`, which login shells read once before the rc
+    /// file. A missing profile is fine, and errors in it are reported
+    /// without stopping startup so a broken profile can't lock anyone out.
+    /// An `exit` in it still exits.
+    pub fn load_profile(&mut self) -> Result<()> {
+        let profile = Utils::expand_path("~/.wsh_profile");
+        if !Path::new(&profile).exists() {
             return Ok(());
         }
+        match self.source_file(&profile, false) {
+            Err(e) if ShellError::is_exit(&e) => Err(e),
+            Err(e) => self.report_error(&e.to_string()),
+            Ok(_) => Ok(()),
+        }
+    }
+
+    /// Run each command of a script file, skipping blank lines and `#`
+    /// comments. Errors are reported and the next command runs anyway,
+    /// unless `stop_on_error` is set. With `set -e` the script also stops
+    /// quietly at the first command that exits non-zero. Lines don't go
+    /// into history.
+    pub fn source_file(&mut self, path: &str, stop_on_error: bool) -> Result<i32> {
+        let expanded = Utils::expand_path(path);
+        let content =
+            std::fs::read_to_string(&expanded).map_err(|e| anyhow!("source: {}: {}", path, e))?;
+        self.run_lines(path, &content, stop_on_error)
+    }
+
+    /// Run a script given on the command line, like `wsh script.wsh`,
+    /// returning the last command's exit status. A `#!` first line is
+    /// skipped so scripts can be made executable. A script that can't be
+    /// read leaves the status at 127, as in bash.
+    pub fn run_script(&mut self, path: &Path) -> Result<i32> {
+        let content = std::fs::read_to_string(path).map_err(|e| {
+            self.last_exit_code = 127;
+            anyhow!("{}: {}", path.display(), e)
+        })?;
+        let body = match content.strip_prefix("#!") {
+            // Keep the newline so line numbers in errors still match
+            Some(shebang) => shebang.find('\n').map_or("", |end| &shebang[end..]),
+            None => &content,
+        };
+        self.run_lines(&path.display().to_string(), body, false)
+    }
+
+    /// The body of `source_file`: run each command, naming `path` and the
+    /// line it starts on in errors. As at the prompt, a command with an
+    /// unclosed quote, a trailing backslash or a here-document carries on
+    /// over the lines after it.
+    fn run_lines(&mut self, path: &str, content: &str, stop_on_error: bool) -> Result<i32> {
+        self.last_exit_code = 0;
+        let mut lines = content.lines().enumerate();
+        while let Some((number, line)) = lines.next() {
+            let line = line.trim_start();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let mut command = line.to_string();
+            while let Some(kind) = Utils::command_continuation(&command) {
+                // At the end of the file the command runs as it is
+                let Some((_, next)) = lines.next() else {
+                    break;
+                };
+                match kind {
+                    Continuation::Escaped => {
+                        command.pop();
+                    }
+                    Continuation::Unclosed => command.push('\n'),
+                }
+                command.push_str(next);
+            }
+
+            if let Err(e) = self.run_line(Utils::trim_line(&command)) {
+                self.last_exit_code = ShellError::status_of(&e);
+                if ShellError::is_exit(&e) {
+                    return Err(e);
+                }
+                let message = format!("{}:{}: {}", path, number + 1, e);
+                if stop_on_error || self.errexit {
+                    return Err(anyhow!(message));
+                }
+                self.report_error(&message)?;
+            } else if self.errexit_stops() {
+                break;
+            }
+        }
+        Ok(self.last_exit_code)
+    }
+
+    /// Report an error without stopping: drawn clear of the prompt in the
+    /// line editor, or as a plain line on stderr in a script or `-c`
+    fn report_error(&self, message: &str) -> Result<()> {
+        if self.interactive {
+            UI::print_error(&self.config, message)
+        } else {
+            eprintln!("wsh: {}", message);
+            Ok(())
+        }
+    }
+
+    /// Run a command line made of `&&`/`||` chained segments. With
+    /// `background` set the line must be a single command, which is started
+    /// as a job instead of being waited for.
+    fn execute_line(&mut self, line: &str, background: bool) -> Result<()> {
+        let segments = Utils::split_chain(line)?;
+        let count = segments.len();
+        if background && count > 1 {
+            return Err(anyhow!("background command chains are not supported"));
+        }
+
+        for (i, (connector, segment)) in segments.into_iter().enumerate() {
+            let should_run = match connector {
+                None => true,
+                Some(Connector::And) => self.last_exit_code == 0,
+                Some(Connector::Or) => self.last_exit_code != 0,
+            };
+            if !should_run {
+                continue;
+            }
+
+            self.errexit_exempt = false;
+            let result = self.execute_segment(&segment, background);
+            // An alias expanding to a list can leave this set already
+            self.errexit_exempt |= i + 1 < count;
+            match result {
+                Ok(code) => self.last_exit_code = code,
+                Err(e) => {
+                    self.last_exit_code = ShellError::status_of(&e);
+                    // Report and keep going so `||` fallbacks still run;
+                    // the last segment's error goes back to the caller
+                    if i + 1 == count || ShellError::is_exit(&e) {
+                        return Err(e);
+                    }
+                    self.report_error(&e.to_string())?;
+                }
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Replace each `<<WORD` here-document with a `<<` operator followed by
+    /// its body as one quoted word, dropping the body and delimiter lines.
+    /// Unquoted delimiters have their bodies' variables expanded first.
+    fn inline_heredocs(&mut self, line: &str) -> Result<String> {
+        let heredocs = Utils::heredocs(line);
+        if heredocs.docs.is_empty() {
+            return Ok(line.to_string());
+        }
+
+        let mut command = heredocs.command;
+        for doc in heredocs.docs.iter().rev() {
+            let body = doc.body.as_deref().ok_or_else(|| {
+                anyhow!(
+                    "here-document ended before its delimiter (wanted '{}')",
+                    doc.delimiter
+                )
+            })?;
+            let body = if doc.expand {
+                Utils::expand_variables(body, self)
+            } else {
+                body.to_string()
+            };
+            command.replace_range(
+                doc.range.clone(),
+                &format!("<< {}", Utils::single_quote(&body)),
+            );
+        }
+        Ok(command)
+    }
+
+    /// Resolve a variable for expansion, including special parameters
+    fn lookup_variable(&self, name: &str) -> Option<String> {
+        match name {
+            "?" => Some(self.last_exit_code.to_string()),
+            _ => self
+                .variables
+                .get(name)
+                .cloned()
+                .or_else(|| std::env::var(name).ok()),
+        }
+    }
+
+    /// Set a shell variable. As in bash, one that's already exported is
+    /// updated in the environment instead, so children see the new value.
+    fn set_variable(&mut self, name: &str, value: &str) {
+        if std::env::var_os(name).is_some() {
+            // SAFETY: only the main thread reads the environment itself.
+            // Completion threads are handed copies of `$PATH` and `$HOME`,
+            // and a `--help` they run is spawned under std's environment
+            // lock, which `set_var` and `remove_var` take too.
+            unsafe { std::env::set_var(name, value) };
+        } else {
+            self.variables.insert(name.to_string(), value.to_string());
+        }
+    }
+
+    /// Run `command` for a `$(command)`, returning what it wrote to stdout
+    /// with trailing newlines removed. Unlike bash it runs in this shell
+    /// rather than a subshell, so a `cd` or `export` inside it sticks;
+    /// only `exit` stops at the substitution.
+    fn capture_output(&mut self, command: &str) -> Result<String> {
+        let (mut reader, writer) = io::pipe()?;
+        // Read as it's written, so a command with a lot to say can't fill
+        // the pipe and wait forever
+        let collector = std::thread::spawn(move || {
+            let mut output = Vec::new();
+            let _ = reader.read_to_end(&mut output);
+            output
+        });
+
+        let outer = self.capture.replace(writer);
+        let exempt = self.errexit_exempt;
+        let result = self.run_line(command);
+        // Dropping our write end lets the collector see end of file
+        self.capture = outer;
+        self.errexit_exempt = exempt;
+        let output = collector.join().unwrap_or_default();
+        if let Err(e) = result {
+            self.last_exit_code = ShellError::status_of(&e);
+            if !ShellError::is_exit(&e) {
+                self.report_error(&e.to_string())?;
+            }
+        }
+        self.substitution_status = Some(self.last_exit_code);
+
+        let output = String::from_utf8_lossy(&output);
+        Ok(output.trim_end_matches('\n').to_string())
+    }
+
+    /// Run a single command or pipeline, returning its exit code. Leading
+    /// `NAME=value` words set shell variables, or with a command after
+    /// them, put NAME in that command's environment alone.
+    fn execute_segment(&mut self, segment: &str, background: bool) -> Result<i32> {
+        let (assignments, command) = Utils::split_assignments(segment);
+        // The command's words are expanded before the assignments, so
+        // `X=1 echo $X` still prints the old value
+        let tokens = Utils::tokenize_command_with(command, self);
+        self.substitution_status = None;
+        let mut saved = Vec::new();
+        let mut expanded = Vec::new();
+        for (name, value) in assignments {
+            // Each value sees the assignments before it
+            let value = Utils::expand_value(&value, self);
+            if self.xtrace {
+                expanded.push((name.clone(), value.clone()));
+            }
+            if command.is_empty() {
+                self.set_variable(&name, &value);
+                continue;
+            }
+            // Put in the environment for the command's run, keeping what
+            // was there to put back after. A shell variable by the same
+            // name is moved aside so expansion sees the new value too.
+            saved.push((
+                name.clone(),
+                std::env::var_os(&name),
+                self.variables.remove(&name),
+            ));
+            // SAFETY: as in `set_variable`
+            unsafe { std::env::set_var(&name, value) };
+        }
+        if self.xtrace {
+            UI::print_trace(&Self::trace_words(&expanded, &tokens))?;
+        }
+        if command.is_empty() {
+            // As in bash, `x=$(cmd)` fails if cmd does; a blank command
+            // just succeeds
+            return Ok(self.substitution_status.unwrap_or(0));
+        }
+
+        let result = self.execute_tokens(command, &tokens, background);
+        for (name, value, local) in saved {
+            // SAFETY: as above
+            unsafe {
+                match value {
+                    Some(value) => std::env::set_var(&name, value),
+                    None => std::env::remove_var(&name),
+                }
+            }
+            if let Some(local) = local {
+                self.variables.insert(name, local);
+            }
+        }
+        result
+    }
 
-        // Add to history
-        self.add_to_history(trimmed.to_string());
+    /// What `set -x` prints for a command or pipeline: its assignments
+    /// and words as expanded, operators included
+    fn trace_words(assignments: &[(String, String)], tokens: &[Token]) -> Vec<String> {
+        assignments
+            .iter()
+            .map(|(name, value)| format!("{}={}", name, value))
+            .chain(tokens.iter().map(|token| token.text().to_string()))
+            .collect()
+    }
 
-        let tokens = Utils::parse_command(trimmed);
+    /// Run a command or pipeline from its expanded `tokens`, with `segment`
+    /// what they came from
+    fn execute_tokens(&mut self, segment: &str, tokens: &[Token], background: bool) -> Result<i32> {
         if tokens.is_empty() {
-            return Ok(());
+            return Ok(0);
+        }
+
+        // A `&` that didn't end a list, like one in an alias's value, has
+        // no list to send to the background
+        if tokens.contains(&Token::Op("&")) {
+            return Err(ShellError::UnexpectedToken("&".to_string()).into());
+        }
+
+        if tokens.contains(&Token::Op("|")) {
+            if background {
+                return Err(anyhow!("background pipelines are not supported"));
+            }
+            // Each stage's command can be an alias, expanded as below
+            if let Some((expanded, names)) = self.expand_stage_aliases(segment) {
+                let depth = self.expanding_aliases.len();
+                self.expanding_aliases.extend(names);
+                let result = self.execute_line(&expanded, background);
+                self.expanding_aliases.truncate(depth);
+                result?;
+                return Ok(self.last_exit_code);
+            }
+            return self.execute_pipeline(tokens);
         }
 
-        let command_name = &tokens[0];
+        let words: Vec<String> = tokens.iter().map(|t| t.text().to_string()).collect();
+
+        // `builtin name` and `command name` run name without looking it up
+        // as an alias, so a builtin stays reachable when an alias shadows it.
+        // Like POSIX `command`, they can be stacked: `command builtin cd`.
+        let mut tokens = tokens;
+        let mut words = words.as_slice();
+        let mut skip_aliases = false;
+        while let [Token::Word(prefix, _), _, ..] = tokens
+            && matches!(prefix.as_str(), "builtin" | "command")
+        {
+            if prefix == "builtin" && !builtins::is_builtin(&words[1]) {
+                return Err(anyhow!("builtin: {}: not a shell builtin", words[1]));
+            }
+            tokens = &tokens[1..];
+            words = &words[1..];
+            skip_aliases = true;
+        }
+
+        let Token::Word(command_name, quoted) = &tokens[0] else {
+            return Err(ShellError::UnexpectedToken(words[0].clone()).into());
+        };
         let args = &tokens[1..];
 
-        // Check for aliases
-        if let Some(alias_command) = self.config.aliases.get(command_name).cloned() {
-            return self.execute_command(&alias_command);
+        // Expand aliases, keeping the rest of the segment as typed so its
+        // arguments and redirections still apply. Like bash, an alias isn't
+        // expanded again inside its own expansion, so `alias ls='ls -F'`
+        // runs the real `ls`, and quoting the name (`\ls`) skips it.
+        if !quoted
+            && !skip_aliases
+            && !self.expanding_aliases.contains(command_name)
+            && let Some(alias_command) = self.config.aliases.get(command_name).cloned()
+        {
+            let rest = Utils::command_words(segment)
+                .first()
+                .map_or("", |word| &segment[word.end..]);
+            let expanded = format!("{}{}", alias_command, rest);
+
+            self.expanding_aliases.push(command_name.clone());
+            let result = self.execute_line(&expanded, background);
+            self.expanding_aliases.pop();
+            result?;
+            return Ok(self.last_exit_code);
         }
 
-        // Handle built-in commands
-        if Utils::is_builtin(command_name) {
+        // Handle built-in commands; these always run in the foreground
+        if builtins::is_builtin(command_name) {
             self.execute_builtin(command_name, args)
+        } else if background {
+            self.spawn_background(command_name, args)
         } else {
             self.execute_external(command_name, args)
         }
     }
 
-    pub fn run_interactive(&mut self) -> Result<()> {
+    /// `segment` with the command word of each pipeline stage that names
+    /// an alias replaced by its value, and the names replaced, or `None` if
+    /// there were none. A quoted name never matches, as its text differs.
+    fn expand_stage_aliases(&self, segment: &str) -> Option<(String, Vec<String>)> {
+        let mut expanded = segment.to_string();
+        let mut names = Vec::new();
+        for word in Utils::command_words(segment).into_iter().rev() {
+            let name = &segment[word.clone()];
+            if self
+                .expanding_aliases
+                .iter()
+                .any(|expanding| expanding == name)
+            {
+                continue;
+            }
+            if let Some(alias_command) = self.config.aliases.get(name) {
+                expanded.replace_range(word, alias_command);
+                names.push(name.to_string());
+            }
+        }
+        (!names.is_empty()).then_some((expanded, names))
+    }
+
+    /// Read and run commands until Ctrl+D or `exit`, returning the status
+    /// to exit with
+    pub fn run_interactive(&mut self) -> Result<i32> {
         UI::display_welcome()?;
 
-        terminal::enable_raw_mode()?;
+        // Ctrl+C must reach foreground commands without killing the shell
+        Utils::catch_sigint();
+        Utils::restore_terminal_on_sigterm();
+        let raw_mode = RawModeGuard::enter()?;
+        self.interactive = true;
 
-        loop {
-            UI::display_prompt(&self.config, &self.current_input, self.cursor_pos)?;
+        let code = loop {
+            self.reap_jobs()?;
+            UI::display_prompt(
+                &self.config,
+                self.prompt_state(),
+                &self.current_input,
+                self.cursor_pos,
+                "",
+            )?;
 
             match self.read_input()? {
                 InputResult::Command(cmd) => {
                     UI::print_newline()?; // New line after input
                     if let Err(e) = self.execute_command(&cmd) {
+                        // Leave through the guards, so the terminal is
+                        // put back the way it was
+                        if ShellError::is_exit(&e) {
+                            break self.last_exit_code;
+                        }
                         UI::print_error(&self.config, &format!("Error: {}", e))?;
                     }
+                    // A command stopped by Ctrl+C (128 + SIGINT) leaves the
+                    // cursor just after the terminal's echoed ^C
+                    if self.last_exit_code == 130 {
+                        UI::print_newline()?;
+                    }
+                    // The command may have changed branch or touched files
+                    Utils::invalidate_git_prompt();
                     self.reset_input();
                 }
-                InputResult::Exit => break,
+                InputResult::Exit => break 0,
             }
-        }
+        };
 
-        terminal::disable_raw_mode()?;
+        drop(raw_mode);
         UI::display_goodbye()?;
-        Ok(())
+        Ok(code)
     }
 
-    fn add_to_history(&mut self, command: String) {
-        // Don't add duplicate consecutive commands
-        if self.history.back() != Some(&command) {
-            self.history.push_back(command);
+    /// Record a command line as it's run, trimmed by `Utils::trim_line`,
+    /// subject to the config's `history_control`. Recalling an entry puts
+    /// back exactly what was recorded.
+    fn add_to_history(&mut self, command: &str) {
+        let control = &self.config.history_control;
+        if control.contains(&HistoryControl::IgnoreSpace) && command.starts_with(' ') {
+            return;
+        }
 
-            // Limit history size
-            while self.history.len() > self.config.history_size {
-                self.history.pop_front();
-            }
+        let command = Utils::trim_line(command);
+        if control.contains(&HistoryControl::IgnoreDups)
+            && self
+                .history
+                .back()
+                .is_some_and(|last| last.command == command)
+        {
+            return;
         }
+        if control.contains(&HistoryControl::EraseDups) {
+            self.history.retain(|entry| entry.command != command);
+        }
+        self.history.push_back(HistoryEntry::new(command));
+        self.trim_history();
     }
 
-    fn execute_builtin(&mut self, command: &str, args: &[String]) -> Result<()> {
-        match command {
-            "cd" => {
-                let path = args.get(0).map(String::as_str).unwrap_or("");
-                Utils::change_directory(path)?;
-                Ok(())
-            }
-            "pwd" => {
-                let current_dir = Utils::get_current_dir()?;
-                execute!(stdout(), Print(&format!("{}\n", current_dir)))?;
-                Ok(())
-            }
-            "exit" => std::process::exit(0),
-            "help" => {
-                UI::show_help()?;
-                Ok(())
-            }
-            "history" => {
-                UI::show_history(&self.history)?;
-                Ok(())
-            }
-            "alias" => {
-                if args.len() == 2 {
-                    self.config.aliases.insert(args[0].clone(), args[1].clone());
-                    execute!(
-                        stdout(),
-                        Print(&format!("Alias '{}' -> '{}' added\n", args[0], args[1]))
-                    )?;
-                } else {
-                    for (alias, command) in &self.config.aliases {
-                        execute!(stdout(), Print(&format!("{} -> {}\n", alias, command)))?;
-                    }
-                }
-                Ok(())
-            }
-            _ => Err(anyhow!("Unknown built-in command: {}", command)),
+    /// Drop the oldest entries beyond `history_size`; 0 means unlimited
+    fn trim_history(&mut self) {
+        let size = self.config.history_size;
+        if size > 0 && self.history.len() > size {
+            self.history.drain(..self.history.len() - size);
         }
     }
 
-    fn execute_external(&self, command: &str, args: &[String]) -> Result<()> {
-        // Disable raw mode temporarily for external commands
-        terminal::disable_raw_mode()?;
+    /// Run a builtin through the registry in `builtins`, reading the
+    /// shell's stdin; each one's work is done by the `builtin_*` method below
+    fn execute_builtin(&mut self, command: &str, args: &[Token]) -> Result<i32> {
+        self.execute_builtin_with(command, args, &mut ShellStdin::default())
+    }
 
-        let result = Command::new(command).args(args).status(); // Use .status() instead of .output()
+    /// Run a builtin reading `input` and writing to the shell's stdout, or
+    /// to the `$(...)` capturing it
+    fn execute_builtin_with(
+        &mut self,
+        command: &str,
+        args: &[Token],
+        input: &mut dyn Input,
+    ) -> Result<i32> {
+        match self
+            .capture
+            .as_ref()
+            .map(PipeWriter::try_clone)
+            .transpose()?
+        {
+            Some(mut capture) => self.run_builtin(command, args, input, &mut capture),
+            None => self.run_builtin(command, args, input, &mut stdout()),
+        }
+    }
 
-        // Re-enable raw mode
-        terminal::enable_raw_mode()?;
+    /// Run a builtin reading `input` and writing to `out`, unless its
+    /// redirections send them elsewhere. With stderr redirected, an error
+    /// is written there too rather than returned to be reported.
+    fn run_builtin(
+        &mut self,
+        command: &str,
+        args: &[Token],
+        input: &mut dyn Input,
+        out: &mut dyn Write,
+    ) -> Result<i32> {
+        let builtin = builtins::find(command)
+            .ok_or_else(|| anyhow!("Unknown built-in command: {}", command))?;
+        let (args, redirections) = Redirections::extract(args)?;
+        let files = redirections.open_files()?;
+        let mut redirected = redirections.open_input()?;
+        let input = match redirected.as_deref_mut() {
+            Some(redirected) => redirected,
+            None => input,
+        };
 
+        let stdout = RefCell::new(out);
+        let mut out = BuiltinOutput::new(redirections.stdout, &stdout, &files);
+        let mut err = BuiltinOutput::new(redirections.stderr, &stdout, &files);
+        let result = builtin.run(self, &args, input, &mut out, &mut err);
+        out.flush()?;
         match result {
-            Ok(status) => {
-                if status.success() {
-                    Ok(())
-                } else {
-                    Err(anyhow!("Command '{}' exited with non-zero status", command))
-                }
+            Err(e) if redirections.stderr != Output::Stderr && !ShellError::is_exit(&e) => {
+                writeln!(err, "wsh: {}", e)?;
+                Ok(ShellError::status_of(&e))
             }
-            Err(e) => Err(anyhow!("Failed to execute '{}': {}", command, e)),
+            result => result,
         }
     }
 
-    fn read_input(&mut self) -> Result<InputResult> {
-        loop {
-            if let Event::Key(KeyEvent {
-                code, modifiers, ..
-            }) = event::read()?
-            {
-                match (code, modifiers) {
-                    (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
-                        return Ok(InputResult::Exit);
-                    }
-                    (KeyCode::Char('d'), KeyModifiers::CONTROL) => {
-                        if self.current_input.is_empty() {
-                            return Ok(InputResult::Exit);
-                        }
-                    }
-                    (KeyCode::Enter, _) => {
-                        let command = self.current_input.clone();
-                        return Ok(InputResult::Command(command));
-                    }
-                    (KeyCode::Backspace, _) => {
-                        self.reset_completion();
-                        if self.cursor_pos > 0 {
-                            self.current_input.remove(self.cursor_pos - 1);
-                            self.cursor_pos -= 1;
-                            UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
-                        }
-                    }
-                    (KeyCode::Delete, _) => {
-                        self.reset_completion();
-                        if self.cursor_pos < self.current_input.len() {
-                            self.current_input.remove(self.cursor_pos);
-                            UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
-                        }
-                    }
-                    (KeyCode::Left, _) => {
-                        if self.cursor_pos > 0 {
-                            self.cursor_pos -= 1;
-                            execute!(stdout(), cursor::MoveLeft(1))?;
-                        }
-                    }
-                    (KeyCode::Right, _) => {
-                        if self.cursor_pos < self.current_input.len() {
-                            self.cursor_pos += 1;
-                            execute!(stdout(), cursor::MoveRight(1))?;
-                        }
-                    }
-                    (KeyCode::Up, _) => {
-                        self.navigate_history(true)?;
-                    }
-                    (KeyCode::Down, _) => {
-                        self.navigate_history(false)?;
-                    }
-                    (KeyCode::Home, _) => {
-                        execute!(stdout(), cursor::MoveLeft(self.cursor_pos as u16))?;
-                        self.cursor_pos = 0;
-                    }
-                    (KeyCode::End, _) => {
-                        let move_right = self.current_input.len() - self.cursor_pos;
-                        execute!(stdout(), cursor::MoveRight(move_right as u16))?;
-                        self.cursor_pos = self.current_input.len();
-                    }
-                    (KeyCode::Tab, _) => {
-                        self.handle_tab_completion()?;
-                    }
-                    (KeyCode::Char(c), _) => {
-                        self.reset_completion();
-                        self.current_input.insert(self.cursor_pos, c);
-                        self.cursor_pos += 1;
-                        UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
-                    }
-                    _ => {}
+    pub(crate) fn builtin_cd(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let path = args.first().map(String::as_str).unwrap_or("");
+        if path == "-" {
+            let previous = self
+                .previous_dir
+                .clone()
+                .ok_or_else(|| anyhow!("cd: OLDPWD not set"))?;
+            self.change_directory(&previous.display().to_string())
+                .map_err(|e| anyhow!("cd: {}", e))?;
+            // Like bash, announce where `cd -` landed
+            let current_dir = Utils::get_current_dir()?;
+            writeln!(out, "{}", current_dir)?;
+        } else if self
+            .change_directory(path)
+            .map_err(|e| anyhow!("cd: {}", e))?
+            .is_some()
+        {
+            // ...and likewise where $CDPATH led
+            let current_dir = std::env::current_dir()?;
+            writeln!(out, "{}", current_dir.display())?;
+        }
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_pwd(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let current_dir = Utils::get_current_dir()?;
+        writeln!(out, "{}", current_dir)?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_pushd(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let current = std::env::current_dir()?;
+        match args.first() {
+            Some(dir) => {
+                self.change_directory(dir)
+                    .map_err(|e| anyhow!("pushd: {}", e))?;
+            }
+            // Like bash, a bare pushd swaps the top two directories
+            None => {
+                let top = self
+                    .dir_stack
+                    .pop()
+                    .ok_or_else(|| anyhow!("pushd: no other directory"))?;
+                if let Err(e) = self.change_directory(&top.display().to_string()) {
+                    self.dir_stack.push(top);
+                    return Err(anyhow!("pushd: {}", e));
                 }
             }
         }
+        self.dir_stack.push(current);
+        self.print_dir_stack(out)?;
+        Ok(0)
     }
 
-    fn navigate_history(&mut self, up: bool) -> Result<()> {
-        if self.history.is_empty() {
-            return Ok(());
+    pub(crate) fn builtin_popd(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let top = self
+            .dir_stack
+            .last()
+            .ok_or_else(|| anyhow!("popd: directory stack empty"))?;
+        // Only drop the entry once the cd has worked
+        self.change_directory(&top.display().to_string())
+            .map_err(|e| anyhow!("popd: {}", e))?;
+        self.dir_stack.pop();
+        self.print_dir_stack(out)?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_dirs(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        self.print_dir_stack(out)?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_exit(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        // Like bash, a bare `exit` reports the last command's status
+        let code = match args.first() {
+            Some(arg) => arg
+                .parse::<i32>()
+                .map_err(|_| anyhow!("exit: {}: numeric argument required", arg))?,
+            None => self.last_exit_code,
+        };
+        // Unwound to the top of the shell, or inside `$(...)` only as far
+        // as the substitution, as bash's subshell would
+        Err(ShellError::Exit(code).into())
+    }
+
+    pub(crate) fn builtin_help(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        if args.is_empty() {
+            UI::show_help(out)?;
         }
+        for name in args {
+            UI::show_builtin_help(out, name)?;
+        }
+        Ok(0)
+    }
 
-        let new_index = match self.history_index {
-            None if up => Some(self.history.len() - 1),
-            None => return Ok(()),
-            Some(i) if up && i > 0 => Some(i - 1),
-            Some(i) if !up && i < self.history.len() - 1 => Some(i + 1),
-            Some(_) if !up => {
+    pub(crate) fn builtin_version(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let features = match env!("WSH_FEATURES") {
+            "" => "none",
+            features => features,
+        };
+        writeln!(out, "wsh {}", env!("CARGO_PKG_VERSION"))?;
+        writeln!(out, "{}", env!("WSH_RUSTC_VERSION"))?;
+        writeln!(
+            out,
+            "target: {} ({})",
+            env!("WSH_TARGET"),
+            env!("WSH_PROFILE")
+        )?;
+        writeln!(out, "features: {}", features)?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_history(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let numbered = || {
+            self.history
+                .iter()
+                .enumerate()
+                .map(|(i, entry)| (i + 1, entry))
+        };
+        let entries: Vec<_> = match args {
+            [] => numbered().collect(),
+            [arg] if arg == "clear" || arg == "-c" => {
+                self.history.clear();
                 self.history_index = None;
-                self.current_input.clear();
-                self.cursor_pos = 0;
-                UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
-                return Ok(());
+                return Ok(0);
+            }
+            [flag, position] if flag == "-d" => {
+                let index = position
+                    .parse::<usize>()
+                    .ok()
+                    .filter(|&n| n >= 1 && n <= self.history.len())
+                    .ok_or_else(|| {
+                        anyhow!("history: {}: history position out of range", position)
+                    })?;
+                self.history.remove(index - 1);
+                self.history_index = None;
+                return Ok(0);
+            }
+            [subcommand, terms @ ..] if subcommand == "search" && !terms.is_empty() => {
+                let term = terms.join(" ");
+                let matches: Vec<_> = numbered()
+                    .filter(|(_, entry)| entry.command.contains(&term))
+                    .collect();
+                if matches.is_empty() {
+                    return Ok(1);
+                }
+                matches
+            }
+            [count] if count.parse::<usize>().is_ok() => {
+                let count = count.parse::<usize>().unwrap_or(0);
+                let skip = self.history.len().saturating_sub(count);
+                numbered().skip(skip).collect()
+            }
+            _ => {
+                return Err(anyhow!(
+                    "history: usage: history [N | clear | search <term> | -d N]"
+                ));
             }
-            _ => return Ok(()),
         };
+        UI::show_history(out, &entries, self.config.show_timestamps)?;
+        Ok(0)
+    }
 
-        self.history_index = new_index;
-        if let Some(index) = new_index {
-            self.current_input = self.history[index].clone();
-            self.cursor_pos = self.current_input.len();
-            UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
+    pub(crate) fn builtin_alias(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> Result<i32> {
+        let Some((first, rest)) = args.split_first() else {
+            for line in self.alias_listing() {
+                writeln!(out, "{}", line)?;
+            }
+            return Ok(0);
+        };
+        // `name=value` as bash prints it, or `name value`; words
+        // after the first are joined, so `alias ll=ls -la` works
+        // without quotes
+        let (name, value) = match first.split_once('=') {
+            Some((name, value)) => {
+                let value = std::iter::once(value)
+                    .chain(rest.iter().map(String::as_str))
+                    .collect::<Vec<_>>()
+                    .join(" ");
+                (name, Some(value))
+            }
+            None if rest.is_empty() => (first.as_str(), None),
+            None => (first.as_str(), Some(rest.join(" "))),
+        };
+        if name.is_empty() {
+            return Err(anyhow!("alias: usage: alias [name[=value] ...]"));
         }
-
-        Ok(())
+        if !Utils::is_valid_alias_name(name) {
+            return Err(anyhow!("alias: '{}': invalid alias name", name));
+        }
+        match value {
+            // Like bash, adding one is quiet, so an rc file full of them
+            // doesn't talk at every startup
+            Some(value) => {
+                self.config.aliases.insert(name.to_string(), value);
+                self.config.persist_aliases()?;
+                // The alias was still added, so this is no error
+                if builtins::is_builtin(name) {
+                    writeln!(
+                        err,
+                        "wsh: alias: warning: '{0}' shadows the builtin; \
+                         use `builtin {0}` to run it",
+                        name
+                    )?;
+                }
+            }
+            // Just a name shows that one alias
+            None => {
+                let value = self
+                    .config
+                    .aliases
+                    .get(name)
+                    .ok_or_else(|| anyhow!("alias: {}: not found", name))?;
+                writeln!(out, "{}={}", name, Utils::single_quote(value))?;
+            }
+        }
+        Ok(0)
     }
 
-    fn reset_input(&mut self) {
-        self.current_input.clear();
-        self.cursor_pos = 0;
-        self.history_index = None;
-        self.reset_completion();
+    pub(crate) fn builtin_source(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let (stop_on_error, args) = match args.first().map(String::as_str) {
+            Some("-e") => (true, &args[1..]),
+            _ => (false, args),
+        };
+        let path = args
+            .first()
+            .ok_or_else(|| anyhow!("source: filename argument required"))?;
+        self.source_file(path, stop_on_error)
     }
 
-    fn reset_completion(&mut self) {
-        self.completion.reset();
+    pub(crate) fn builtin_reload(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let path = self
+            .config
+            .path
+            .clone()
+            .unwrap_or_else(Config::default_path);
+        let config =
+            Config::read(&path).map_err(|e| anyhow!("reload: {}: {}", path.display(), e))?;
+        self.keymap = Keymap::new(&config.keybindings)
+            .map_err(|e| anyhow!("reload: {}: {}", path.display(), e))?;
+        self.config = config;
+        // A smaller history_size applies to what's already recorded
+        self.trim_history();
+        Utils::invalidate_git_prompt();
+        writeln!(out, "Reloaded config from {}", path.display())?;
+        Ok(0)
     }
 
-    fn handle_tab_completion(&mut self) -> Result<()> {
-        if self.completion.is_empty() {
-            // Generate completions
-            self.completion.generate(
-                &self.current_input,
-                self.cursor_pos,
-                &self.config,
-                &self.history,
-            );
-            if self.completion.is_empty() {
-                return Ok(());
+    pub(crate) fn builtin_set(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        if args.is_empty() {
+            for (name, on) in [("errexit", self.errexit), ("xtrace", self.xtrace)] {
+                let state = if on { "on" } else { "off" };
+                writeln!(out, "{:<15}{}", name, state)?;
             }
+            return Ok(0);
+        }
 
-            // Start completion and apply first match
-            self.completion.start(&self.current_input, self.cursor_pos);
-            self.completion
-                .apply(&mut self.current_input, &mut self.cursor_pos)?;
-        } else {
-            // Cycle to next completion
-            self.completion.cycle_next();
-            self.completion
-                .apply(&mut self.current_input, &mut self.cursor_pos)?;
+        let mut args = args.iter();
+        while let Some(arg) = args.next() {
+            let (on, flags) = match arg.split_at_checked(1) {
+                Some(("-", flags)) if !flags.is_empty() => (true, flags),
+                Some(("+", flags)) if !flags.is_empty() => (false, flags),
+                _ => return Err(anyhow!("set: {}: invalid argument", arg)),
+            };
+            // `-o name` spells an option out in full
+            let names: Vec<String> = if flags == "o" {
+                let name = args
+                    .next()
+                    .ok_or_else(|| anyhow!("set: {}o: option name required", &arg[..1]))?;
+                vec![name.clone()]
+            } else {
+                flags.chars().map(String::from).collect()
+            };
+            for name in names {
+                match name.as_str() {
+                    "e" | "errexit" => self.errexit = on,
+                    "x" | "xtrace" => self.xtrace = on,
+                    _ => return Err(anyhow!("set: {}: invalid option", name)),
+                }
+            }
         }
+        Ok(0)
+    }
 
-        // Redraw the line
-        UI::redraw_line(&self.config, &self.current_input, self.cursor_pos)?;
-        Ok(())
+    pub(crate) fn builtin_true(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        Ok(0)
     }
 
-    // All completion logic moved to completion.rs
-}
+    pub(crate) fn builtin_false(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        Ok(1)
+    }
 
-enum InputResult {
-    Command(String),
-    Exit,
+    pub(crate) fn builtin_rehash(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        self.completion.rehash();
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_clear(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        // Unlike Ctrl+L there's no line to keep; the next prompt
+        // lands at the top
+        out.queue(terminal::Clear(terminal::ClearType::All))?
+            .queue(cursor::MoveTo(0, 0))?;
+        Ok(0)
+    }
+
+    /// With a command to run these are handled in `execute_segment`;
+    /// alone they do nothing
+    pub(crate) fn builtin_prefix(
+        &mut self,
+        _args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_unalias(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        if args.is_empty() {
+            return Err(anyhow!("unalias: usage: unalias name [name ...]"));
+        }
+        for name in args {
+            if self.config.aliases.remove(name).is_none() {
+                return Err(anyhow!("unalias: {}: not found", name));
+            }
+        }
+        self.config.persist_aliases()?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_echo(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let mut newline = true;
+        let mut escapes = false;
+        let mut words = args;
+        // Leading flag words like `-n` or `-ne`; anything else is text
+        while let Some(flag) = words.first()
+            && flag.len() > 1
+            && flag.starts_with('-')
+            && flag[1..].chars().all(|c| matches!(c, 'n' | 'e' | 'E'))
+        {
+            for c in flag[1..].chars() {
+                match c {
+                    'n' => newline = false,
+                    'e' => escapes = true,
+                    _ => escapes = false,
+                }
+            }
+            words = &words[1..];
+        }
+
+        let mut output = words.join(" ");
+        if escapes {
+            let (expanded, stop) = Utils::interpret_escapes(&output);
+            output = expanded;
+            newline &= !stop;
+        }
+        if newline {
+            output.push('\n');
+        }
+        write!(out, "{}", output)?;
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_which(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let mut code = 0;
+        for name in args {
+            let line = match self.resolve(name) {
+                Resolution::Alias(command) => format!("{}: aliased to {}", name, command),
+                Resolution::Builtin => format!("{}: shell builtin", name),
+                Resolution::File(path) => path.display().to_string(),
+                Resolution::NotFound => {
+                    code = 1;
+                    format!("{} not found", name)
+                }
+            };
+            writeln!(out, "{}", line)?;
+        }
+        Ok(code)
+    }
+
+    pub(crate) fn builtin_type(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        let mut code = 0;
+        for name in args {
+            let line = match self.resolve(name) {
+                Resolution::Alias(command) => {
+                    format!("{} is aliased to `{}'", name, command)
+                }
+                Resolution::Builtin => format!("{} is a shell builtin", name),
+                Resolution::File(path) => format!("{} is {}", name, path.display()),
+                Resolution::NotFound => {
+                    code = 1;
+                    format!("type: {}: not found", name)
+                }
+            };
+            writeln!(out, "{}", line)?;
+        }
+        Ok(code)
+    }
+
+    pub(crate) fn builtin_export(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        if args.is_empty() {
+            let mut vars: Vec<(String, String)> = std::env::vars().collect();
+            vars.sort();
+            for (name, value) in vars {
+                writeln!(out, "{}={}", name, value)?;
+            }
+            return Ok(0);
+        }
+
+        for arg in args {
+            let (name, value) = match arg.split_once('=') {
+                Some((name, value)) => (name, Some(value)),
+                None => (arg.as_str(), None),
+            };
+            if !Utils::is_valid_identifier(name) {
+                return Err(anyhow!("export: '{}': not a valid identifier", arg));
+            }
+            // A bare name exports the shell variable by that name, if any;
+            // one already in the environment is visible to children
+            let local = self.variables.remove(name);
+            if let Some(value) = value.or(local.as_deref()) {
+                // SAFETY: as in `set_variable`
+                unsafe { std::env::set_var(name, value) };
+            }
+        }
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_unset(
+        &mut self,
+        args: &[String],
+        _input: &mut dyn Input,
+        _out: &mut dyn Write,
+        _err: &mut dyn Write,
+    ) -> Result<i32> {
+        // Like bash, unsetting a variable that isn't set is fine
+        for name in args {
+            if !Utils::is_valid_identifier(name) {
+                return Err(anyhow!("unset: '{}': not a valid identifier", name));
+            }
+            self.variables.remove(name);
+            // SAFETY: as in `set_variable`
+            unsafe { std::env::remove_var(name) };
+        }
+        Ok(0)
+    }
+
+    pub(crate) fn builtin_read(
+        &mut self,
+        args: &[String],
+        input: &mut dyn Input,
+        _out: &mut dyn Write,
+        err: &mut dyn Write,
+    ) -> Result<i32> {
+        let (prompt, names) = match args {
+            [flag, prompt, names @ ..] if flag == "-p" => (Some(prompt), names),
+            [flag] if flag == "-p" => return Err(anyhow!("read: -p: option requires an argument")),
+            names => (None, names),
+        };
+        if let Some(name) = names.iter().find(|name| !Utils::is_valid_identifier(name)) {
+            return Err(anyhow!("read: '{}': not a valid identifier", name));
+        }
+        if !input.is_terminal() {
+            return self.read_into(input, names);
+        }
+
+        // Read cooked, so the line gets the terminal's echo and editing
+        let _cooked = CookedMode::enter()?;
+        // Like bash, the prompt goes to stderr and only to a terminal
+        if let Some(prompt) = prompt {
+            write!(err, "{}", prompt)?;
+            err.flush()?;
+        }
+        self.read_into(input, names)
+    }
+
+    /// Read a line from `input` into the variables `names`, or all of it
+    /// into `REPLY` if there are none. The line is split into words at
+    /// runs of `$IFS` characters, the last name taking whatever is left.
+    /// Backslashes are kept, as with `read -r`. Returns 1 at end of input.
+    fn read_into(&mut self, input: &mut dyn BufRead, names: &[String]) -> Result<i32> {
+        let mut line = String::new();
+        if input.read_line(&mut line)? == 0 {
+            return Ok(1);
+        }
+        let line = line.strip_suffix('\n').unwrap_or(&line);
+        if names.is_empty() {
+            self.set_variable("REPLY", line);
+            return Ok(0);
+        }
+
+        let ifs = self
+            .lookup_variable("IFS")
+            .unwrap_or_else(|| " \t\n".to_string());
+        let is_separator = |ch: char| ifs.contains(ch);
+        let mut rest = line.trim_matches(is_separator);
+        for (i, name) in names.iter().enumerate() {
+            let value = if i + 1 == names.len() {
+                rest
+            } else {
+                let (word, after) = rest.split_at(rest.find(is_separator).unwrap_or(rest.len()));
+                rest = after.trim_start_matches(is_separator);
+                word
+            };
+            self.set_variable(name, value);
+        }
+        Ok(0)
+    }
+
+    /// Work out what running `name` would do, checking in the same order as
+    /// `execute_segment`: aliases, then builtins, then `$PATH`
+    fn resolve(&self, name: &str) -> Resolution {
+        if let Some(command) = self.config.aliases.get(name) {
+            Resolution::Alias(command.clone())
+        } else if builtins::is_builtin(name) {
+            Resolution::Builtin
+        } else if let Some(path) = Utils::find_in_path(name) {
+            Resolution::File(path)
+        } else {
+            Resolution::NotFound
+        }
+    }
+
+    /// Print the current directory followed by the `pushd` stack, newest
+    /// first, with the home directory shown as `~`
+    fn print_dir_stack(&self, out: &mut dyn Write) -> Result<()> {
+        let home = std::env::var("HOME").ok().filter(|home| !home.is_empty());
+        let current = std::env::current_dir()?;
+        let dirs: Vec<String> = std::iter::once(&current)
+            .chain(self.dir_stack.iter().rev())
+            .map(|dir| {
+                let dir = dir.display().to_string();
+                match home.as_deref().and_then(|home| dir.strip_prefix(home)) {
+                    Some(rest) if rest.is_empty() || rest.starts_with('/') => {
+                        format!("~{}", rest)
+                    }
+                    _ => dir,
+                }
+            })
+            .collect();
+        writeln!(out, "{}", dirs.join(" "))?;
+        Ok(())
+    }
+
+    /// The aliases as `name='value'` lines sorted by name, quoted like bash
+    /// so each line reads back as a single word
+    fn alias_listing(&self) -> Vec<String> {
+        let mut aliases: Vec<(&String, &String)> = self.config.aliases.iter().collect();
+        aliases.sort();
+        aliases
+            .into_iter()
+            .map(|(alias, command)| format!("{}={}", alias, Utils::single_quote(command)))
+            .collect()
+    }
+
+    /// Change directory, remembering where we came from for `cd -`.
+    /// Returns the directory if it was found through `$CDPATH`.
+    fn change_directory(&mut self, path: &str) -> Result<Option<PathBuf>> {
+        let previous = std::env::current_dir()?;
+        let found = Utils::change_directory(path)?;
+
+        // SAFETY: as in `set_variable`
+        unsafe {
+            std::env::set_var("OLDPWD", &previous);
+            std::env::set_var("PWD", std::env::current_dir()?);
+        }
+        self.previous_dir = Some(previous);
+        Ok(found)
+    }
+
+    fn execute_external(&self, command: &str, args: &[Token]) -> Result<i32> {
+        let (args, redirections) = Redirections::extract(args)?;
+
+        let mut cmd = Command::new(command);
+        cmd.args(&args);
+        // Open redirect targets before leaving raw mode so a bad path can't
+        // leave the terminal half-configured
+        if let Some(stdin) = redirections.open_stdin()? {
+            cmd.stdin(stdin);
+        }
+        Self::set_outputs(&mut cmd, &redirections, self.capture.as_ref())?;
+
+        let result = {
+            let _cooked = CookedMode::enter()?;
+            cmd.status() // Use .status() instead of .output()
+        };
+
+        match result {
+            Ok(status) => Ok(Utils::exit_code(status)),
+            Err(e) => Err(ShellError::spawn(command, e).into()),
+        }
+    }
+
+    /// Point a command's stdout and stderr where its redirections say, with
+    /// stdout going into `pipe` unless redirected
+    fn set_outputs(
+        cmd: &mut Command,
+        redirections: &Redirections,
+        pipe: Option<&PipeWriter>,
+    ) -> Result<()> {
+        let (stdout, stderr) = redirections.open_outputs(pipe)?;
+        if let Some(stdout) = stdout {
+            cmd.stdout(stdout);
+        }
+        if let Some(stderr) = stderr {
+            cmd.stderr(stderr);
+        }
+        Ok(())
+    }
+
+    /// Start an external command as a background job and return at once.
+    ///
+    /// The job's stdin is detached so it can't steal keystrokes from the line
+    /// editor, but its output still goes to the terminal. Known limitation:
+    /// output written while the prompt is showing lands on top of it, and
+    /// since the terminal is in raw mode bare newlines don't return the
+    /// cursor to column 0. Redirect the job's output to avoid both.
+    fn spawn_background(&mut self, command: &str, args: &[Token]) -> Result<i32> {
+        let (args, redirections) = Redirections::extract(args)?;
+
+        let mut cmd = Command::new(command);
+        cmd.args(&args)
+            .stdin(redirections.open_stdin()?.unwrap_or_else(Stdio::null));
+        // In a process group of its own, Ctrl+C meant for a foreground
+        // command doesn't reach the job
+        #[cfg(unix)]
+        cmd.process_group(0);
+        Self::set_outputs(&mut cmd, &redirections, None)?;
+
+        let child = cmd.spawn().map_err(|e| ShellError::spawn(command, e))?;
+        execute!(
+            stdout(),
+            Print(&format!("[{}] {}\r\n", self.jobs.len() + 1, child.id()))
+        )?;
+        self.jobs.push(child);
+        Ok(0)
+    }
+
+    /// Collect background jobs that have finished, reporting each one so
+    /// they don't linger as zombies
+    fn reap_jobs(&mut self) -> Result<()> {
+        let mut index = 0;
+        while index < self.jobs.len() {
+            match self.jobs[index].try_wait()? {
+                Some(status) => {
+                    // try_wait has already reaped it
+                    let pid = self.jobs.remove(index).id();
+                    // Like bash, only a failing job reports its status
+                    let state = match Utils::exit_code(status) {
+                        0 => "Done".to_string(),
+                        code => format!("Exit {}", code),
+                    };
+                    execute!(
+                        stdout(),
+                        Print(&format!("[{}] {} {}\r\n", index + 1, state, pid))
+                    )?;
+                }
+                None => index += 1,
+            }
+        }
+        Ok(())
+    }
+
+    fn execute_pipeline(&mut self, tokens: &[Token]) -> Result<i32> {
+        let stages: Vec<&[Token]> = tokens.split(|t| *t == Token::Op("|")).collect();
+        if stages.iter().any(|stage| stage.is_empty()) {
+            return Err(ShellError::UnexpectedToken("|".to_string()).into());
+        }
+
+        // Leave raw mode for the whole pipeline, like a single external command
+        let _cooked = CookedMode::enter()?;
+        let mut children = Vec::with_capacity(stages.len());
+        // Threads writing builtins' output into the next stage's pipe
+        let mut feeders = Vec::new();
+        let started = self.start_pipeline(&stages, &mut children, &mut feeders);
+        if started.is_err() {
+            // Don't leave earlier stages running with nobody reading
+            for (_, child) in &mut children {
+                let _ = child.kill();
+            }
+        }
+
+        // Like other shells, the pipeline's status is that of its last stage
+        let mut code = Ok(0);
+        for (name, mut child) in children {
+            code = child
+                .wait()
+                .map(Utils::exit_code)
+                .map_err(|e| anyhow!("Failed to wait for '{}': {}", name, e));
+        }
+        for feeder in feeders {
+            let _ = feeder.join();
+        }
+        started?.unwrap_or(code)
+    }
+
+    /// Start each stage of a pipeline in turn, adding external commands to
+    /// `children` as they're spawned. Returns the result of a builtin in the
+    /// last stage, which is the pipeline's.
+    fn start_pipeline<'a>(
+        &mut self,
+        stages: &[&'a [Token]],
+        children: &mut Vec<(&'a str, Child)>,
+        feeders: &mut Vec<JoinHandle<()>>,
+    ) -> Result<Option<Result<i32>>> {
+        let last = stages.len() - 1;
+        // The read end of the pipe from the previous stage
+        let mut previous: Option<PipeReader> = None;
+        for (i, stage) in stages.iter().enumerate() {
+            let Token::Word(name, _) = &stage[0] else {
+                return Err(ShellError::UnexpectedToken(stage[0].text().to_string()).into());
+            };
+            // Builtins run here in the shell, reading what the stages before
+            // them wrote. What one writes is fed to the next stage from a
+            // thread, so a full pipe can't hold up the shell.
+            if builtins::is_builtin(name) {
+                let args = &stage[1..];
+                let mut input: Box<dyn Input> = match previous.take() {
+                    Some(pipe_in) => Box::new(BufReader::new(pipe_in)),
+                    None => Box::new(ShellStdin::default()),
+                };
+                if i == last {
+                    return Ok(Some(self.execute_builtin_with(name, args, input.as_mut())));
+                }
+                let mut output = Vec::new();
+                // As in a subshell, `exit` here only ends this stage
+                if let Err(e) = self.run_builtin(name, args, input.as_mut(), &mut output)
+                    && !ShellError::is_exit(&e)
+                {
+                    self.report_error(&e.to_string())?;
+                }
+                let (reader, mut writer) = io::pipe()?;
+                previous = Some(reader);
+                feeders.push(std::thread::spawn(move || {
+                    // The next stage may exit without reading it all
+                    let _ = writer.write_all(&output);
+                }));
+                continue;
+            }
+
+            let (args, redirections) = Redirections::extract(&stage[1..])?;
+            let mut cmd = Command::new(name);
+            cmd.args(&args);
+            // A `<` file takes the place of the pipe, which is then closed
+            // so the previous stage sees its reader go away, as in bash. If
+            // the previous stage sent its output elsewhere, the pipe just
+            // reads as empty.
+            let pipe_in = previous.take();
+            match redirections.open_stdin()? {
+                Some(stdin) => {
+                    cmd.stdin(stdin);
+                }
+                None => {
+                    if let Some(pipe_in) = pipe_in {
+                        cmd.stdin(Stdio::from(pipe_in));
+                    }
+                }
+            }
+            // The final stage inherits the terminal's stdout, unless a
+            // `$(...)` is capturing it
+            let pipe_out = if i < last {
+                let (reader, writer) = io::pipe()?;
+                previous = Some(reader);
+                Some(writer)
+            } else {
+                self.capture
+                    .as_ref()
+                    .map(PipeWriter::try_clone)
+                    .transpose()?
+            };
+            Self::set_outputs(&mut cmd, &redirections, pipe_out.as_ref())?;
+            // The Command is dropped once spawned, closing the shell's
+            // copies of its pipe ends so readers see end of file
+            let child = cmd.spawn().map_err(|e| ShellError::spawn(name, e))?;
+            children.push((name.as_str(), child));
+        }
+        Ok(None)
+    }
+
+    fn read_input(&mut self) -> Result<InputResult> {
+        loop {
+            // Candidates that arrive late are shown unless the line has
+            // changed since Tab was pressed
+            if self.completion.is_pending() && !event::poll(COMPLETION_POLL)? {
+                if self
+                    .completion
+                    .poll(&self.current_input, self.cursor_pos, Duration::ZERO)
+                {
+                    self.show_completions()?;
+                }
+                continue;
+            }
+            let event = event::read()?;
+            // The right prompt and the completion grid are laid out for the
+            // terminal's width, so lay them out again for the new size
+            if let Event::Resize(_, _) = event {
+                self.redraw_line()?;
+                continue;
+            }
+            if let Event::Paste(text) = &event {
+                self.insert_paste(text)?;
+                continue;
+            }
+            if let Event::Key(KeyEvent {
+                code, modifiers, ..
+            }) = event
+            {
+                // Cancelling works in every mode and menu
+                if self.keymap.action(code, modifiers) == Some(Action::CancelLine) {
+                    self.cancel_line()?;
+                    continue;
+                }
+
+                if self.completion.shows_menu(&self.config) && self.handle_menu_key(code)? {
+                    continue;
+                }
+
+                if self.config.edit_mode == EditMode::Vi && self.vi_mode == ViMode::Normal {
+                    if let Some(result) = self.handle_vi_normal(code, modifiers)? {
+                        return Ok(result);
+                    }
+                    continue;
+                }
+
+                if code == KeyCode::Esc && self.config.edit_mode == EditMode::Vi {
+                    self.reset_completion();
+                    self.vi_mode = ViMode::Normal;
+                    // Like vi, leaving insert mode steps back onto the
+                    // last inserted character
+                    self.cursor_pos = Utils::prev_grapheme(&self.current_input, self.cursor_pos);
+                    self.redraw_line()?;
+                    continue;
+                }
+
+                match self.keymap.action(code, modifiers) {
+                    Some(action) => {
+                        if let Some(result) = self.run_action(action)? {
+                            return Ok(result);
+                        }
+                    }
+                    None => {
+                        if let KeyCode::Char(c) = code {
+                            self.reset_completion();
+                            self.insert_at_cursor(c.encode_utf8(&mut [0; 4]));
+                            self.redraw_line()?;
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Insert pasted text at the cursor without running anything. Each
+    /// pasted line break ends a line, which is kept as a continuation
+    /// under the secondary prompt, so the whole paste runs together when
+    /// Enter is pressed.
+    fn insert_paste(&mut self, text: &str) -> Result<()> {
+        self.reset_completion();
+        let text = text.replace("\r\n", "\n").replace('\r', "\n");
+        let tail = self.current_input.split_off(self.cursor_pos);
+        self.current_input.push_str(&text);
+
+        while let Some(newline) = self.current_input.find('\n') {
+            let rest = self.current_input.split_off(newline + 1);
+            // Show the finished line in full before moving below it
+            self.current_input.pop();
+            self.cursor_pos = self.current_input.len();
+            self.redraw_line()?;
+            UI::print_newline()?;
+            self.continuation.push_str(&self.current_input);
+            self.continuation.push('\n');
+            self.current_input = rest;
+        }
+
+        self.cursor_pos = self.current_input.len();
+        self.current_input.push_str(&tail);
+        self.redraw_line()
+    }
+
+    /// Insert `text` at the cursor, leaving the cursor just after it
+    fn insert_at_cursor(&mut self, text: &str) {
+        self.current_input.insert_str(self.cursor_pos, text);
+        self.cursor_pos += text.len();
+    }
+
+    /// Delete the grapheme before the cursor, returning whether there was one
+    fn delete_backward(&mut self) -> bool {
+        let Some(start) = self.grapheme_before() else {
+            return false;
+        };
+        self.current_input.replace_range(start..self.cursor_pos, "");
+        self.cursor_pos = start;
+        true
+    }
+
+    /// Delete the grapheme under the cursor, returning whether there was one
+    fn delete_forward(&mut self) -> bool {
+        let Some(end) = self.grapheme_after() else {
+            return false;
+        };
+        self.current_input.replace_range(self.cursor_pos..end, "");
+        true
+    }
+
+    /// Where the grapheme before the cursor starts, unless it's at the start
+    fn grapheme_before(&self) -> Option<usize> {
+        (self.cursor_pos > 0).then(|| Utils::prev_grapheme(&self.current_input, self.cursor_pos))
+    }
+
+    /// Where the grapheme under the cursor ends, unless it's at the end
+    fn grapheme_after(&self) -> Option<usize> {
+        (self.cursor_pos < self.current_input.len())
+            .then(|| Utils::next_grapheme(&self.current_input, self.cursor_pos))
+    }
+
+    /// Run a line editing action, returning a result if it ends input
+    fn run_action(&mut self, action: Action) -> Result<Option<InputResult>> {
+        match action {
+            Action::AcceptLine => return self.submit_line(),
+            Action::CancelLine => self.cancel_line()?,
+            Action::ExitOrDeleteChar => {
+                if self.current_input.is_empty() && self.continuation.is_empty() {
+                    return Ok(Some(InputResult::Exit));
+                }
+                return self.run_action(Action::DeleteChar);
+            }
+            Action::BackwardDeleteChar => {
+                self.reset_completion();
+                if self.delete_backward() {
+                    self.redraw_line()?;
+                }
+            }
+            Action::DeleteChar => {
+                self.reset_completion();
+                if self.delete_forward() {
+                    self.redraw_line()?;
+                }
+            }
+            Action::BackwardChar => {
+                if let Some(start) = self.grapheme_before() {
+                    self.move_cursor_to(start)?;
+                }
+            }
+            Action::ForwardChar if !self.suggestion().is_empty() => {
+                return self.run_action(Action::AcceptSuggestion);
+            }
+            Action::ForwardChar => {
+                if let Some(end) = self.grapheme_after() {
+                    self.move_cursor_to(end)?;
+                }
+            }
+            Action::BackwardWord => {
+                let start = Utils::prev_word_boundary(&self.current_input, self.cursor_pos);
+                self.move_cursor_to(start)?;
+            }
+            Action::ForwardWord => {
+                let end = Utils::next_word_boundary(&self.current_input, self.cursor_pos);
+                self.move_cursor_to(end)?;
+            }
+            Action::BeginningOfLine => self.move_cursor_to(0)?,
+            Action::EndOfLine => self.move_cursor_to(self.current_input.len())?,
+            Action::AcceptSuggestion => {
+                let suggestion = self.suggestion().to_string();
+                if !suggestion.is_empty() {
+                    self.current_input.push_str(&suggestion);
+                    self.cursor_pos = self.current_input.len();
+                    self.redraw_line()?;
+                }
+            }
+            Action::PreviousHistory => self.navigate_history(true)?,
+            Action::NextHistory => self.navigate_history(false)?,
+            Action::ReverseSearch => {
+                self.reset_completion();
+                self.reverse_search()?;
+            }
+            Action::Complete => self.handle_tab_completion()?,
+            Action::BackwardKillWord => {
+                let start = Utils::prev_word_boundary(&self.current_input, self.cursor_pos);
+                self.kill(start..self.cursor_pos)?;
+            }
+            Action::BackwardKillLine => self.kill(0..self.cursor_pos)?,
+            Action::KillLine => self.kill(self.cursor_pos..self.current_input.len())?,
+            Action::Yank => {
+                self.reset_completion();
+                self.insert_at_cursor(&self.kill_buffer.clone());
+                self.redraw_line()?;
+            }
+            Action::ClearScreen => {
+                // Leave completion state alone so Tab keeps cycling
+                execute!(
+                    stdout(),
+                    terminal::Clear(terminal::ClearType::All),
+                    cursor::MoveTo(0, 0)
+                )?;
+                UI::display_prompt(
+                    &self.config,
+                    self.prompt_state(),
+                    &self.current_input,
+                    self.cursor_pos,
+                    self.suggestion(),
+                )?;
+            }
+        }
+        Ok(None)
+    }
+
+    /// Handle a key while the completion menu is open. Tab, Shift+Tab and
+    /// the arrows move the selection, Enter keeps it and Esc puts back what
+    /// was typed. Any other key closes the menu and returns `false` so it
+    /// still does its usual job.
+    fn handle_menu_key(&mut self, code: KeyCode) -> Result<bool> {
+        let columns = self.completion.menu_columns()? as isize;
+        match code {
+            KeyCode::Tab => self.completion.cycle_next(),
+            KeyCode::BackTab => self.completion.cycle_prev(),
+            KeyCode::Right => self.completion.step(1),
+            KeyCode::Left => self.completion.step(-1),
+            KeyCode::Down => self.completion.step(columns),
+            KeyCode::Up => self.completion.step(-columns),
+            KeyCode::Enter => {
+                self.reset_completion();
+                self.redraw_line()?;
+                return Ok(true);
+            }
+            KeyCode::Esc => {
+                self.completion
+                    .restore(&mut self.current_input, &mut self.cursor_pos);
+                self.reset_completion();
+                self.redraw_line()?;
+                return Ok(true);
+            }
+            _ => {
+                self.reset_completion();
+                self.redraw_line()?;
+                return Ok(false);
+            }
+        }
+        self.completion
+            .apply(&mut self.current_input, &mut self.cursor_pos)?;
+        self.redraw_line()?;
+        Ok(true)
+    }
+
+    /// Handle Ctrl+C: like bash, mark the line with `^C` and throw it away,
+    /// along with any continuation lines, then start over on a new prompt
+    fn cancel_line(&mut self) -> Result<()> {
+        UI::redraw_line(
+            &self.config,
+            self.prompt_state(),
+            &self.current_input,
+            self.current_input.len(),
+            "",
+        )?;
+        execute!(stdout(), Print("^C"))?;
+        UI::print_newline()?;
+        self.reset_input();
+        self.redraw_line()
+    }
+
+    /// Handle Enter: return the command if it's complete, otherwise keep it
+    /// as a continuation and start a new line under a secondary prompt
+    fn submit_line(&mut self) -> Result<Option<InputResult>> {
+        // Don't leave an unaccepted suggestion on screen
+        if !self.suggestion().is_empty() {
+            UI::redraw_line(
+                &self.config,
+                self.prompt_state(),
+                &self.current_input,
+                self.cursor_pos,
+                "",
+            )?;
+        }
+
+        let mut command = std::mem::take(&mut self.continuation);
+        command.push_str(&self.current_input);
+
+        match Utils::command_continuation(&command) {
+            None => Ok(Some(InputResult::Command(command))),
+            Some(kind) => {
+                match kind {
+                    // A backslash-newline joins the lines, like in POSIX sh
+                    Continuation::Escaped => {
+                        command.pop();
+                    }
+                    Continuation::Unclosed => command.push('\n'),
+                }
+                self.reset_input();
+                self.continuation = command;
+                UI::print_newline()?;
+                self.redraw_line()?;
+                Ok(None)
+            }
+        }
+    }
+
+    /// Handle a key in vi normal mode, returning a result if the key ends
+    /// input (Enter, Ctrl+C, Ctrl+D)
+    fn handle_vi_normal(
+        &mut self,
+        code: KeyCode,
+        modifiers: KeyModifiers,
+    ) -> Result<Option<InputResult>> {
+        let len = self.current_input.len();
+
+        if let Some(operator) = self.vi_pending.take() {
+            // Only `d` is supported; any other motion cancels it
+            if operator == 'd' {
+                let range = match code {
+                    KeyCode::Char('d') => 0..len,
+                    KeyCode::Char('w') => {
+                        self.cursor_pos
+                            ..Utils::next_word_start(&self.current_input, self.cursor_pos)
+                    }
+                    KeyCode::Char('b') => {
+                        Utils::prev_word_boundary(&self.current_input, self.cursor_pos)
+                            ..self.cursor_pos
+                    }
+                    KeyCode::Char('$') => self.cursor_pos..len,
+                    KeyCode::Char('0') => 0..self.cursor_pos,
+                    _ => return Ok(None),
+                };
+                self.kill(range)?;
+                self.vi_clamp_cursor()?;
+            }
+            return Ok(None);
+        }
+
+        match (code, modifiers) {
+            (KeyCode::Char('d'), KeyModifiers::CONTROL)
+                if self.current_input.is_empty() && self.continuation.is_empty() =>
+            {
+                return Ok(Some(InputResult::Exit));
+            }
+            (KeyCode::Enter, _) => return self.submit_line(),
+            (KeyCode::Char('h'), _) | (KeyCode::Left, _) | (KeyCode::Backspace, _) => {
+                let start = Utils::prev_grapheme(&self.current_input, self.cursor_pos);
+                self.move_cursor_to(start)?;
+            }
+            (KeyCode::Char('l'), _) | (KeyCode::Right, _) => {
+                let end = Utils::next_grapheme(&self.current_input, self.cursor_pos);
+                if end < len {
+                    self.move_cursor_to(end)?;
+                }
+            }
+            (KeyCode::Char('0'), _) | (KeyCode::Char('^'), _) | (KeyCode::Home, _) => {
+                self.move_cursor_to(0)?;
+            }
+            (KeyCode::Char('$'), _) | (KeyCode::End, _) => {
+                self.move_cursor_to(Utils::prev_grapheme(&self.current_input, len))?;
+            }
+            (KeyCode::Char('w'), _) => {
+                let start = Utils::next_word_start(&self.current_input, self.cursor_pos);
+                self.move_cursor_to(start)?;
+                self.vi_clamp_cursor()?;
+            }
+            (KeyCode::Char('b'), _) => {
+                let start = Utils::prev_word_boundary(&self.current_input, self.cursor_pos);
+                self.move_cursor_to(start)?;
+            }
+            (KeyCode::Char('k'), _) | (KeyCode::Up, _) => self.navigate_history(true)?,
+            (KeyCode::Char('j'), _) | (KeyCode::Down, _) => self.navigate_history(false)?,
+            (KeyCode::Char('i'), _) => self.enter_vi_insert(self.cursor_pos)?,
+            (KeyCode::Char('a'), _) => {
+                let end = Utils::next_grapheme(&self.current_input, self.cursor_pos);
+                self.enter_vi_insert(end)?;
+            }
+            (KeyCode::Char('I'), _) => self.enter_vi_insert(0)?,
+            (KeyCode::Char('A'), _) => self.enter_vi_insert(len)?,
+            (KeyCode::Char('x'), _) | (KeyCode::Delete, _) => {
+                let end = Utils::next_grapheme(&self.current_input, self.cursor_pos);
+                self.kill(self.cursor_pos..end)?;
+                self.vi_clamp_cursor()?;
+            }
+            (KeyCode::Char('D'), _) => {
+                self.kill(self.cursor_pos..len)?;
+                self.vi_clamp_cursor()?;
+            }
+            (KeyCode::Char('C'), _) => {
+                self.kill(self.cursor_pos..len)?;
+                self.enter_vi_insert(self.cursor_pos)?;
+            }
+            (KeyCode::Char('p'), _) if !self.kill_buffer.is_empty() => {
+                let at = Utils::next_grapheme(&self.current_input, self.cursor_pos);
+                self.current_input.insert_str(at, &self.kill_buffer);
+                // Like vi, the cursor ends on the last pasted character
+                let end = at + self.kill_buffer.len();
+                self.cursor_pos = Utils::prev_grapheme(&self.current_input, end);
+                self.redraw_line()?;
+            }
+            (KeyCode::Char('d'), KeyModifiers::NONE) => self.vi_pending = Some('d'),
+            _ => {}
+        }
+        Ok(None)
+    }
+
+    /// Switch to vi insert mode with the cursor at byte offset `pos`
+    fn enter_vi_insert(&mut self, pos: usize) -> Result<()> {
+        self.vi_mode = ViMode::Insert;
+        self.cursor_pos = pos;
+        self.redraw_line()
+    }
+
+    /// In normal mode the cursor sits on a character, never past the end
+    fn vi_clamp_cursor(&mut self) -> Result<()> {
+        let len = self.current_input.len();
+        if len > 0 && self.cursor_pos >= len {
+            self.move_cursor_to(Utils::prev_grapheme(&self.current_input, len))?;
+        }
+        Ok(())
+    }
+
+    /// Cut `range` out of the input into the kill buffer for Ctrl+Y,
+    /// leaving the cursor where the text was
+    fn kill(&mut self, range: Range<usize>) -> Result<()> {
+        if range.is_empty() {
+            return Ok(());
+        }
+        self.reset_completion();
+        self.kill_buffer = self.current_input[range.clone()].to_string();
+        self.current_input.replace_range(range.clone(), "");
+        self.cursor_pos = range.start;
+        self.redraw_line()
+    }
+
+    /// The parts of the shell's state shown in the prompt
+    fn prompt_state(&self) -> PromptState {
+        PromptState {
+            last_exit_code: self.last_exit_code,
+            vi_mode: (self.config.edit_mode == EditMode::Vi).then_some(self.vi_mode),
+            continuation: !self.continuation.is_empty(),
+            duration: self.last_duration.filter(|duration| {
+                *duration >= Duration::from_millis(self.config.duration_threshold_ms)
+            }),
+        }
+    }
+
+    /// Redraw the prompt and the current input
+    fn redraw_line(&self) -> Result<()> {
+        UI::redraw_line(
+            &self.config,
+            self.prompt_state(),
+            &self.current_input,
+            self.cursor_pos,
+            self.suggestion(),
+        )?;
+        self.completion.show_info(&self.config)
+    }
+
+    /// The rest of the newest history entry that starts with the input,
+    /// shown dimmed after the cursor while it's at the end of the line.
+    /// Nothing is suggested mid-completion or in vi normal mode.
+    fn suggestion(&self) -> &str {
+        let active = self.config.use_colors()
+            && !self.current_input.is_empty()
+            && self.cursor_pos == self.current_input.len()
+            && self.continuation.is_empty()
+            && self.completion.is_empty()
+            && !(self.config.edit_mode == EditMode::Vi && self.vi_mode == ViMode::Normal);
+        if !active {
+            return "";
+        }
+
+        self.history
+            .iter()
+            .rev()
+            .filter_map(|entry| entry.command.strip_prefix(self.current_input.as_str()))
+            // Multiline entries can't be drawn after a single-line prompt
+            .find(|rest| !rest.is_empty() && !rest.contains('\n'))
+            .unwrap_or("")
+    }
+
+    /// Move the cursor to byte offset `pos` in the input, which must be a
+    /// grapheme boundary, shifting the terminal cursor by the display width
+    /// of the text in between so wide characters stay in sync
+    fn move_cursor_to(&mut self, pos: usize) -> Result<()> {
+        // The suggestion only shows at the end of the line, so it appears or
+        // disappears when the cursor moves to or away from there
+        let had_suggestion = !self.suggestion().is_empty();
+        let previous = std::mem::replace(&mut self.cursor_pos, pos);
+        if had_suggestion || !self.suggestion().is_empty() {
+            return self.redraw_line();
+        }
+
+        if pos < previous {
+            let width = Utils::display_width(&self.current_input[pos..previous]);
+            if width > 0 {
+                execute!(stdout(), cursor::MoveLeft(width as u16))?;
+            }
+        } else {
+            let width = Utils::display_width(&self.current_input[previous..pos]);
+            if width > 0 {
+                execute!(stdout(), cursor::MoveRight(width as u16))?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Interactive reverse history search (Ctrl+R). On accept the match is
+    /// placed in `current_input` for editing; on cancel the original input
+    /// is restored.
+    fn reverse_search(&mut self) -> Result<()> {
+        let mut query = String::new();
+        let mut match_index: Option<usize> = None;
+        let mut failed = false;
+
+        loop {
+            let matched = match_index
+                .map(|i| self.history[i].command.as_str())
+                .unwrap_or("");
+            UI::display_reverse_search(&self.config, self.prompt_state(), &query, matched, failed)?;
+
+            let Event::Key(KeyEvent {
+                code, modifiers, ..
+            }) = event::read()?
+            else {
+                continue;
+            };
+
+            match (code, modifiers) {
+                (KeyCode::Char('r'), KeyModifiers::CONTROL) => {
+                    // Jump to the next older match
+                    let before = match_index.unwrap_or(self.history.len());
+                    match self.search_history(&query, before) {
+                        Some(i) => {
+                            match_index = Some(i);
+                            failed = false;
+                        }
+                        None => failed = true,
+                    }
+                }
+                (KeyCode::Esc, _) | (KeyCode::Char('c'), KeyModifiers::CONTROL) => {
+                    break;
+                }
+                (KeyCode::Enter, _) => {
+                    if let Some(i) = match_index {
+                        self.current_input = self.history[i].command.clone();
+                        self.cursor_pos = self.current_input.len();
+                    }
+                    break;
+                }
+                (KeyCode::Backspace, _) => {
+                    query.pop();
+                    match_index = self.search_history(&query, self.history.len());
+                    failed = match_index.is_none() && !query.is_empty();
+                }
+                (KeyCode::Char(c), _) => {
+                    query.push(c);
+                    // Keep the current match if it still contains the query
+                    let before = match_index.map(|i| i + 1).unwrap_or(self.history.len());
+                    match self.search_history(&query, before) {
+                        Some(i) => {
+                            match_index = Some(i);
+                            failed = false;
+                        }
+                        None => failed = true,
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        self.redraw_line()
+    }
+
+    /// Find the newest history entry before index `before` containing `query`
+    fn search_history(&self, query: &str, before: usize) -> Option<usize> {
+        if query.is_empty() {
+            return None;
+        }
+        self.history
+            .iter()
+            .take(before)
+            .rposition(|entry| entry.command.contains(query))
+    }
+
+    fn navigate_history(&mut self, up: bool) -> Result<()> {
+        if self.history.is_empty() {
+            return Ok(());
+        }
+
+        let new_index = match self.history_index {
+            None if up => Some(self.history.len() - 1),
+            None => return Ok(()),
+            Some(i) if up && i > 0 => Some(i - 1),
+            Some(i) if !up && i < self.history.len() - 1 => Some(i + 1),
+            Some(_) if !up => {
+                self.history_index = None;
+                self.current_input.clear();
+                self.cursor_pos = 0;
+                self.redraw_line()?;
+                return Ok(());
+            }
+            _ => return Ok(()),
+        };
+
+        self.history_index = new_index;
+        if let Some(index) = new_index {
+            self.current_input = self.history[index].command.clone();
+            self.cursor_pos = self.current_input.len();
+            self.redraw_line()?;
+        }
+
+        Ok(())
+    }
+
+    fn reset_input(&mut self) {
+        self.current_input.clear();
+        self.continuation.clear();
+        self.cursor_pos = 0;
+        // Every new line starts in insert mode, as in bash and zsh
+        self.vi_mode = ViMode::Insert;
+        self.vi_pending = None;
+        self.history_index = None;
+        self.reset_completion();
+    }
+
+    fn reset_completion(&mut self) {
+        self.completion.reset();
+    }
+
+    fn handle_tab_completion(&mut self) -> Result<()> {
+        if self.completion.is_pending() {
+            // Still working on the last Tab
+            return Ok(());
+        }
+        if self.completion.is_empty() {
+            // Generate completions on another thread so a slow scan
+            // doesn't freeze the line, showing them here if they're quick
+            self.completion.request(
+                &self.current_input,
+                self.cursor_pos,
+                &self.config,
+                &self.history,
+            );
+            if self
+                .completion
+                .poll(&self.current_input, self.cursor_pos, COMPLETION_WAIT)
+            {
+                self.show_completions()?;
+            }
+            return Ok(());
+        }
+
+        // Cycle to next completion
+        self.completion.cycle_next();
+        self.completion
+            .apply(&mut self.current_input, &mut self.cursor_pos)?;
+        self.redraw_line()
+    }
+
+    /// Put freshly generated candidates on the line
+    fn show_completions(&mut self) -> Result<()> {
+        // Taking the common prefix resets the completion, notice and all,
+        // and a scan cut short is worth mentioning even when something matched
+        let notice = self.completion.notice().map(str::to_string);
+        if self.completion.is_empty() {
+            return match notice {
+                Some(notice) => Completion::show_notice(&self.config, &notice),
+                None => Ok(()),
+            };
+        }
+
+        // Complete as far as all candidates agree, and only start
+        // cycling through them once there's nothing more to add
+        self.completion.start(&self.current_input, self.cursor_pos);
+        if self
+            .completion
+            .apply_common_prefix(&mut self.current_input, &mut self.cursor_pos)
+        {
+            // The next Tab starts over from the longer prefix
+            self.completion.reset();
+        } else if self.completion.exceeds_limit(&self.config)
+            && !self.completion.confirm_long_list(&self.config)?
+        {
+            // Declining leaves the line as it was typed
+            self.completion.reset();
+        } else {
+            self.completion
+                .apply(&mut self.current_input, &mut self.cursor_pos)?;
+        }
+
+        self.redraw_line()?;
+        // An open menu draws the notice under itself
+        match notice {
+            Some(notice) if !self.completion.shows_menu(&self.config) => {
+                Completion::show_notice(&self.config, &notice)
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // All completion logic moved to completion.rs
+}
+
+impl Expander for Shell {
+    fn variable(&self, name: &str) -> Option<String> {
+        self.lookup_variable(name)
+    }
+
+    /// A substitution whose output can't be captured expands to nothing
+    fn command_output(&mut self, command: &str) -> Option<String> {
+        Some(self.capture_output(command).unwrap_or_default())
+    }
+}
+
+/// Keeps the terminal in raw mode for the line editor, and takes it back
+/// out when dropped, so an error returned from the interactive loop doesn't
+/// leave the user's terminal without echo
+struct RawModeGuard;
+
+impl RawModeGuard {
+    fn enter() -> Result<Self> {
+        terminal::enable_raw_mode()?;
+        // Pastes arrive as one event, so their newlines don't run anything
+        execute!(stdout(), EnableBracketedPaste)?;
+        Ok(Self)
+    }
+}
+
+impl Drop for RawModeGuard {
+    fn drop(&mut self) {
+        let _ = execute!(stdout(), DisableBracketedPaste);
+        let _ = terminal::disable_raw_mode();
+    }
+}
+
+/// Takes the terminal out of raw mode while foreground commands run, so
+/// they get the usual line discipline and echo, and puts it back when
+/// dropped, however the caller leaves. Outside raw mode (scripts, `-c`)
+/// it does nothing.
+struct CookedMode {
+    was_raw: bool,
+}
+
+impl CookedMode {
+    fn enter() -> Result<Self> {
+        let was_raw = terminal::is_raw_mode_enabled()?;
+        if was_raw {
+            // Commands that don't expect bracketed paste would see the markers
+            execute!(stdout(), DisableBracketedPaste)?;
+            terminal::disable_raw_mode()?;
+        }
+        Ok(Self { was_raw })
+    }
+}
+
+impl Drop for CookedMode {
+    fn drop(&mut self) {
+        if self.was_raw {
+            let _ = terminal::enable_raw_mode();
+            let _ = execute!(stdout(), EnableBracketedPaste);
+        }
+    }
+}
+
+/// The shell's stdin, which builtins read unless redirected. It's locked
+/// only once read from, so a builtin like `source` that runs others
+/// doesn't hold the lock they need.
+#[derive(Default)]
+struct ShellStdin(Option<io::StdinLock<'static>>);
+
+impl ShellStdin {
+    fn lock(&mut self) -> &mut io::StdinLock<'static> {
+        self.0.get_or_insert_with(|| io::stdin().lock())
+    }
+}
+
+impl Read for ShellStdin {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.lock().read(buf)
+    }
+}
+
+impl BufRead for ShellStdin {
+    fn fill_buf(&mut self) -> io::Result<&[u8]> {
+        self.lock().fill_buf()
+    }
+
+    fn consume(&mut self, amount: usize) {
+        self.lock().consume(amount)
+    }
+}
+
+impl Input for ShellStdin {
+    fn is_terminal(&self) -> bool {
+        io::stdin().is_terminal()
+    }
+}
+
+/// What a command name refers to, for `which` and `type`
+enum Resolution {
+    Alias(String),
+    Builtin,
+    File(PathBuf),
+    NotFound,
+}
+
+enum InputResult {
+    Command(String),
+    Exit,
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Shell;
+    use crate::builtins::{self, BUILTINS};
+    use crate::config::{Config, HistoryControl};
+    use crate::error::ShellError;
+    use crate::utils::{Token, Utils, lock_env};
+    use std::io::Cursor;
+
+    fn shell(control: &[HistoryControl], history_size: usize) -> Shell {
+        Shell::new(Config {
+            history_control: control.to_vec(),
+            history_size,
+            ..Config::default()
+        })
+        .unwrap()
+    }
+
+    /// Unquoted words, as builtins are given them
+    fn words(words: &[&str]) -> Vec<Token> {
+        words
+            .iter()
+            .map(|word| Token::Word(word.to_string(), false))
+            .collect()
+    }
+
+    fn record(shell: &mut Shell, commands: &[&str]) -> Vec<String> {
+        for command in commands {
+            shell.add_to_history(command);
+        }
+        shell
+            .history
+            .iter()
+            .map(|entry| entry.command.clone())
+            .collect()
+    }
+
+    /// The terminal column of the cursor within the input
+    fn cursor_column(shell: &Shell) -> usize {
+        Utils::display_width(&shell.current_input[..shell.cursor_pos])
+    }
+
+    #[test]
+    fn cjk_moves_by_two_columns() {
+        let mut shell = shell(&[], 10);
+        shell.insert_at_cursor("a漢字");
+        assert_eq!(cursor_column(&shell), 5);
+
+        for column in [3, 1, 0] {
+            shell.cursor_pos = shell.grapheme_before().unwrap();
+            assert_eq!(cursor_column(&shell), column);
+        }
+        assert_eq!(shell.grapheme_before(), None);
+
+        for column in [1, 3] {
+            shell.cursor_pos = shell.grapheme_after().unwrap();
+            assert_eq!(cursor_column(&shell), column);
+        }
+        shell.insert_at_cursor("x");
+        assert_eq!(shell.current_input, "a漢x字");
+    }
+
+    #[test]
+    fn backspace_removes_whole_cjk_character() {
+        let mut shell = shell(&[], 10);
+        shell.insert_at_cursor("漢字é");
+        shell.cursor_pos = shell.grapheme_before().unwrap();
+        assert!(shell.delete_backward());
+        assert_eq!(shell.current_input, "漢é");
+        assert!(shell.delete_backward());
+        assert_eq!(shell.current_input, "é");
+        assert_eq!(shell.cursor_pos, 0);
+        assert!(!shell.delete_backward());
+
+        assert!(shell.delete_forward());
+        assert_eq!(shell.current_input, "");
+        assert!(!shell.delete_forward());
+    }
+
+    #[test]
+    fn combining_mark_is_one_grapheme() {
+        let mut shell = shell(&[], 10);
+        // `e` followed by a combining acute accent
+        shell.insert_at_cursor("ae\u{301}b");
+        assert_eq!(cursor_column(&shell), 3);
+
+        shell.cursor_pos = shell.grapheme_before().unwrap();
+        shell.cursor_pos = shell.grapheme_before().unwrap();
+        assert_eq!(cursor_column(&shell), 1);
+        assert_eq!(&shell.current_input[shell.cursor_pos..], "e\u{301}b");
+
+        shell.cursor_pos = shell.grapheme_after().unwrap();
+        assert!(shell.delete_backward());
+        assert_eq!(shell.current_input, "ab");
+        assert_eq!(shell.cursor_pos, 1);
+    }
+
+    #[test]
+    fn heredocs_are_inlined_as_quoted_words() {
+        let mut shell = shell(&[], 10);
+        let inlined = shell
+            .inline_heredocs("cat <<EOF > out\nit's $?\nEOF")
+            .unwrap();
+        assert_eq!(inlined, "cat << 'it'\\''s 0\n' > out");
+
+        let inlined = shell.inline_heredocs("cat <<'EOF'\n$?\nEOF").unwrap();
+        assert_eq!(inlined, "cat << '$?\n'");
+        assert_eq!(
+            Utils::tokenize_command_with(&inlined, &mut |_: &str| None),
+            [
+                Token::Word("cat".into(), false),
+                Token::Op("<<"),
+                Token::Word("$?\n".into(), true)
+            ]
+        );
+
+        assert!(shell.inline_heredocs("cat <<EOF\nno end").is_err());
+    }
+
+    #[test]
+    fn alias_passes_arguments_through() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        shell
+            .config
+            .aliases
+            .insert("setenv".to_string(), "export".to_string());
+        shell
+            .execute_command("setenv WSH_ALIAS_ARGS=passed WSH_ALIAS_ARGS2=too")
+            .unwrap();
+        assert_eq!(std::env::var("WSH_ALIAS_ARGS").unwrap(), "passed");
+        assert_eq!(std::env::var("WSH_ALIAS_ARGS2").unwrap(), "too");
+    }
+
+    #[test]
+    fn aliases_expand_in_every_pipeline_stage() {
+        let mut shell = shell(&[], 10);
+        for (name, value) in [
+            ("e", "echo"),
+            ("tr", "tr -d x"),
+            ("up", "command tr a-z A-Z"),
+        ] {
+            shell
+                .config
+                .aliases
+                .insert(name.to_string(), value.to_string());
+        }
+        assert_eq!(shell.execute_command("e hi | read WSH_E").unwrap(), 0);
+        assert_eq!(shell.lookup_variable("WSH_E").as_deref(), Some("hi"));
+
+        // Like a lone command, `tr` isn't expanded again inside its own
+        // expansion
+        shell.execute_command("e six | tr | read WSH_TR").unwrap();
+        assert_eq!(shell.lookup_variable("WSH_TR").as_deref(), Some("si"));
+    }
+
+    #[test]
+    fn builtin_and_command_skip_aliases() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let start = std::env::current_dir().unwrap();
+        shell
+            .execute_command("alias cd='export WSH_SHADOWED_CD=1'")
+            .unwrap();
+
+        shell.execute_command("cd").unwrap();
+        assert_eq!(std::env::var("WSH_SHADOWED_CD").unwrap(), "1");
+        // Shadowing a builtin is warned about, but not an error
+        shell
+            .execute_command("WSH_WARNING=$(alias pwd=echo 2>&1 >/dev/null)")
+            .unwrap();
+        assert_eq!(
+            shell.lookup_variable("WSH_WARNING").as_deref(),
+            Some("wsh: alias: warning: 'pwd' shadows the builtin; use `builtin pwd` to run it")
+        );
+        assert_eq!(std::env::current_dir().unwrap(), start);
+
+        shell.execute_command("builtin cd /").unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), std::path::Path::new("/"));
+        shell
+            .execute_command(&format!("command cd {}", start.display()))
+            .unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), start);
+
+        assert_eq!(
+            shell.execute_command("builtin ls").unwrap_err().to_string(),
+            "builtin: ls: not a shell builtin"
+        );
+        assert_eq!(shell.execute_command("builtin").unwrap(), 0);
+        assert_eq!(shell.execute_command("command").unwrap(), 0);
+        shell.execute_command("command builtin cd /").unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), std::path::Path::new("/"));
+        std::env::set_current_dir(&start).unwrap();
+    }
+
+    #[test]
+    fn command_runs_the_external_program_with_its_arguments() {
+        let mut shell = shell(&[], 10);
+        shell
+            .config
+            .aliases
+            .insert("sh".to_string(), "export WSH_SHADOWED_SH=1".to_string());
+        let code = shell.execute_command("command sh -c 'exit 3'").unwrap();
+        assert_eq!(code, 3);
+        assert!(std::env::var("WSH_SHADOWED_SH").is_err());
+    }
+
+    #[test]
+    fn self_referential_aliases_expand_once() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let aliases = &mut shell.config.aliases;
+        aliases.insert("export".to_string(), "export WSH_ALIAS_SELF=1".to_string());
+        aliases.insert("loop_a".to_string(), "loop_b".to_string());
+        aliases.insert("loop_b".to_string(), "loop_a".to_string());
+
+        shell.execute_command("export WSH_ALIAS_OTHER=2").unwrap();
+        assert_eq!(std::env::var("WSH_ALIAS_SELF").unwrap(), "1");
+        assert_eq!(std::env::var("WSH_ALIAS_OTHER").unwrap(), "2");
+
+        // loop_a -> loop_b -> loop_a, which then runs as a plain command
+        assert!(shell.execute_command("loop_a").is_err());
+        assert!(shell.expanding_aliases.is_empty());
+    }
+
+    #[test]
+    fn alias_expands_tilde_and_variables_when_run() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let original = std::env::current_dir().unwrap();
+        let aliases = &mut shell.config.aliases;
+        aliases.insert("h".to_string(), "cd ~".to_string());
+        aliases.insert("j".to_string(), "cd $WSH_ALIAS_DIR".to_string());
+
+        shell.execute_command("h").unwrap();
+        let home = std::fs::canonicalize(std::env::var("HOME").unwrap()).unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), home);
+
+        // SAFETY: the env lock is held
+        unsafe { std::env::set_var("WSH_ALIAS_DIR", std::env::temp_dir()) };
+        shell.execute_command("j").unwrap();
+        let temp = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), temp);
+
+        std::env::set_current_dir(original).unwrap();
+    }
+
+    #[test]
+    fn semicolon_lists_run_regardless_of_failure() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        shell
+            .execute_command(
+                "export WSH_SEQ_A=1 && export WSH_SEQ_B=2; wsh-no-such-command && export WSH_SEQ_C=3; \
+                 wsh-no-such-command || export WSH_SEQ_D='4;5'",
+            )
+            .unwrap();
+        assert_eq!(std::env::var("WSH_SEQ_A").unwrap(), "1");
+        assert_eq!(std::env::var("WSH_SEQ_B").unwrap(), "2");
+        assert!(std::env::var("WSH_SEQ_C").is_err());
+        assert_eq!(std::env::var("WSH_SEQ_D").unwrap(), "4;5");
+    }
+
+    #[test]
+    fn true_and_false_are_builtins_for_chains() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        assert!(builtins::is_builtin("true") && builtins::is_builtin("false"));
+        assert_eq!(shell.execute_command("false").unwrap(), 1);
+        assert_eq!(shell.execute_command("true").unwrap(), 0);
+
+        shell
+            .execute_command(
+                "export WSH_TRUE_FALSE=\"$(false || echo ok) $(true && echo ok) $(true || echo no)\"",
+            )
+            .unwrap();
+        assert_eq!(std::env::var("WSH_TRUE_FALSE").unwrap(), "ok ok ");
+        shell.execute_command("unset WSH_TRUE_FALSE").unwrap();
+    }
+
+    #[test]
+    fn colored_prompt_is_measured_by_its_visible_columns() {
+        let _env = lock_env();
+        let start = std::env::current_dir().unwrap();
+        let dir = std::env::temp_dir().join(format!("wsh-日本語-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::env::set_current_dir(&dir).unwrap();
+        let shell = shell(&[], 10);
+
+        let plain = Utils::format_prompt("{cwd} $ ", shell.prompt_state());
+        let colored = Utils::format_prompt(
+            "\x1b]0;{cwd}\x07\x1b[1;34m{cwd}\x1b[0m \x1b[32m$\x1b[m ",
+            shell.prompt_state(),
+        );
+        // The three CJK characters take two columns each
+        assert_eq!(Utils::display_width(&plain), plain.chars().count() + 3);
+        assert_eq!(Utils::display_width(&colored), Utils::display_width(&plain));
+
+        std::env::set_current_dir(&start).unwrap();
+        std::fs::remove_dir(&dir).unwrap();
+    }
+
+    #[test]
+    fn blank_lines_clear_the_command_duration() {
+        let mut shell = shell(&[], 10);
+        shell.config.duration_threshold_ms = 0;
+        shell.execute_command("true").unwrap();
+        assert!(shell.prompt_state().duration.is_some());
+        shell.execute_command("  ").unwrap();
+        assert_eq!(shell.prompt_state().duration, None);
+    }
+
+    #[test]
+    fn config_env_is_exported_to_children() {
+        let _env = lock_env();
+        // SAFETY: the env lock is held
+        unsafe { std::env::set_var("WSH_CONFIG_BASE", "/opt/base") };
+        let env = [
+            ("WSH_CONFIG_PATH", "~/bin:$WSH_CONFIG_BASE/bin"),
+            ("WSH_CONFIG_KEPT", "$(date) \\$HOME"),
+            ("not valid", "skipped"),
+        ];
+        let mut shell = Shell::new(Config {
+            env: env
+                .iter()
+                .map(|&(name, value)| (name.to_string(), value.to_string()))
+                .collect(),
+            ..Config::default()
+        })
+        .unwrap();
+
+        shell
+            .execute_command("WSH_CHILD_SAW=\"$(printenv WSH_CONFIG_PATH WSH_CONFIG_KEPT)\"")
+            .unwrap();
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            shell.lookup_variable("WSH_CHILD_SAW").unwrap(),
+            format!("{}/bin:/opt/base/bin\n$(date) $HOME", home)
+        );
+        assert!(std::env::var_os("not valid").is_none());
+
+        // SAFETY: as above
+        unsafe {
+            for name in ["WSH_CONFIG_BASE", "WSH_CONFIG_PATH", "WSH_CONFIG_KEPT"] {
+                std::env::remove_var(name);
+            }
+        }
+    }
+
+    #[test]
+    fn version_reports_the_build() {
+        let mut shell = shell(&[], 10);
+        let mut out = Vec::new();
+        let version = shell.run_builtin("version", &[], &mut Cursor::new(""), &mut out);
+        assert_eq!(version.unwrap(), 0);
+        let out = String::from_utf8(out).unwrap();
+        let lines: Vec<&str> = out.lines().collect();
+        assert_eq!(lines[0], format!("wsh {}", env!("CARGO_PKG_VERSION")));
+        assert!(lines[1].starts_with("rustc "), "{}", lines[1]);
+        assert!(lines[2].starts_with("target: "), "{}", lines[2]);
+        assert_eq!(lines[3], "features: none");
+    }
+
+    #[test]
+    fn errexit_stops_a_sourced_script_at_the_first_failure() {
+        let _env = lock_env();
+        let script = std::env::temp_dir().join(format!("wsh-errexit-{}", std::process::id()));
+        std::fs::write(
+            &script,
+            "export WSH_ERREXIT_A=1\nset -e\nwsh-no-such-command || export WSH_ERREXIT_B=2\n\
+             wsh-no-such-command\nexport WSH_ERREXIT_C=3\n",
+        )
+        .unwrap();
+
+        let mut shell = shell(&[], 10);
+        let result = shell.source_file(&script.display().to_string(), false);
+        std::fs::remove_file(&script).unwrap();
+
+        assert!(result.is_err());
+        assert!(shell.errexit);
+        assert_eq!(std::env::var("WSH_ERREXIT_A").unwrap(), "1");
+        assert_eq!(std::env::var("WSH_ERREXIT_B").unwrap(), "2");
+        assert!(std::env::var("WSH_ERREXIT_C").is_err());
+        // SAFETY: the env lock is held
+        unsafe {
+            std::env::remove_var("WSH_ERREXIT_A");
+            std::env::remove_var("WSH_ERREXIT_B");
+        }
+    }
+
+    #[test]
+    fn errexit_ignores_failures_before_the_end_of_a_list() {
+        let mut shell = shell(&[], 10);
+        let run = |shell: &mut Shell, script: &str| {
+            shell.errexit = false;
+            shell.run_lines("script", script, false).unwrap();
+            shell.lookup_variable("WSH_AFTER")
+        };
+
+        // Like bash, only the last command of an `&&`/`||` list counts
+        let after = "set -e\nWSH_AFTER=\n";
+        for list in [
+            "false && true",
+            "false && false",
+            "false || true",
+            "true && false || true",
+        ] {
+            let script = format!("{}{}\nWSH_AFTER=ran\n", after, list);
+            assert_eq!(run(&mut shell, &script).as_deref(), Some("ran"), "{}", list);
+        }
+        for list in [
+            "true && false",
+            "false || false",
+            "false; true",
+            "true && $(false && true) false",
+        ] {
+            let script = format!("{}{}\nWSH_AFTER=ran\n", after, list);
+            assert_eq!(run(&mut shell, &script).as_deref(), Some(""), "{}", list);
+        }
+    }
+
+    #[test]
+    fn external_commands_run_outside_raw_mode() {
+        let mut shell = shell(&[], 10);
+        shell.execute_command("false").unwrap();
+        assert_eq!(shell.last_exit_code, 1);
+        shell.execute_command("true | false | true").unwrap();
+        assert_eq!(shell.last_exit_code, 0);
+        assert!(!crossterm::terminal::is_raw_mode_enabled().unwrap());
+    }
+
+    #[test]
+    fn exit_unwinds_to_the_caller_with_its_status() {
+        let mut shell = shell(&[], 10);
+        let error = shell
+            .execute_command("exit 3; echo not reached")
+            .unwrap_err();
+        assert!(ShellError::is_exit(&error));
+        assert_eq!(shell.last_exit_code, 3);
+
+        shell.execute_command("false").unwrap();
+        let error = shell.execute_command("exit").unwrap_err();
+        assert!(ShellError::is_exit(&error));
+        assert_eq!(shell.last_exit_code, 1);
+        assert!(shell.execute_command("exit x").is_err());
+    }
+
+    #[test]
+    fn commands_that_cannot_run_exit_126_or_127() {
+        let mut shell = shell(&[], 10);
+        let error = shell.execute_command("wsh-no-such-command").unwrap_err();
+        assert_eq!(error.to_string(), "wsh-no-such-command: command not found");
+        assert_eq!(shell.last_exit_code, 127);
+
+        let script = std::env::temp_dir().join(format!("wsh-noexec-{}", std::process::id()));
+        std::fs::write(&script, "true\n").unwrap();
+        let result = shell.execute_command(&script.display().to_string());
+        std::fs::remove_file(&script).unwrap();
+        assert!(result.is_err());
+        assert_eq!(shell.last_exit_code, 126);
+
+        shell
+            .execute_command("wsh-no-such-command || true")
+            .unwrap();
+        assert_eq!(shell.last_exit_code, 0);
+    }
+
+    #[test]
+    fn syntax_errors_exit_2_and_keep_their_kind() {
+        let mut shell = shell(&[], 10);
+        for line in ["ls ;; ls", "ls &&", "ls | | wc", "cat <", "| ls", "& ls"] {
+            let error = shell.execute_command(line).unwrap_err();
+            assert!(
+                matches!(
+                    error.downcast_ref::<ShellError>(),
+                    Some(ShellError::UnexpectedToken(_) | ShellError::Parse(_))
+                ),
+                "{}: {}",
+                line,
+                error
+            );
+            assert_eq!(shell.last_exit_code, 2, "{}", line);
+        }
+
+        let error = shell.execute_command("wsh-no-such-command").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ShellError>(),
+            Some(ShellError::CommandNotFound(name)) if name == "wsh-no-such-command"
+        ));
+    }
+
+    #[test]
+    fn ampersand_ends_a_list_or_is_rejected() {
+        let mut shell = shell(&[], 10);
+        // Builtins stay in the foreground, so both run in order, and
+        // neither sees the `&` or what follows as arguments
+        shell.execute_command("WSH_AMP=$(echo a & echo b)").unwrap();
+        assert_eq!(shell.lookup_variable("WSH_AMP").as_deref(), Some("a\nb"));
+
+        // Inside an alias's value there's no list for it to end
+        shell
+            .config
+            .aliases
+            .insert("amp".to_string(), "echo x & echo y".to_string());
+        let error = shell.execute_command("amp").unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<ShellError>(),
+            Some(ShellError::UnexpectedToken(token)) if token == "&"
+        ));
+    }
+
+    #[test]
+    fn pushd_and_popd_walk_the_directory_stack() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let start = std::env::current_dir().unwrap();
+        let temp = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+
+        assert!(shell.execute_builtin("popd", &[]).is_err());
+        assert!(shell.execute_builtin("pushd", &[]).is_err());
+
+        shell
+            .execute_builtin("pushd", &words(&[&temp.display().to_string()]))
+            .unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), temp);
+        assert_eq!(shell.dir_stack, std::slice::from_ref(&start));
+
+        // A bare pushd swaps back, keeping the other directory on the stack
+        shell.execute_builtin("pushd", &[]).unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), start);
+        assert_eq!(shell.dir_stack, std::slice::from_ref(&temp));
+
+        shell.execute_builtin("popd", &[]).unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), temp);
+        assert!(shell.dir_stack.is_empty());
+        assert_eq!(shell.previous_dir, Some(start.clone()));
+
+        std::env::set_current_dir(start).unwrap();
+    }
+
+    #[test]
+    fn cd_searches_cdpath_after_the_current_directory() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let start = std::env::current_dir().unwrap();
+        let base = std::fs::canonicalize(std::env::temp_dir())
+            .unwrap()
+            .join(format!("wsh-cdpath-{}", std::process::id()));
+        for dir in ["work/both", "first/both", "second/both", "second/only"] {
+            std::fs::create_dir_all(base.join(dir)).unwrap();
+        }
+        std::fs::write(base.join("work/file"), "").unwrap();
+        let work = base.join("work");
+        let cdpath = std::env::join_paths(["", "/wsh-missing", "first", "second"].map(|dir| {
+            if dir.is_empty() || dir.starts_with('/') {
+                std::path::PathBuf::from(dir)
+            } else {
+                base.join(dir)
+            }
+        }))
+        .unwrap();
+        // SAFETY: the env lock is held, as by every test that reads CDPATH
+        unsafe { std::env::set_var("CDPATH", &cdpath) };
+
+        std::env::set_current_dir(&work).unwrap();
+        assert_eq!(
+            shell.change_directory("only").unwrap(),
+            Some(base.join("second/only"))
+        );
+        assert_eq!(std::env::current_dir().unwrap(), base.join("second/only"));
+
+        // The first CDPATH entry with a match wins...
+        std::env::set_current_dir(&base).unwrap();
+        assert_eq!(
+            shell.change_directory("both").unwrap(),
+            Some(base.join("first/both"))
+        );
+
+        // ...but the current directory comes before any of them, and
+        // explicitly relative paths skip the search
+        std::env::set_current_dir(&work).unwrap();
+        assert_eq!(shell.change_directory("both").unwrap(), None);
+        assert_eq!(std::env::current_dir().unwrap(), work.join("both"));
+        std::env::set_current_dir(&work).unwrap();
+        let error = shell.change_directory("./only").unwrap_err().to_string();
+        assert!(error.ends_with("only: no such directory"), "{}", error);
+
+        let error = shell.execute_builtin("cd", &words(&["missing"]));
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "cd: missing: no such directory"
+        );
+        let error = shell.execute_builtin("cd", &words(&["file"]));
+        assert_eq!(error.unwrap_err().to_string(), "cd: file: not a directory");
+
+        // SAFETY: the env lock is held
+        unsafe { std::env::remove_var("CDPATH") };
+        std::env::set_current_dir(start).unwrap();
+        std::fs::remove_dir_all(&base).unwrap();
+    }
+
+    #[test]
+    fn scripts_skip_the_shebang_and_exit_with_the_last_status() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let path = std::env::temp_dir().join(format!("wsh-script-{}.wsh", std::process::id()));
+        std::fs::write(
+            &path,
+            "#!/usr/bin/env wsh\nexport WSH_SCRIPT_RAN=yes\nfalse\n",
+        )
+        .unwrap();
+        assert_eq!(shell.run_script(&path).unwrap(), 1);
+        assert_eq!(std::env::var("WSH_SCRIPT_RAN").unwrap(), "yes");
+        assert!(shell.history.is_empty());
+
+        std::fs::remove_file(&path).unwrap();
+
+        assert!(shell.run_script(&path).is_err());
+        assert_eq!(shell.last_exit_code, 127);
+    }
+
+    #[test]
+    fn script_commands_carry_on_over_lines() {
+        let mut shell = shell(&[], 10);
+        let script = "read <<EOF\n  kept as is\nEOF\n\
+                      WSH_QUOTED=\"one\n# two\"\n\
+                      WSH_JOINED=a\\\nb\n\
+                      wsh-no-such-command\n";
+        let code = shell.run_lines("script", script, false).unwrap();
+        assert_eq!(code, 127);
+        assert_eq!(
+            shell.lookup_variable("REPLY").as_deref(),
+            Some("  kept as is")
+        );
+        assert_eq!(
+            shell.lookup_variable("WSH_QUOTED").as_deref(),
+            Some("one\n# two")
+        );
+        assert_eq!(shell.lookup_variable("WSH_JOINED").as_deref(), Some("ab"));
+
+        // Errors name the line the command starts on
+        let error = shell
+            .run_lines("script", "echo 'a\nb'\nwsh-no-such-command", true)
+            .unwrap_err();
+        assert_eq!(
+            error.to_string(),
+            "script:3: wsh-no-such-command: command not found"
+        );
+    }
+
+    #[test]
+    fn stderr_follows_stdout_as_it_was_at_the_duplication() {
+        let mut shell = shell(&[], 10);
+        let dir = std::env::temp_dir().join(format!("wsh-dup-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).display().to_string();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+        let both = "sh -c 'echo out; echo err >&2'";
+
+        // Redirected first, stdout takes stderr along to the file...
+        shell
+            .execute_command(&format!("{} >{} 2>&1", both, file("after")))
+            .unwrap();
+        assert_eq!(read("after"), "out\nerr\n");
+
+        // ...but duplicated first, stderr keeps stdout's old place: here
+        // the pipe, while stdout alone goes to the file
+        shell
+            .execute_command(&format!(
+                "{} 2>&1 >{} | cat >{}",
+                both,
+                file("before"),
+                file("piped")
+            ))
+            .unwrap();
+        assert_eq!(read("before"), "out\n");
+        assert_eq!(read("piped"), "err\n");
+
+        // `2>` alone and `>&2` work the same way
+        shell
+            .execute_command(&format!("{} 2>{} >&2", both, file("stderr")))
+            .unwrap();
+        assert_eq!(read("stderr"), "out\nerr\n");
+
+        assert!(shell.execute_command("env true 2>&3").is_err());
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn builtins_follow_their_redirections() {
+        let mut shell = shell(&[], 10);
+        let dir = std::env::temp_dir().join(format!("wsh-builtin-io-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let file = |name: &str| dir.join(name).display().to_string();
+        let read = |name: &str| std::fs::read_to_string(dir.join(name)).unwrap();
+
+        shell
+            .execute_command(&format!("echo hi > {}", file("out")))
+            .unwrap();
+        assert_eq!(read("out"), "hi\n");
+        shell
+            .execute_command(&format!("echo more >> {}", file("out")))
+            .unwrap();
+        assert_eq!(read("out"), "hi\nmore\n");
+        // A digit from a variable is written, not taken for a stream
+        shell
+            .execute_command(&format!("f=2; echo $f>{}", file("digit")))
+            .unwrap();
+        assert_eq!(read("digit"), "2\n");
+
+        shell
+            .execute_command(&format!("read WSH_FIRST < {}", file("out")))
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_FIRST").as_deref(), Some("hi"));
+        shell
+            .execute_command("read WSH_DOC <<EOF\nfrom a heredoc\nEOF")
+            .unwrap();
+        assert_eq!(
+            shell.lookup_variable("WSH_DOC").as_deref(),
+            Some("from a heredoc")
+        );
+
+        // The operators never reach the builtin as arguments
+        shell.execute_command("WSH_DUP=$(echo x 2>&1)").unwrap();
+        assert_eq!(shell.lookup_variable("WSH_DUP").as_deref(), Some("x"));
+
+        // With stderr redirected, so is the builtin's error
+        let code = shell
+            .execute_command(&format!("cd missing 2> {}", file("err")))
+            .unwrap();
+        assert_eq!(code, 1);
+        assert_eq!(read("err"), "wsh: cd: missing: no such directory\n");
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn builtins_write_into_pipelines() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let piped = std::env::temp_dir().join(format!("wsh-piped-{}", std::process::id()));
+        record(&mut shell, &["git status", "ls", "git log"]);
+
+        shell
+            .execute_command(&format!("history | grep git > {}", piped.display()))
+            .unwrap();
+        // The pipeline itself is in history by the time it runs
+        assert_eq!(
+            std::fs::read_to_string(&piped).unwrap(),
+            format!(
+                "   1: git status\n   3: git log\n   4: history | grep git > {}\n",
+                piped.display()
+            )
+        );
+
+        shell
+            .execute_command(&format!(
+                "echo b a | tr ' ' '\\n' | sort > {}",
+                piped.display()
+            ))
+            .unwrap();
+        assert_eq!(std::fs::read_to_string(&piped).unwrap(), "a\nb\n");
+
+        // The status is the last stage's, builtin or not
+        assert_eq!(shell.execute_command("echo x | grep -q y").unwrap(), 1);
+        assert_eq!(
+            shell
+                .execute_command("sh -c 'exit 3' | unset WSH_UNSET")
+                .unwrap(),
+            0
+        );
+        std::fs::remove_file(&piped).unwrap();
+    }
+
+    #[test]
+    fn builtins_read_from_pipelines() {
+        let mut shell = shell(&[], 10);
+        shell
+            .execute_command("echo piped | read WSH_PIPED")
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_PIPED").as_deref(), Some("piped"));
+
+        shell
+            .execute_command("printf 'a b\\n' | read WSH_A WSH_B")
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_B").as_deref(), Some("b"));
+
+        // One in the middle reads the stage before it; the next stage gets
+        // only what it writes, which here is nothing
+        shell
+            .execute_command("printf 'one\\ntwo\\n' | read WSH_ONE | read WSH_TWO")
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_ONE").as_deref(), Some("one"));
+        assert_eq!(shell.lookup_variable("WSH_TWO"), None);
+    }
+
+    #[test]
+    fn sourced_files_share_the_directory_stack() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let start = std::env::current_dir().unwrap();
+        let temp = std::fs::canonicalize(std::env::temp_dir()).unwrap();
+        let script = temp.join(format!("wsh-pushd-{}", std::process::id()));
+        std::fs::write(&script, format!("pushd {}\ncd /\n", temp.display())).unwrap();
+
+        shell
+            .source_file(&script.display().to_string(), true)
+            .unwrap();
+        std::fs::remove_file(&script).unwrap();
+        assert_eq!(shell.dir_stack, std::slice::from_ref(&start));
+        assert_eq!(shell.previous_dir.as_deref(), Some(temp.as_path()));
+
+        // What the file left behind works from the prompt afterwards
+        shell.execute_command("cd -").unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), temp);
+        shell.execute_command("popd").unwrap();
+        assert_eq!(std::env::current_dir().unwrap(), start);
+        assert!(shell.dir_stack.is_empty());
+    }
+
+    #[test]
+    fn help_covers_every_builtin() {
+        for builtin in BUILTINS {
+            let name = builtin.name();
+            // Each name is listed once, so the registry finds this entry
+            let found = builtins::find(name).unwrap();
+            assert!(std::ptr::addr_eq(found, *builtin), "{}", name);
+            assert!(builtin.help().usage.starts_with(name));
+        }
+        assert_eq!(builtins::find(".").unwrap().name(), "source");
+        assert!(!builtins::is_builtin("ls"));
+
+        let mut shell = shell(&[], 10);
+        let error = shell.execute_builtin("help", &words(&["nonexistent"]));
+        assert_eq!(
+            error.unwrap_err().to_string(),
+            "help: no help topics match 'nonexistent'"
+        );
+    }
+
+    #[test]
+    fn alias_listing_is_sorted_and_quoted() {
+        let mut shell = shell(&[], 10);
+        let aliases = &mut shell.config.aliases;
+        aliases.insert("ll".to_string(), "ls -la".to_string());
+        aliases.insert("say".to_string(), "echo 'hi there' $USER".to_string());
+        aliases.insert("g".to_string(), "git".to_string());
+
+        let listing = shell.alias_listing();
+        assert_eq!(
+            listing,
+            [
+                "g='git'",
+                "ll='ls -la'",
+                r#"say='echo '\''hi there'\'' $USER'"#
+            ]
+        );
+        for line in listing {
+            let words = Utils::parse_command(&line);
+            let [word] = words.as_slice() else {
+                panic!("{} split into {:?}", line, words);
+            };
+            let (name, value) = word.split_once('=').unwrap();
+            assert_eq!(&shell.config.aliases[name], value);
+        }
+    }
+
+    #[test]
+    fn alias_takes_name_equals_value() {
+        let mut shell = shell(&[], 10);
+        let original = shell.config.aliases.clone();
+        // Adding one prints nothing
+        shell
+            .execute_command("WSH_ADDED=$(alias ll=ls -la)")
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_ADDED").as_deref(), Some(""));
+        shell
+            .execute_command("alias say='echo \"it'\\''s\"'")
+            .unwrap();
+        shell.execute_command("alias g git").unwrap();
+        shell.execute_command("alias gs git status").unwrap();
+        let aliases = &shell.config.aliases;
+        assert_eq!(aliases["ll"], "ls -la");
+        assert_eq!(aliases["say"], "echo \"it's\"");
+        assert_eq!(aliases["g"], "git");
+        assert_eq!(aliases["gs"], "git status");
+
+        // Feeding the listing back in defines the same aliases
+        let listing = shell.alias_listing();
+        shell.config.aliases = original;
+        for line in &listing {
+            shell.execute_command(&format!("alias {}", line)).unwrap();
+        }
+        assert_eq!(shell.alias_listing(), listing);
+
+        for bad in [
+            "alias =ls",
+            "alias 'a b'=ls",
+            "alias a/b=ls",
+            "alias missing",
+        ] {
+            assert!(shell.execute_command(bad).is_err(), "{}", bad);
+        }
+        assert_eq!(
+            shell.execute_command("alias =ls").unwrap_err().to_string(),
+            "alias: usage: alias [name[=value] ...]"
+        );
+    }
+
+    #[test]
+    fn quoted_operators_and_names_are_taken_literally() {
+        let mut shell = shell(&[], 10);
+        shell
+            .execute_command(r#"test ">" = '>' -a "|" = \|"#)
+            .unwrap();
+        assert_eq!(shell.last_exit_code, 0);
+
+        shell
+            .config
+            .aliases
+            .insert("wsh-quoted".to_string(), "true".to_string());
+        shell.execute_command("wsh-quoted").unwrap();
+        assert_eq!(shell.last_exit_code, 0);
+        // Quoting the name skips the alias
+        assert!(shell.execute_command("\\wsh-quoted").is_err());
+        assert_eq!(shell.last_exit_code, 127);
+    }
+
+    #[test]
+    fn set_toggles_flags() {
+        let mut shell = shell(&[], 10);
+        shell.execute_builtin("set", &words(&["-ex"])).unwrap();
+        assert!(shell.errexit && shell.xtrace);
+        shell
+            .execute_builtin("set", &words(&["+o", "errexit", "+x"]))
+            .unwrap();
+        assert!(!shell.errexit && !shell.xtrace);
+        assert!(shell.execute_builtin("set", &words(&["-z"])).is_err());
+        assert!(shell.execute_builtin("set", &words(&["-o"])).is_err());
+
+        // A trace covers a whole pipeline, and the assignments before it
+        let assignments = [("X".to_string(), "1".to_string())];
+        assert_eq!(
+            Shell::trace_words(&assignments, &Utils::tokenize_command("echo a | cat")),
+            ["X=1", "echo", "a", "|", "cat"]
+        );
+        assert_eq!(Shell::trace_words(&assignments, &[]), ["X=1"]);
+    }
+
+    #[test]
+    fn unset_hides_a_variable_from_children() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let child_sees = || {
+            std::process::Command::new("sh")
+                .args(["-c", "test -n \"${WSH_UNSET_TEST+set}\""])
+                .status()
+                .unwrap()
+                .success()
+        };
+
+        shell.execute_command("export WSH_UNSET_TEST=1").unwrap();
+        assert!(child_sees());
+        shell
+            .execute_command("unset WSH_UNSET_TEST WSH_NEVER_SET")
+            .unwrap();
+        assert!(!child_sees());
+        assert!(shell.execute_command("unset 1BAD").is_err());
+    }
+
+    #[test]
+    fn command_substitution_captures_builtins_and_pipelines() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        shell
+            .execute_command("export WSH_SUBST=\"$(echo a; printf 'b\\n\\n' | tr b c)\" WSH_SUBST_QUOTED='$(echo no)'")
+            .unwrap();
+        assert_eq!(std::env::var("WSH_SUBST").unwrap(), "a\nc");
+        assert_eq!(std::env::var("WSH_SUBST_QUOTED").unwrap(), "$(echo no)");
+
+        // The status is the outer command's, not the substitution's
+        assert_eq!(shell.execute_command("echo $(false)").unwrap(), 0);
+        assert_eq!(shell.execute_command("$(echo false)").unwrap(), 1);
+        shell
+            .execute_command("unset WSH_SUBST WSH_SUBST_QUOTED")
+            .unwrap();
+    }
+
+    #[test]
+    fn read_stores_a_line_in_shell_variables() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let names = |names: &[&str]| names.iter().map(|n| n.to_string()).collect::<Vec<_>>();
+        let mut input = std::io::Cursor::new("  a b\\  c d \n  as typed \n");
+
+        let code = shell
+            .read_into(
+                &mut input,
+                &names(&["WSH_READ_X", "WSH_READ_Y", "WSH_READ_Z"]),
+            )
+            .unwrap();
+        assert_eq!(code, 0);
+        assert_eq!(shell.lookup_variable("WSH_READ_Z").as_deref(), Some("c d"));
+        shell.read_into(&mut input, &[]).unwrap();
+        assert_eq!(
+            shell.lookup_variable("REPLY").as_deref(),
+            Some("  as typed ")
+        );
+        assert_eq!(shell.read_into(&mut input, &[]).unwrap(), 1);
+
+        // Later commands expand them, but children don't inherit them
+        shell
+            .execute_command("export WSH_READ_SEEN=\"$WSH_READ_X|$WSH_READ_Y\"")
+            .unwrap();
+        assert_eq!(std::env::var("WSH_READ_SEEN").unwrap(), "a|b\\");
+        assert!(std::env::var("WSH_READ_X").is_err());
+
+        assert!(shell.execute_command("read -p").is_err());
+        assert!(shell.execute_command("read 1BAD").is_err());
+        shell
+            .execute_command("unset WSH_READ_SEEN WSH_READ_X")
+            .unwrap();
+        assert_eq!(shell.lookup_variable("WSH_READ_X"), None);
+    }
+
+    #[test]
+    fn assignments_are_local_unless_exported_or_one_shot() {
+        let _env = lock_env();
+        let mut shell = shell(&[], 10);
+        let child_sees = |name: &str| {
+            std::process::Command::new("sh")
+                .args(["-c", &format!("test -n \"${{{}+set}}\"", name)])
+                .status()
+                .unwrap()
+                .success()
+        };
+
+        // A plain assignment expands later but stays out of children
+        shell
+            .execute_command("WSH_LOCAL='a  b' WSH_LOCAL_COPY=$WSH_LOCAL")
+            .unwrap();
+        assert_eq!(
+            shell.lookup_variable("WSH_LOCAL_COPY").as_deref(),
+            Some("a  b")
+        );
+        assert!(!child_sees("WSH_LOCAL"));
+        shell.execute_command("export WSH_LOCAL").unwrap();
+        assert!(child_sees("WSH_LOCAL"));
+        assert_eq!(std::env::var("WSH_LOCAL").unwrap(), "a  b");
+
+        // In front of a command, only that command sees it
+        assert_eq!(
+            shell
+                .execute_command("WSH_ONE_SHOT=1 sh -c 'exit $WSH_ONE_SHOT'")
+                .unwrap(),
+            1
+        );
+        assert!(!child_sees("WSH_ONE_SHOT"));
+        assert_eq!(shell.lookup_variable("WSH_ONE_SHOT"), None);
+
+        // An assignment alone exits with its substitution's status, and
+        // `exit` in one ends just the substitution
+        assert_eq!(
+            shell.execute_command("WSH_LOCAL_COPY=$(exit 3)").unwrap(),
+            3
+        );
+        assert_eq!(shell.execute_command("WSH_LOCAL_COPY=x").unwrap(), 0);
+        shell
+            .execute_command("unset WSH_LOCAL WSH_LOCAL_COPY")
+            .unwrap();
+    }
+
+    #[test]
+    fn history_builtin_deletes_and_clears() {
+        let mut shell = shell(&[], 10);
+        record(&mut shell, &["a", "b", "c"]);
+
+        shell
+            .execute_builtin("history", &words(&["-d", "2"]))
+            .unwrap();
+        assert_eq!(record(&mut shell, &[]), ["a", "c"]);
+        assert!(
+            shell
+                .execute_builtin("history", &words(&["-d", "3"]))
+                .is_err()
+        );
+        assert!(
+            shell
+                .execute_builtin("history", &words(&["-d", "0"]))
+                .is_err()
+        );
+
+        shell
+            .execute_builtin("history", &words(&["clear"]))
+            .unwrap();
+        assert!(shell.history.is_empty());
+    }
+
+    #[test]
+    fn history_keeps_everything_without_control() {
+        let mut shell = shell(&[], 10);
+        assert_eq!(
+            record(&mut shell, &["ls", "ls", " pwd"]),
+            ["ls", "ls", "pwd"]
+        );
+    }
+
+    #[test]
+    fn ignoredups_skips_repeats_of_the_previous_command() {
+        let mut shell = shell(&[HistoryControl::IgnoreDups], 10);
+        assert_eq!(
+            record(&mut shell, &["ls", "ls", "pwd", "ls"]),
+            ["ls", "pwd", "ls"]
+        );
+    }
+
+    #[test]
+    fn ignorespace_skips_commands_with_a_leading_space() {
+        let mut shell = shell(&[HistoryControl::IgnoreSpace], 10);
+        assert_eq!(record(&mut shell, &["ls", " secret", "pwd"]), ["ls", "pwd"]);
+        // Only a leading space hides a command; a trailing one is trimmed
+        assert_eq!(record(&mut shell, &["echo hi  "]), ["ls", "pwd", "echo hi"]);
+    }
+
+    #[test]
+    fn history_records_the_line_as_it_ran() {
+        let mut shell = shell(&[HistoryControl::IgnoreDups], 10);
+        shell
+            .execute_command("  true    &&   true 'a    b'  ")
+            .unwrap();
+        shell.execute_command("true a\\  ").unwrap();
+        assert_eq!(
+            record(&mut shell, &["true a\\ "]),
+            ["true    &&   true 'a    b'", "true a\\ "]
+        );
+    }
+
+    #[test]
+    fn erasedups_keeps_only_the_newest_copy() {
+        let mut shell = shell(&[HistoryControl::EraseDups], 3);
+        assert_eq!(
+            record(&mut shell, &["a", "b", "a", "c", "b"]),
+            ["a", "c", "b"]
+        );
+        // A repeat frees its old slot, so nothing else is trimmed
+        assert_eq!(record(&mut shell, &["a"]), ["c", "b", "a"]);
+        assert_eq!(record(&mut shell, &["d"]), ["b", "a", "d"]);
+    }
+
+    #[test]
+    fn lowering_history_size_drops_the_oldest_entries() {
+        // 0 keeps everything
+        let mut shell = shell(&[], 0);
+        let commands: Vec<String> = (0..20).map(|n| format!("echo {}", n)).collect();
+        let commands: Vec<&str> = commands.iter().map(String::as_str).collect();
+        assert_eq!(record(&mut shell, &commands).len(), 20);
+
+        let dir = std::env::temp_dir().join(format!("wsh-history-size-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let path = dir.join("config.toml");
+        Config {
+            history_size: 3,
+            ..Config::default()
+        }
+        .save(&path)
+        .unwrap();
+        shell.config.path = Some(path);
+        shell.execute_command("reload").unwrap();
+        assert_eq!(record(&mut shell, &[]), ["echo 18", "echo 19", "reload"]);
+        assert_eq!(record(&mut shell, &["pwd"]), ["echo 19", "reload", "pwd"]);
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
 }
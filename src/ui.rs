@@ -1,11 +1,13 @@
+use crate::builtins::{self, BUILTINS};
 use crate::config::Config;
-use anyhow::Result;
+use crate::utils::{HistoryEntry, PromptState, Utils};
+use anyhow::{Result, anyhow};
 use crossterm::{
-    cursor, execute,
-    style::{Color, Print, ResetColor, SetForegroundColor},
+    cursor, execute, queue,
+    style::{Print, ResetColor, SetForegroundColor},
     terminal::{self, ClearType},
 };
-use std::io::{Write, stdout};
+use std::io::{Write, stderr, stdout};
 
 pub struct UI;
 
@@ -27,25 +29,59 @@ impl UI {
         Ok(())
     }
 
-    pub fn display_prompt(config: &Config, current_input: &str, cursor_pos: usize) -> Result<()> {
-        use crate::utils::Utils;
-        let prompt = Utils::format_prompt(&config.prompt);
+    pub fn display_prompt(
+        config: &Config,
+        state: PromptState,
+        current_input: &str,
+        cursor_pos: usize,
+        suggestion: &str,
+    ) -> Result<()> {
+        // Continuation lines get the secondary prompt, like bash's PS2
+        let template = if state.continuation {
+            &config.prompt2
+        } else {
+            &config.prompt
+        };
+        let mut prompt = Utils::format_prompt(template, state);
+        // Vi users always get a mode indicator, even without `{mode}`
+        if state.vi_mode.is_some()
+            && !template.contains("{mode}")
+            && !config.rprompt.contains("{mode}")
+        {
+            prompt = format!("{} {}", Utils::vi_mode_indicator(state.vi_mode), prompt);
+        }
 
-        if config.enable_colors {
+        if config.use_colors() {
             execute!(
                 stdout(),
-                SetForegroundColor(Color::Green),
+                SetForegroundColor(config.colors.prompt()),
                 Print(&prompt),
-                ResetColor,
-                Print(current_input)
+                ResetColor
             )?;
+            Self::print_input(config, current_input)?;
+            if !suggestion.is_empty() {
+                execute!(
+                    stdout(),
+                    SetForegroundColor(config.colors.suggestion()),
+                    Print(suggestion),
+                    ResetColor
+                )?;
+            }
         } else {
             print!("{}{}", prompt, current_input);
         }
 
+        if !config.rprompt.is_empty() {
+            let end_column = Utils::display_width(&prompt)
+                + Utils::display_width(current_input)
+                + Utils::display_width(suggestion);
+            Self::display_rprompt(config, state, end_column)?;
+        }
+
         // Position cursor
-        if cursor_pos < current_input.len() {
-            let remaining = current_input.len() - cursor_pos;
+        let remaining =
+            Utils::display_width(&current_input[cursor_pos..]) + Utils::display_width(suggestion);
+        if remaining > 0 {
             execute!(stdout(), cursor::MoveLeft(remaining as u16))?;
         }
 
@@ -53,14 +89,128 @@ impl UI {
         Ok(())
     }
 
-    pub fn redraw_line(config: &Config, current_input: &str, cursor_pos: usize) -> Result<()> {
+    /// Print the input line, coloring command names when syntax highlighting
+    /// is on and colors are in use. Only colors change, so the cursor math
+    /// stays the same.
+    fn print_input(config: &Config, input: &str) -> Result<()> {
+        if !config.syntax_highlight || !config.use_colors() {
+            execute!(stdout(), Print(input))?;
+            return Ok(());
+        }
+
+        let mut out = stdout();
+        let mut printed = 0;
+        for word in Utils::command_words(input) {
+            let name = Utils::parse_command(&input[word.clone()]);
+            let known = name.first().is_some_and(|name| {
+                config.aliases.contains_key(name)
+                    || builtins::is_builtin(name)
+                    || Utils::find_in_path(name).is_some()
+            });
+            let color = if known {
+                config.colors.command()
+            } else {
+                config.colors.error()
+            };
+            queue!(
+                out,
+                Print(&input[printed..word.start]),
+                SetForegroundColor(color),
+                Print(&input[word.clone()]),
+                ResetColor
+            )?;
+            printed = word.end;
+        }
+        queue!(out, Print(&input[printed..]))?;
+        out.flush()?;
+        Ok(())
+    }
+
+    /// Draw the right-hand prompt flush with the terminal's right edge, then
+    /// put the cursor back at `end_column`. It's left out when it wouldn't
+    /// fit beside the input with at least one column to spare.
+    fn display_rprompt(config: &Config, state: PromptState, end_column: usize) -> Result<()> {
+        let rprompt = Utils::format_prompt(&config.rprompt, state);
+        let width = Utils::display_width(&rprompt);
+        let (columns, _) = terminal::size()?;
+        let columns = columns as usize;
+        if end_column + width >= columns {
+            return Ok(());
+        }
+
+        execute!(stdout(), cursor::MoveToColumn((columns - width) as u16))?;
+        if config.use_colors() {
+            execute!(
+                stdout(),
+                SetForegroundColor(config.colors.prompt()),
+                Print(&rprompt),
+                ResetColor
+            )?;
+        } else {
+            execute!(stdout(), Print(&rprompt))?;
+        }
+        execute!(stdout(), cursor::MoveToColumn(end_column as u16))?;
+        Ok(())
+    }
+
+    pub fn redraw_line(
+        config: &Config,
+        state: PromptState,
+        current_input: &str,
+        cursor_pos: usize,
+        suggestion: &str,
+    ) -> Result<()> {
         execute!(
             stdout(),
             Print("\r"), // Move to the start of the line
             cursor::MoveToColumn(0),
             terminal::Clear(ClearType::FromCursorDown)
         )?;
-        Self::display_prompt(config, current_input, cursor_pos)?;
+        Self::display_prompt(config, state, current_input, cursor_pos, suggestion)?;
+        Ok(())
+    }
+
+    pub fn display_reverse_search(
+        config: &Config,
+        state: PromptState,
+        query: &str,
+        matched: &str,
+        failed: bool,
+    ) -> Result<()> {
+        let label = if failed {
+            "(failed reverse-i-search)"
+        } else {
+            "(reverse-i-search)"
+        };
+        // On a continuation line, keep showing that the command goes on
+        let prompt = if state.continuation {
+            Utils::format_prompt(&config.prompt2, state)
+        } else {
+            String::new()
+        };
+        execute!(
+            stdout(),
+            Print("\r"),
+            cursor::MoveToColumn(0),
+            terminal::Clear(ClearType::FromCursorDown),
+            Print(&format!("{}{}`{}': ", prompt, label, query))
+        )?;
+
+        // Highlight the part of the match that the query hit
+        match matched.find(query) {
+            Some(start) if config.use_colors() && !query.is_empty() => {
+                let end = start + query.len();
+                execute!(
+                    stdout(),
+                    Print(&matched[..start]),
+                    SetForegroundColor(config.colors.match_highlight()),
+                    Print(&matched[start..end]),
+                    ResetColor,
+                    Print(&matched[end..])
+                )?;
+            }
+            _ => execute!(stdout(), Print(matched))?,
+        }
         Ok(())
     }
 
@@ -72,10 +222,10 @@ impl UI {
             terminal::Clear(ClearType::CurrentLine)
         )?;
 
-        if config.enable_colors {
+        if config.use_colors() {
             execute!(
                 stdout(),
-                SetForegroundColor(Color::Red),
+                SetForegroundColor(config.colors.error()),
                 Print(&format!("Error: {}\r\n", message)),
                 ResetColor
             )?;
@@ -85,111 +235,106 @@ impl UI {
         Ok(())
     }
 
-    pub fn show_help() -> Result<()> {
-        execute!(stdout(), Print("WSH - Built-in Commands:\n"))?;
-        execute!(stdout(), Print("  cd [path]     - Change directory\n"))?;
-        execute!(
-            stdout(),
-            Print("  pwd           - Print working directory\n")
-        )?;
-        execute!(stdout(), Print("  history       - Show command history\n"))?;
-        execute!(
-            stdout(),
-            Print("  alias [name] [cmd] - Create or show aliases\n")
-        )?;
-        execute!(
-            stdout(),
-            Print("  help          - Show this help message\n")
-        )?;
-        execute!(stdout(), Print("  exit          - Exit the shell\n"))?;
-        execute!(stdout(), Print("\nKeyboard shortcuts:\n"))?;
-        execute!(stdout(), Print("  Ctrl+C / Ctrl+D - Exit\n"))?;
-        execute!(stdout(), Print("  Up/Down arrows  - Navigate history\n"))?;
-        execute!(stdout(), Print("  Left/Right      - Move cursor\n"))?;
-        execute!(
-            stdout(),
-            Print("  Home/End        - Jump to line start/end\n")
-        )?;
+    /// Echo a command about to run, for `set -x`
+    pub fn print_trace(words: &[String]) -> Result<()> {
+        // Builtins run with the terminal still in raw mode
+        let newline = if terminal::is_raw_mode_enabled()? {
+            "\r\n"
+        } else {
+            "\n"
+        };
         execute!(
-            stdout(),
-            Print("  Tab             - Auto-complete commands and paths\n")
+            stderr(),
+            Print(&format!("+ {}{}", words.join(" "), newline))
         )?;
-        execute!(stdout(), Print("\nAutocompletion features:\n"))?;
-        execute!(stdout(), Print("  - Built-in commands\n"))?;
-        execute!(stdout(), Print("  - Executable commands in PATH\n"))?;
-        execute!(stdout(), Print("  - File and directory paths\n"))?;
-        execute!(stdout(), Print("  - Command aliases\n"))?;
-        execute!(stdout(), Print("  - Commands from history\n"))?;
         Ok(())
     }
 
-    pub fn show_history(history: &std::collections::VecDeque<String>) -> Result<()> {
-        if history.is_empty() {
-            execute!(stdout(), Print("No history available\n"))?;
-            return Ok(());
+    /// List the builtins with one-line summaries, then the keys
+    pub fn show_help(out: &mut dyn Write) -> Result<()> {
+        writeln!(out, "WSH - Built-in Commands:")?;
+        let width = BUILTINS
+            .iter()
+            .map(|b| b.help().usage.len())
+            .max()
+            .unwrap_or(0);
+        for builtin in BUILTINS.iter().map(|b| b.help()) {
+            writeln!(
+                out,
+                "  {:<width$}  {}",
+                builtin.usage,
+                builtin.summary,
+                width = width
+            )?;
         }
+        writeln!(out, "\nType 'help <name>' for more about a builtin.")?;
+        writeln!(out, "\nKeyboard shortcuts:")?;
+        writeln!(out, "  Ctrl+C          - Cancel the line")?;
+        writeln!(out, "  Ctrl+D          - Exit on an empty line")?;
+        writeln!(out, "  Up/Down arrows  - Navigate history")?;
+        writeln!(out, "  Ctrl+R          - Reverse search history")?;
+        writeln!(out, "  Ctrl+L          - Clear the screen")?;
+        writeln!(out, "  Ctrl+A / Ctrl+E - Jump to line start/end")?;
+        writeln!(out, "  Ctrl+W          - Delete the previous word")?;
+        writeln!(out, "  Ctrl+U / Ctrl+K - Delete to line start/end")?;
+        writeln!(out, "  Ctrl+Y          - Paste deleted text")?;
+        writeln!(out, "  Left/Right      - Move cursor")?;
+        writeln!(
+            out,
+            "  Right / Ctrl+F  - Accept the suggestion from history"
+        )?;
+        writeln!(out, "  Alt+Left/Right  - Move cursor by word")?;
+        writeln!(out, "  Home/End        - Jump to line start/end")?;
+        writeln!(out, "  Tab             - Auto-complete commands and paths")?;
+        writeln!(
+            out,
+            "  Arrows/Enter/Esc - Move, accept or cancel in the completion menu"
+        )?;
+        writeln!(out, "\nAutocompletion features:")?;
+        writeln!(out, "  - Built-in commands")?;
+        writeln!(out, "  - Executable commands in PATH")?;
+        writeln!(out, "  - File and directory paths")?;
+        writeln!(out, "  - Command options (from --help)")?;
+        writeln!(out, "  - Command aliases")?;
+        writeln!(out, "  - Commands from history")?;
+        Ok(())
+    }
 
-        for (i, cmd) in history.iter().enumerate() {
-            execute!(stdout(), Print(&format!("{:4}: {}\n", i + 1, cmd)))?;
+    /// Print the usage and description of one builtin, for `help <name>`
+    pub fn show_builtin_help(out: &mut dyn Write, name: &str) -> Result<()> {
+        let builtin = builtins::find(name)
+            .ok_or_else(|| anyhow!("help: no help topics match '{}'", name))?
+            .help();
+        writeln!(
+            out,
+            "{}: {}\n    {}",
+            builtin.name, builtin.usage, builtin.summary
+        )?;
+        for line in builtin.details.lines() {
+            writeln!(out, "    {}", line)?;
         }
         Ok(())
     }
 
-    pub fn show_completion_info(
-        completions: &[String],
-        completion_index: Option<usize>,
+    /// Print history entries, each with its position in the full history
+    pub fn show_history(
+        out: &mut dyn Write,
+        entries: &[(usize, &HistoryEntry)],
+        show_timestamps: bool,
     ) -> Result<()> {
-        if completions.len() <= 1 {
+        if entries.is_empty() {
+            writeln!(out, "No history available")?;
             return Ok(());
         }
 
-        execute!(
-            stdout(),
-            Print(&format!(
-                "\nCompletions ({}/{}):\n",
-                completion_index.map(|i| i + 1).unwrap_or(0),
-                completions.len()
-            ))
-        )?;
-
-        let max_display = 10;
-        let start_idx = if completions.len() <= max_display {
-            0
-        } else {
-            let current = completion_index.unwrap_or(0);
-            if current < max_display / 2 {
-                0
-            } else if current > completions.len() - max_display / 2 {
-                completions.len() - max_display
+        for &(number, entry) in entries {
+            if show_timestamps {
+                let time = Utils::format_timestamp(entry.timestamp);
+                writeln!(out, "{:4}: {}  {}", number, time, entry.command)?;
             } else {
-                current - max_display / 2
+                writeln!(out, "{:4}: {}", number, entry.command)?;
             }
-        };
-
-        for (i, completion) in completions
-            .iter()
-            .enumerate()
-            .skip(start_idx)
-            .take(max_display)
-        {
-            let marker = if Some(i) == completion_index {
-                ">"
-            } else {
-                " "
-            };
-            execute!(stdout(), Print(&format!("  {}{}\n", marker, completion)))?;
         }
-
-        if completions.len() > max_display {
-            execute!(
-                stdout(),
-                Print(&format!(
-                    "  ... ({} more)\n",
-                    completions.len() - max_display
-                ))
-            )?;
-        }
-
         Ok(())
     }
 
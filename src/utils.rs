@@ -1,72 +1,1457 @@
-use anyhow::Result;
+use crate::error::ShellError;
+use anyhow::{Result, anyhow};
+use std::borrow::Cow;
+use std::cell::RefCell;
+#[cfg(unix)]
+use std::ffi::{CStr, CString};
+use std::io::ErrorKind;
+use std::iter::Peekable;
+use std::ops::Range;
 #[cfg(unix)]
 use std::os::unix::fs::PermissionsExt;
-use std::path::Path;
+#[cfg(unix)]
+use std::os::unix::process::ExitStatusExt;
+use std::path::{Component, Path, PathBuf};
+use std::process::{Command, ExitStatus, Stdio};
+use std::str::Chars;
+#[cfg(unix)]
+use std::sync::OnceLock;
+use std::time::Duration;
+use unicode_segmentation::UnicodeSegmentation;
+use unicode_width::UnicodeWidthStr;
+
+/// Supplies the values `$` expansions stand for
+pub trait Expander {
+    /// The value of variable `name`, if it's set
+    fn variable(&self, name: &str) -> Option<String>;
+
+    /// The output of `$(command)`, or `None` to leave the substitution
+    /// as written
+    fn command_output(&mut self, command: &str) -> Option<String>;
+}
+
+/// A lookup function expands variables alone, leaving `$(...)` as written
+impl<F: Fn(&str) -> Option<String>> Expander for F {
+    fn variable(&self, name: &str) -> Option<String> {
+        self(name)
+    }
+
+    fn command_output(&mut self, _command: &str) -> Option<String> {
+        None
+    }
+}
+
+/// How a command in a chain is joined to the one before it
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Connector {
+    /// `&&`: run only if the previous command succeeded
+    And,
+    /// `||`: run only if the previous command failed
+    Or,
+}
+
+thread_local! {
+    /// The last `{git}` prompt segment and the directory it was computed for
+    static GIT_PROMPT_CACHE: RefCell<Option<(PathBuf, String)>> = const { RefCell::new(None) };
+}
+
+/// A command line in history and when it was run, in seconds since the
+/// Unix epoch
+#[derive(Debug, Clone, PartialEq)]
+pub struct HistoryEntry {
+    pub command: String,
+    pub timestamp: u64,
+}
+
+impl HistoryEntry {
+    pub fn new(command: &str) -> Self {
+        Self {
+            command: command.to_string(),
+            timestamp: Utils::now(),
+        }
+    }
+}
+
+/// Which mode the vi line editor is in
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum ViMode {
+    Insert,
+    Normal,
+}
+
+/// Shell state that prompt placeholders can show
+#[derive(Debug, Clone, Copy)]
+pub struct PromptState {
+    pub last_exit_code: i32,
+    /// The vi editing mode, or `None` when using emacs bindings
+    pub vi_mode: Option<ViMode>,
+    /// Set while reading the second or later line of a command
+    pub continuation: bool,
+    /// How long the last command took, if long enough to show
+    pub duration: Option<Duration>,
+}
+
+/// Why a command line needs another line before it can run
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Continuation {
+    /// It ends with a backslash escaping the newline
+    Escaped,
+    /// A quote, `$(` or `{` hasn't been closed yet
+    Unclosed,
+}
+
+/// A `<<WORD` here-document in a command line
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heredoc {
+    /// Where the `<<WORD` operator sits in `Heredocs::command`
+    pub range: Range<usize>,
+    pub delimiter: String,
+    /// `<<-`: leading tabs are stripped from the body and delimiter lines
+    pub strip_tabs: bool,
+    /// The delimiter was unquoted, so `$VAR`s in the body are expanded
+    pub expand: bool,
+    /// The body, or `None` until the delimiter line has been read
+    pub body: Option<String>,
+}
+
+/// A command line split into its command lines and here-document bodies
+#[derive(Debug, Clone, PartialEq)]
+pub struct Heredocs {
+    /// The input without the body and delimiter lines
+    pub command: String,
+    pub docs: Vec<Heredoc>,
+}
+
+impl Heredocs {
+    /// Whether a here-document is still waiting for its delimiter line
+    pub fn pending(&self) -> bool {
+        self.docs.iter().any(|doc| doc.body.is_none())
+    }
+}
+
+/// A piece of a command line as the tokenizer sees it
+#[derive(Debug, Clone, PartialEq)]
+pub enum Token {
+    /// A word, and whether any of it was quoted or escaped
+    Word(String, bool),
+    /// An unquoted operator: `|`, `&`, `<`, `<<`, `>`, `>>`, `>&`, `2>`,
+    /// `2>>` or `2>&`
+    Op(&'static str),
+}
+
+impl Token {
+    /// The word's text, or the operator as typed
+    pub fn text(&self) -> &str {
+        match self {
+            Token::Word(text, _) => text,
+            Token::Op(op) => op,
+        }
+    }
+}
+
+/// Field separators when `$IFS` isn't set
+const DEFAULT_IFS: &str = " \t\n";
+
+/// Check if a character is a glob metacharacter
+fn is_glob_char(ch: char) -> bool {
+    matches!(ch, '*' | '?' | '[')
+}
+
+/// How much of a finished word `Word::finish` expands
+#[derive(Clone, Copy, PartialEq)]
+enum Expand {
+    /// Nothing, keeping the word as written
+    None,
+    /// A leading `~`, as in an assignment's value
+    Tilde,
+    /// A leading `~`, then globs, as in a command's words
+    TildeAndGlobs,
+}
+
+/// A word being assembled by the tokenizer
+#[derive(Default)]
+struct Word {
+    text: String,
+    /// `text` with quoted metacharacters escaped, for glob matching
+    pattern: String,
+    /// Set when the word contains quoted or escaped characters, so that e.g.
+    /// a quoted "2" is never mistaken for a file descriptor and `""` still
+    /// produces an (empty) argument
+    quoted: bool,
+    /// Set when any of the text came from an expansion, which like quoted
+    /// text never makes a file descriptor: `$f>out` writes `$f` to `out`
+    expanded: bool,
+    /// Set when the word contains an unquoted glob metacharacter
+    has_glob: bool,
+    /// Set when the word starts with an unquoted `~`
+    tilde: bool,
+}
+
+impl Word {
+    fn push(&mut self, ch: char, quoted: bool) {
+        if self.text.is_empty() && ch == '~' && !quoted {
+            self.tilde = true;
+        }
+        self.push_text(ch, quoted);
+    }
+
+    /// Like `push`, for text that came from a variable: tilde expansion
+    /// happens before variables are expanded, so a `~` in a value is kept
+    fn push_expanded(&mut self, ch: char, quoted: bool) {
+        self.expanded = true;
+        self.push_text(ch, quoted);
+    }
+
+    fn push_text(&mut self, ch: char, quoted: bool) {
+        self.text.push(ch);
+        if is_glob_char(ch) && !quoted {
+            self.has_glob = true;
+        } else if is_glob_char(ch) || ch == '\\' {
+            self.pattern.push('\\');
+        }
+        self.pattern.push(ch);
+    }
+
+    /// Add an unquoted expansion's value, splitting it into fields at the
+    /// characters in `ifs` like a POSIX shell: runs of IFS whitespace
+    /// separate fields and vanish at the ends, while any other IFS
+    /// character ends a field even if it's empty, so `a::b` splits into
+    /// `a`, an empty field and `b`
+    fn split_fields(&mut self, value: &str, ifs: &str, tokens: &mut Vec<Token>, expand: Expand) {
+        // Whitespace next to another delimiter is part of that delimiter
+        let mut after_whitespace = false;
+        for ch in value.chars() {
+            if !ifs.contains(ch) {
+                self.push_expanded(ch, false);
+                after_whitespace = false;
+            } else if ch.is_whitespace() {
+                if !self.is_empty() {
+                    self.finish(tokens, expand);
+                    after_whitespace = true;
+                }
+            } else if self.is_empty() && !after_whitespace {
+                tokens.push(Token::Word(String::new(), false));
+            } else {
+                self.finish(tokens, expand);
+                after_whitespace = false;
+            }
+        }
+    }
+
+    fn is_empty(&self) -> bool {
+        self.text.is_empty() && !self.quoted
+    }
+
+    /// Push the finished word onto `tokens`, tilde- and glob-expanding it
+    /// as far as `expand` asks, and reset for the next word
+    fn finish(&mut self, tokens: &mut Vec<Token>, expand: Expand) {
+        let mut word = std::mem::take(self);
+        if word.text.is_empty() && !word.quoted {
+            return;
+        }
+        if expand != Expand::None && word.tilde {
+            word.text = Utils::expand_path(&word.text);
+            word.pattern = Utils::expand_path(&word.pattern);
+        }
+        if expand == Expand::TildeAndGlobs && word.has_glob {
+            let matches = Utils::expand_globs(&word.pattern);
+            // Like bash, a pattern that matches nothing is left as-is
+            if !matches.is_empty() {
+                tokens.extend(matches.into_iter().map(|m| Token::Word(m, word.quoted)));
+                return;
+            }
+        }
+        tokens.push(Token::Word(word.text, word.quoted));
+    }
+}
+
+/// Follows quoting through a command line one character at a time, so
+/// everything that splits a line agrees on what is quoted. The inside of a
+/// `$(...)` counts as quoted: its operators belong to the command inside.
+#[derive(Default)]
+struct Quoting {
+    /// Closers owed for each open quote or `$(`, innermost last
+    open: Vec<char>,
+    escaped: bool,
+    /// Set on the `$` of a `$(`, so the `(` after it opens a substitution
+    opening: bool,
+}
+
+impl Quoting {
+    /// Step past `ch`, with `next` the character after it. Returns true if
+    /// `ch` is unquoted syntax: outside all quotes and substitutions, not
+    /// escaped, and not itself a quote or backslash.
+    fn unquoted(&mut self, ch: char, next: Option<char>) -> bool {
+        if self.escaped {
+            self.escaped = false;
+            return false;
+        }
+        if self.opening {
+            self.opening = false;
+            self.open.push(')');
+            return false;
+        }
+        let closer = self.open.last().copied();
+        match ch {
+            _ if closer == Some('\'') => {
+                if ch == '\'' {
+                    self.open.pop();
+                }
+                false
+            }
+            '\\' => {
+                self.escaped = true;
+                false
+            }
+            '$' if next == Some('(') => {
+                self.opening = true;
+                false
+            }
+            _ if closer == Some('"') => {
+                if ch == '"' {
+                    self.open.pop();
+                }
+                false
+            }
+            '"' | '\'' => {
+                self.open.push(ch);
+                false
+            }
+            ')' if closer == Some(')') => {
+                self.open.pop();
+                false
+            }
+            _ => closer.is_none(),
+        }
+    }
+}
 
 /// Utility functions for the shell
 pub struct Utils;
 
 impl Utils {
-    /// Expand tilde (~) to home directory
+    /// Expand a leading `~` or `~user` to the matching home directory,
+    /// leaving the path unchanged if it can't be resolved
     pub fn expand_path(path: &str) -> String {
-        if path.starts_with('~') {
-            if let Ok(home) = std::env::var("HOME") {
-                path.replacen('~', &home, 1)
+        Self::expand_path_from(path, std::env::var("HOME").ok().as_deref())
+    }
+
+    /// Like `expand_path`, with `home` standing in for `$HOME`
+    pub fn expand_path_from(path: &str, home: Option<&str>) -> String {
+        let Some(rest) = path.strip_prefix('~') else {
+            return path.to_string();
+        };
+        let (user, remainder) = match rest.find('/') {
+            Some(i) => rest.split_at(i),
+            None => (rest, ""),
+        };
+        let home = if user.is_empty() {
+            home.map(str::to_string)
+        } else {
+            Self::user_home_dir(user)
+        };
+
+        match home {
+            Some(home) => format!("{}{}", home, remainder),
+            None => path.to_string(),
+        }
+    }
+
+    /// Look up a user's home directory in the passwd database
+    #[cfg(unix)]
+    fn user_home_dir(user: &str) -> Option<String> {
+        let name = CString::new(user).ok()?;
+        // SAFETY: getpwnam returns null or a pointer to static storage that
+        // stays valid until the next passwd call; we copy the directory out
+        // before returning
+        unsafe {
+            let entry = libc::getpwnam(name.as_ptr());
+            if entry.is_null() || (*entry).pw_dir.is_null() {
+                return None;
+            }
+            Some(
+                CStr::from_ptr((*entry).pw_dir)
+                    .to_string_lossy()
+                    .into_owned(),
+            )
+        }
+    }
+
+    #[cfg(not(unix))]
+    fn user_home_dir(_user: &str) -> Option<String> {
+        None
+    }
+
+    /// Parse command line into tokens, handling quotes and escapes
+    pub fn parse_command(input: &str) -> Vec<String> {
+        Self::tokenize_command(input)
+            .into_iter()
+            .map(|token| match token {
+                Token::Word(text, _) => text,
+                Token::Op(op) => op.to_string(),
+            })
+            .collect()
+    }
+
+    /// Split a command line into words and operators, so a quoted `">"`
+    /// stays a word rather than a redirection
+    pub fn tokenize_command(input: &str) -> Vec<Token> {
+        Self::tokenize(input, None, false)
+    }
+
+    /// Like `tokenize_command`, expanding each word in the POSIX order:
+    /// a leading `~`, then `$NAME`, `${NAME}` and `$(command)` from
+    /// `expander`, then globs. Single quotes suppress all of them. Double
+    /// quotes suppress `~` and globs but not `$`, and keep an expansion's
+    /// value in one word; unquoted values are split on `$IFS`. Expansions
+    /// happen as they are reached, so a quoted `$(...)` never runs.
+    pub fn tokenize_command_with(input: &str, expander: &mut dyn Expander) -> Vec<Token> {
+        Self::tokenize(input, Some(expander), true)
+    }
+
+    /// Split the leading `NAME=value` assignments off a command, returning
+    /// each name with its value as written, and the rest of the command.
+    /// The name must be unquoted, and the value runs to the first unquoted
+    /// blank or redirection.
+    pub fn split_assignments(input: &str) -> (Vec<(String, String)>, &str) {
+        let mut assignments = Vec::new();
+        let mut rest = input.trim_start();
+        while let Some((name, after)) = rest.split_once('=')
+            && Self::is_valid_identifier(name)
+        {
+            let mut quoting = Quoting::default();
+            let mut chars = after.char_indices().peekable();
+            let mut end = after.len();
+            while let Some((i, ch)) = chars.next() {
+                if quoting.unquoted(ch, chars.peek().map(|&(_, c)| c))
+                    && (ch.is_whitespace() || "|<>".contains(ch))
+                {
+                    end = i;
+                    break;
+                }
+            }
+            assignments.push((name.to_string(), after[..end].to_string()));
+            rest = after[end..].trim_start();
+        }
+        (assignments, rest)
+    }
+
+    /// Expand an assignment's value like a word, except that it's never
+    /// split into fields or glob-expanded, as in POSIX shells
+    pub fn expand_value(value: &str, expander: &mut dyn Expander) -> String {
+        Self::tokenize(value, Some(expander), false)
+            .first()
+            .map_or_else(String::new, |token| token.text().to_string())
+    }
+
+    /// Read a variable name following a `$`, supporting both the bare and
+    /// braced forms. Returns `None` (consuming nothing) if no name follows,
+    /// in which case the `$` should be taken literally.
+    fn read_variable_name(chars: &mut Peekable<Chars>) -> Option<String> {
+        match chars.peek() {
+            Some('{') => {
+                let mut lookahead = chars.clone();
+                lookahead.next();
+                let mut name = String::new();
+                for ch in lookahead.by_ref() {
+                    if ch == '}' {
+                        *chars = lookahead;
+                        return Some(name);
+                    }
+                    name.push(ch);
+                }
+                // Unterminated `${`
+                None
+            }
+            // `$?` is the only single-character special parameter so far
+            Some('?') => {
+                chars.next();
+                Some("?".to_string())
+            }
+            Some(&ch) if ch.is_ascii_alphanumeric() || ch == '_' => {
+                let mut name = String::new();
+                while let Some(&ch) = chars.peek() {
+                    if !(ch.is_ascii_alphanumeric() || ch == '_') {
+                        break;
+                    }
+                    name.push(ch);
+                    chars.next();
+                }
+                Some(name)
+            }
+            _ => None,
+        }
+    }
+
+    /// Read the command of a `$(command)` whose `$` was just consumed, up
+    /// to the matching `)`. Returns `None` (consuming nothing) if no `(`
+    /// follows or it's never closed, in which case the `$` is literal.
+    fn read_command_substitution(chars: &mut Peekable<Chars>) -> Option<String> {
+        if chars.peek() != Some(&'(') {
+            return None;
+        }
+        let mut lookahead = chars.clone();
+        lookahead.next();
+        // Inside the substitution, owed its `)`
+        let mut quoting = Quoting {
+            open: vec![')'],
+            ..Quoting::default()
+        };
+        let mut command = String::new();
+        while let Some(ch) = lookahead.next() {
+            quoting.unquoted(ch, lookahead.peek().copied());
+            if quoting.open.is_empty() {
+                *chars = lookahead;
+                return Some(command);
+            }
+            command.push(ch);
+        }
+        None
+    }
+
+    /// Split `input` into tokens, expanding words if there's an `expander`.
+    /// Unless `fields` is set, expansions aren't split on `$IFS` and globs
+    /// are left alone.
+    fn tokenize(input: &str, mut expander: Option<&mut dyn Expander>, fields: bool) -> Vec<Token> {
+        // Globbing is part of expansion, so only the expanding parse does it
+        let expand = match (&expander, fields) {
+            (None, _) => Expand::None,
+            (Some(_), false) => Expand::Tilde,
+            (Some(_), true) => Expand::TildeAndGlobs,
+        };
+        let mut tokens = Vec::new();
+        let mut word = Word::default();
+        let mut in_quotes = false;
+        let mut quote_char = '"';
+        let mut escape_next = false;
+        let mut chars = input.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if escape_next {
+                word.push(ch, true);
+                escape_next = false;
+                continue;
+            }
+
+            match ch {
+                // Backslash follows POSIX quoting: it escapes anything when
+                // unquoted, only `$ ` " \` inside double quotes, and nothing
+                // inside single quotes. Sequences like `\n` are therefore
+                // passed through untouched for commands such as `echo -e`.
+                '\\' if !in_quotes => {
+                    escape_next = true;
+                    word.quoted = true;
+                }
+                '\\' if quote_char == '"' => {
+                    if matches!(chars.peek(), Some('$' | '`' | '"' | '\\')) {
+                        escape_next = true;
+                    } else {
+                        word.push(ch, true);
+                    }
+                }
+                '"' | '\'' if !in_quotes => {
+                    in_quotes = true;
+                    quote_char = ch;
+                    word.quoted = true;
+                }
+                ch if in_quotes && ch == quote_char => {
+                    in_quotes = false;
+                }
+                '$' if !(in_quotes && quote_char == '\'') => {
+                    let value = if let Some(command) = Self::read_command_substitution(&mut chars) {
+                        match expander
+                            .as_deref_mut()
+                            .and_then(|e| e.command_output(&command))
+                        {
+                            Some(output) => Some(output),
+                            None => {
+                                // Unexpanded, the substitution stays one word
+                                for ch in format!("$({})", command).chars() {
+                                    word.push(ch, in_quotes);
+                                }
+                                continue;
+                            }
+                        }
+                    } else {
+                        match expander.as_deref() {
+                            Some(expander) => Self::read_variable_name(&mut chars)
+                                .map(|name| expander.variable(&name).unwrap_or_default()),
+                            None => None,
+                        }
+                    };
+                    match value {
+                        Some(value) if in_quotes || !fields => {
+                            for ch in value.chars() {
+                                word.push_expanded(ch, in_quotes);
+                            }
+                        }
+                        Some(value) => {
+                            let ifs = expander
+                                .as_deref()
+                                .and_then(|e| e.variable("IFS"))
+                                .unwrap_or_else(|| DEFAULT_IFS.to_string());
+                            word.split_fields(&value, &ifs, &mut tokens, expand);
+                        }
+                        None => word.push('$', in_quotes),
+                    }
+                }
+                ' ' | '\t' | '\n' if !in_quotes => {
+                    word.finish(&mut tokens, expand);
+                }
+                '>' if !in_quotes => {
+                    // `2>` redirects stderr and `1>` is a plain `>`; any
+                    // other pending word ends here
+                    let fd = (!word.quoted && !word.expanded).then_some(word.text.as_str());
+                    let stderr = fd == Some("2");
+                    if stderr || fd == Some("1") {
+                        word = Word::default();
+                    } else {
+                        word.finish(&mut tokens, expand);
+                    }
+                    let append = chars.next_if_eq(&'>').is_some();
+                    // `>&2` and `2>&1` point one stream at the other
+                    let duplicate = !append && chars.next_if_eq(&'&').is_some();
+                    tokens.push(Token::Op(match (stderr, append, duplicate) {
+                        (false, false, false) => ">",
+                        (false, true, _) => ">>",
+                        (false, false, true) => ">&",
+                        (true, false, false) => "2>",
+                        (true, true, _) => "2>>",
+                        (true, false, true) => "2>&",
+                    }));
+                }
+                '<' if !in_quotes => {
+                    word.finish(&mut tokens, expand);
+                    if chars.next_if_eq(&'<').is_some() {
+                        tokens.push(Token::Op("<<"));
+                    } else {
+                        tokens.push(Token::Op("<"));
+                    }
+                }
+                '|' if !in_quotes => {
+                    word.finish(&mut tokens, expand);
+                    tokens.push(Token::Op("|"));
+                }
+                '&' if !in_quotes => {
+                    word.finish(&mut tokens, expand);
+                    tokens.push(Token::Op("&"));
+                }
+                _ => word.push(ch, in_quotes),
+            }
+        }
+
+        word.finish(&mut tokens, expand);
+        tokens
+    }
+
+    /// Expand a glob pattern against the filesystem, returning the sorted
+    /// matches, or nothing if no path matches. Supports `*`, `?`, `[...]`
+    /// classes and `**` for any number of directories; metacharacters
+    /// escaped with a backslash match literally.
+    pub fn expand_globs(token: &str) -> Vec<String> {
+        let (mut candidates, rest) = match token.strip_prefix('/') {
+            Some(rest) => (vec!["/".to_string()], rest),
+            None => (vec![String::new()], token),
+        };
+        let components: Vec<&str> = rest.split('/').collect();
+
+        for (i, component) in components.iter().enumerate() {
+            let is_last = i + 1 == components.len();
+            let mut next = Vec::new();
+            for base in &candidates {
+                if *component == "**" {
+                    Self::glob_walk(base, is_last, &mut next);
+                } else if Self::has_glob_chars(component) {
+                    let dir = if base.is_empty() { "." } else { base.as_str() };
+                    let Ok(entries) = std::fs::read_dir(dir) else {
+                        continue;
+                    };
+                    for entry in entries.flatten() {
+                        let name = entry.file_name().to_string_lossy().into_owned();
+                        // Hidden files only match a pattern that asks for them
+                        if name.starts_with('.') && !component.starts_with('.') {
+                            continue;
+                        }
+                        if Self::glob_match(component, &name) {
+                            next.push(Self::join_glob_path(base, &name));
+                        }
+                    }
+                } else {
+                    next.push(Self::join_glob_path(base, &Self::unescape_glob(component)));
+                }
+            }
+            candidates = next;
+        }
+
+        // Literal components were joined on faith; drop paths that don't exist
+        candidates.retain(|path| std::fs::symlink_metadata(path).is_ok());
+        candidates.sort();
+        candidates.dedup();
+        candidates
+    }
+
+    /// Collect what `**` matches under `base`: every directory (including
+    /// `base` itself) when more components follow, or every entry when it's
+    /// the last component. Symlinks are not followed, so loops can't recurse.
+    fn glob_walk(base: &str, include_files: bool, out: &mut Vec<String>) {
+        if !include_files {
+            out.push(base.to_string());
+        }
+        let dir = if base.is_empty() { "." } else { base };
+        let Ok(entries) = std::fs::read_dir(dir) else {
+            return;
+        };
+        for entry in entries.flatten() {
+            let name = entry.file_name().to_string_lossy().into_owned();
+            if name.starts_with('.') {
+                continue;
+            }
+            let path = Self::join_glob_path(base, &name);
+            let is_dir = entry.file_type().map(|ft| ft.is_dir()).unwrap_or(false);
+            if is_dir {
+                if include_files {
+                    out.push(path.clone());
+                }
+                Self::glob_walk(&path, include_files, out);
+            } else if include_files {
+                out.push(path);
+            }
+        }
+    }
+
+    fn join_glob_path(base: &str, name: &str) -> String {
+        if base.is_empty() {
+            name.to_string()
+        } else if base.ends_with('/') {
+            format!("{}{}", base, name)
+        } else {
+            format!("{}/{}", base, name)
+        }
+    }
+
+    /// Check if a pattern contains an unescaped glob metacharacter
+    fn has_glob_chars(pattern: &str) -> bool {
+        let mut chars = pattern.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => {
+                    chars.next();
+                }
+                ch if is_glob_char(ch) => return true,
+                _ => {}
+            }
+        }
+        false
+    }
+
+    /// Remove the backslashes that escape characters in a glob pattern
+    fn unescape_glob(pattern: &str) -> String {
+        let mut result = String::new();
+        let mut chars = pattern.chars();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' => result.extend(chars.next()),
+                _ => result.push(ch),
+            }
+        }
+        result
+    }
+
+    /// Match a single path component against a glob pattern
+    fn glob_match(pattern: &str, name: &str) -> bool {
+        let pattern: Vec<char> = pattern.chars().collect();
+        let name: Vec<char> = name.chars().collect();
+        Self::glob_match_chars(&pattern, &name)
+    }
+
+    /// Match with the two-pointer method: on a mismatch, go back to just
+    /// after the last `*` and let it swallow one more character. Earlier
+    /// stars never need revisiting, so this is linear in practice rather
+    /// than exponential like naive backtracking on `*a*a*a*b`.
+    fn glob_match_chars(pattern: &[char], name: &[char]) -> bool {
+        let (mut p, mut n) = (0, 0);
+        // The pattern position after the last `*`, and how much of the
+        // name that star has taken so far
+        let mut star: Option<(usize, usize)> = None;
+        while n < name.len() {
+            if pattern.get(p) == Some(&'*') {
+                p += 1;
+                star = Some((p, n));
+            } else if let Some(len) = Self::match_one(&pattern[p..], name[n]) {
+                p += len;
+                n += 1;
+            } else if let Some((after, taken)) = star {
+                p = after;
+                n = taken + 1;
+                star = Some((after, n));
+            } else {
+                return false;
+            }
+        }
+        pattern[p..].iter().all(|&ch| ch == '*')
+    }
+
+    /// Match `ch` against the pattern element at the start of `pattern`,
+    /// returning how many pattern characters the element takes up
+    fn match_one(pattern: &[char], ch: char) -> Option<usize> {
+        match *pattern.first()? {
+            '?' => Some(1),
+            '[' => match Self::match_class(&pattern[1..], ch) {
+                Some((matched, rest)) => matched.then_some(pattern.len() - rest.len()),
+                // An unterminated class is just a literal `[`
+                None => (ch == '[').then_some(1),
+            },
+            '\\' if pattern.len() > 1 => (pattern[1] == ch).then_some(2),
+            literal => (literal == ch).then_some(1),
+        }
+    }
+
+    /// Match `ch` against a `[...]` class whose body starts at `pattern`,
+    /// returning whether it matched and the pattern after the closing `]`.
+    /// Returns `None` if the class is never closed.
+    fn match_class(pattern: &[char], ch: char) -> Option<(bool, &[char])> {
+        let (negated, mut i) = match pattern.first() {
+            Some('!') | Some('^') => (true, 1),
+            _ => (false, 0),
+        };
+        let mut matched = false;
+        let mut first = true;
+
+        loop {
+            let mut start = *pattern.get(i)?;
+            // A `]` right after the opening bracket is a literal member
+            if start == ']' && !first {
+                break;
+            }
+            first = false;
+            if start == '\\' {
+                i += 1;
+                start = *pattern.get(i)?;
+            }
+
+            let mut end = start;
+            if pattern.get(i + 1) == Some(&'-') && pattern.get(i + 2).is_some_and(|&c| c != ']') {
+                i += 2;
+                end = pattern[i];
+                if end == '\\' {
+                    i += 1;
+                    end = *pattern.get(i)?;
+                }
+            }
+            if start <= ch && ch <= end {
+                matched = true;
+            }
+            i += 1;
+        }
+
+        Some((matched != negated, &pattern[i + 1..]))
+    }
+
+    /// Remove `#` comments: an unquoted `#` at the start of a word hides the
+    /// rest of its line. A `#` inside a word (`a#b`) or quotes is literal.
+    pub fn strip_comments(input: &str) -> String {
+        let mut result = String::new();
+        let mut quoting = Quoting::default();
+        let mut in_comment = false;
+        let mut previous: Option<char> = None;
+        let mut chars = input.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if in_comment {
+                if ch != '\n' {
+                    continue;
+                }
+                in_comment = false;
+            } else if quoting.unquoted(ch, chars.peek().copied())
+                && ch == '#'
+                && previous.is_none_or(|p| p.is_whitespace() || ";&|()".contains(p))
+            {
+                in_comment = true;
+                continue;
+            }
+            result.push(ch);
+            previous = Some(ch);
+        }
+        result
+    }
+
+    /// Split here-documents out of a command line. Lines are read in order:
+    /// once a line has `<<WORD` operators, the lines after it are their
+    /// bodies, each ending at a line that is exactly `WORD`.
+    pub fn heredocs(input: &str) -> Heredocs {
+        let mut command = String::new();
+        let mut docs: Vec<Heredoc> = Vec::new();
+        // Index of the first document still collecting its body
+        let mut collecting = 0;
+
+        for line in input.split('\n') {
+            if let Some(doc) = docs.get_mut(collecting) {
+                let line = if doc.strip_tabs {
+                    line.trim_start_matches('\t')
+                } else {
+                    line
+                };
+                let body = doc.body.get_or_insert_with(String::new);
+                if line == doc.delimiter {
+                    collecting += 1;
+                } else {
+                    body.push_str(line);
+                    body.push('\n');
+                }
+                continue;
+            }
+
+            if !command.is_empty() {
+                command.push('\n');
+            }
+            let offset = command.len();
+            command.push_str(line);
+            for (range, doc) in Self::heredoc_operators(line) {
+                docs.push(Heredoc {
+                    range: range.start + offset..range.end + offset,
+                    ..doc
+                });
+            }
+        }
+
+        // A document whose delimiter never came has no body yet
+        for doc in docs.iter_mut().skip(collecting) {
+            doc.body = None;
+        }
+        Heredocs { command, docs }
+    }
+
+    /// Find the unquoted `<<WORD` and `<<-WORD` operators in one line
+    fn heredoc_operators(line: &str) -> Vec<(Range<usize>, Heredoc)> {
+        let mut operators = Vec::new();
+        let mut in_quotes = false;
+        let mut quote_char = '"';
+        let mut escape_next = false;
+        let mut previous: Option<char> = None;
+        let mut chars = line.char_indices().peekable();
+
+        while let Some((start, ch)) = chars.next() {
+            if escape_next {
+                escape_next = false;
+            } else if in_quotes {
+                if ch == quote_char {
+                    in_quotes = false;
+                }
             } else {
-                path.to_string()
+                match ch {
+                    '\\' => escape_next = true,
+                    '"' | '\'' => {
+                        in_quotes = true;
+                        quote_char = ch;
+                    }
+                    '#' if previous.is_none_or(|p| p.is_whitespace() || ";&|()".contains(p)) => {
+                        break;
+                    }
+                    '<' if chars.peek().map(|&(_, c)| c) == Some('<') => {
+                        chars.next();
+                        let strip_tabs = chars.next_if(|&(_, c)| c == '-').is_some();
+                        while chars.next_if(|&(_, c)| c == ' ' || c == '\t').is_some() {}
+
+                        // The delimiter word, with any quoting removed
+                        let mut delimiter = String::new();
+                        let mut expand = true;
+                        let mut word_quote: Option<char> = None;
+                        let mut end = line.len();
+                        while let Some(&(i, c)) = chars.peek() {
+                            match (word_quote, c) {
+                                (None, c) if c.is_whitespace() || ";&|<>()".contains(c) => {
+                                    end = i;
+                                    break;
+                                }
+                                (None, '"' | '\'') => {
+                                    word_quote = Some(c);
+                                    expand = false;
+                                }
+                                (None, '\\') => {
+                                    expand = false;
+                                    chars.next();
+                                    if let Some(&(_, c)) = chars.peek() {
+                                        delimiter.push(c);
+                                    }
+                                }
+                                (Some(q), c) if c == q => word_quote = None,
+                                (_, c) => delimiter.push(c),
+                            }
+                            chars.next();
+                        }
+
+                        if !delimiter.is_empty() {
+                            let doc = Heredoc {
+                                range: 0..0,
+                                delimiter,
+                                strip_tabs,
+                                expand,
+                                body: None,
+                            };
+                            operators.push((start..end, doc));
+                        }
+                        previous = None;
+                        continue;
+                    }
+                    _ => {}
+                }
+            }
+            previous = Some(ch);
+        }
+        operators
+    }
+
+    /// Expand `$VAR`, `${VAR}` and `$(command)` in a here-document body. A
+    /// backslash keeps a following `$`, `` ` `` or `\` literal.
+    pub fn expand_variables(text: &str, expander: &mut dyn Expander) -> String {
+        let mut result = String::new();
+        let mut chars = text.chars().peekable();
+        while let Some(ch) = chars.next() {
+            match ch {
+                '\\' if matches!(chars.peek(), Some('$' | '`' | '\\')) => {
+                    result.extend(chars.next());
+                }
+                '$' => {
+                    if let Some(command) = Self::read_command_substitution(&mut chars) {
+                        match expander.command_output(&command) {
+                            Some(output) => result.push_str(&output),
+                            None => result.push_str(&format!("$({})", command)),
+                        }
+                    } else if let Some(name) = Self::read_variable_name(&mut chars) {
+                        result.push_str(&expander.variable(&name).unwrap_or_default());
+                    } else {
+                        result.push('$');
+                    }
+                }
+                _ => result.push(ch),
+            }
+        }
+        result
+    }
+
+    /// Wrap text in single quotes so the tokenizer reads it back verbatim
+    pub fn single_quote(text: &str) -> String {
+        format!("'{}'", text.replace('\'', "'\\''"))
+    }
+
+    /// Backslash-escape the characters the shell would otherwise treat
+    /// specially, so the tokenizer reads `text` back as one word. A `~` is
+    /// left alone, since one at the start of a completion came from what
+    /// was typed.
+    pub fn escape_word(text: &str) -> String {
+        let mut escaped = String::with_capacity(text.len());
+        for ch in text.chars() {
+            if ch.is_whitespace() || "\\'\"$`|&;<>()*?[]{}!#".contains(ch) {
+                escaped.push('\\');
+            }
+            escaped.push(ch);
+        }
+        escaped
+    }
+
+    /// Byte offset where the word being typed at the end of `input` starts,
+    /// after the last unquoted, unescaped space or operator. Equal to
+    /// `input.len()` when a new word hasn't been started.
+    pub fn current_word_start(input: &str) -> usize {
+        let mut start = 0;
+        let mut in_quotes = false;
+        let mut quote_char = '"';
+        let mut escape_next = false;
+
+        for (i, ch) in input.char_indices() {
+            if escape_next {
+                escape_next = false;
+                continue;
+            }
+            match ch {
+                '\\' if !(in_quotes && quote_char == '\'') => escape_next = true,
+                '"' | '\'' if !in_quotes => {
+                    in_quotes = true;
+                    quote_char = ch;
+                }
+                ch if in_quotes && ch == quote_char => in_quotes = false,
+                ch if !in_quotes && (ch.is_whitespace() || "|&;<>".contains(ch)) => {
+                    start = i + ch.len_utf8();
+                }
+                _ => {}
+            }
+        }
+        start
+    }
+
+    /// Byte ranges of the words in command position: the first word of the
+    /// line and of each segment after `|`, `&&`, `||`, `;` or `&`. Leading
+    /// `NAME=value` assignments are skipped, and scanning stops at a comment.
+    pub fn command_words(input: &str) -> Vec<Range<usize>> {
+        let mut words = Vec::new();
+        let mut in_quotes = false;
+        let mut quote_char = '"';
+        let mut escape_next = false;
+        let mut word_start: Option<usize> = None;
+        let mut command_position = true;
+
+        let mut end_word = |start: &mut Option<usize>, end: usize, command_position: &mut bool| {
+            if let Some(start) = start.take() {
+                let word = &input[start..end];
+                let is_assignment = word
+                    .split_once('=')
+                    .is_some_and(|(name, _)| Self::is_valid_identifier(name));
+                if *command_position && !is_assignment {
+                    words.push(start..end);
+                    *command_position = false;
+                }
+            }
+        };
+
+        for (i, ch) in input.char_indices() {
+            if escape_next {
+                escape_next = false;
+                continue;
+            }
+            match ch {
+                '\\' if !(in_quotes && quote_char == '\'') => {
+                    escape_next = true;
+                    word_start.get_or_insert(i);
+                }
+                '"' | '\'' if !in_quotes => {
+                    in_quotes = true;
+                    quote_char = ch;
+                    word_start.get_or_insert(i);
+                }
+                ch if in_quotes && ch == quote_char => in_quotes = false,
+                _ if in_quotes => {}
+                '#' if word_start.is_none() => break,
+                // The `&` of `2>&1` is part of the redirection
+                '&' if input[..i].ends_with('>') => {
+                    word_start.get_or_insert(i);
+                }
+                '|' | '&' | ';' => {
+                    end_word(&mut word_start, i, &mut command_position);
+                    command_position = true;
+                }
+                ch if ch.is_whitespace() => end_word(&mut word_start, i, &mut command_position),
+                _ => {
+                    word_start.get_or_insert(i);
+                }
             }
-        } else {
-            path.to_string()
         }
+        end_word(&mut word_start, input.len(), &mut command_position);
+        words
     }
 
-    /// Parse command line into tokens, handling quotes and escapes
-    pub fn parse_command(input: &str) -> Vec<String> {
-        let mut tokens = Vec::new();
-        let mut current_token = String::new();
+    /// Check whether a command read so far, possibly several lines, needs
+    /// another line: a here-document is waiting for its delimiter, or the
+    /// command lines are incomplete. Bodies are taken literally and a quote
+    /// or backslash inside a comment doesn't need closing, so only what's
+    /// left of them is checked.
+    pub fn command_continuation(command: &str) -> Option<Continuation> {
+        let heredocs = Self::heredocs(command);
+        if heredocs.pending() {
+            return Some(Continuation::Unclosed);
+        }
+        Self::continuation(&Self::strip_comments(&heredocs.command))
+    }
+
+    /// Check whether a command line is incomplete: an unterminated quote,
+    /// a trailing backslash, or an unclosed `$(` or `{`
+    pub fn continuation(input: &str) -> Option<Continuation> {
         let mut in_quotes = false;
         let mut quote_char = '"';
         let mut escape_next = false;
+        // Closers still owed for each open `$(` or `{`
+        let mut open = Vec::new();
+        let mut chars = input.chars().peekable();
 
-        for ch in input.chars() {
+        while let Some(ch) = chars.next() {
             if escape_next {
-                current_token.push(ch);
                 escape_next = false;
                 continue;
             }
 
             match ch {
-                '\\' => escape_next = true,
+                '\\' if !(in_quotes && quote_char == '\'') => escape_next = true,
                 '"' | '\'' if !in_quotes => {
                     in_quotes = true;
                     quote_char = ch;
                 }
-                ch if in_quotes && ch == quote_char => {
-                    in_quotes = false;
+                ch if in_quotes && ch == quote_char => in_quotes = false,
+                _ if in_quotes && quote_char == '\'' => {}
+                '$' if chars.peek() == Some(&'(') => {
+                    chars.next();
+                    open.push(')');
+                }
+                '{' => open.push('}'),
+                ')' | '}' if open.last() == Some(&ch) => {
+                    open.pop();
+                }
+                _ => {}
+            }
+        }
+
+        if escape_next {
+            Some(Continuation::Escaped)
+        } else if in_quotes || !open.is_empty() {
+            Some(Continuation::Unclosed)
+        } else {
+            None
+        }
+    }
+
+    /// Split a command line on unquoted `&&` and `||`, pairing each segment
+    /// with the connector that precedes it (`None` for the first one)
+    pub fn split_chain(input: &str) -> Result<Vec<(Option<Connector>, String)>> {
+        let mut segments = Vec::new();
+        let mut current = String::new();
+        let mut connector = None;
+        let mut quoting = Quoting::default();
+        let mut chars = input.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if quoting.unquoted(ch, chars.peek().copied())
+                && matches!(ch, '&' | '|')
+                && chars.peek() == Some(&ch)
+            {
+                chars.next();
+                let segment = Self::trim_line(&current);
+                if segment.is_empty() {
+                    return Err(ShellError::UnexpectedToken(format!("{}{}", ch, ch)).into());
                 }
-                ' ' | '\t' if !in_quotes => {
-                    if !current_token.is_empty() {
-                        tokens.push(current_token.clone());
-                        current_token.clear();
+                segments.push((connector, segment.to_string()));
+                current.clear();
+                connector = Some(if ch == '&' {
+                    Connector::And
+                } else {
+                    Connector::Or
+                });
+                continue;
+            }
+            current.push(ch);
+        }
+
+        let segment = Self::trim_line(&current);
+        if !segment.is_empty() {
+            segments.push((connector, segment.to_string()));
+        } else if connector.is_some() {
+            return Err(ShellError::Parse("unexpected end of input".to_string()).into());
+        }
+
+        Ok(segments)
+    }
+
+    /// Split a command line into the lists separated by unquoted `;`, `&`
+    /// or newlines, which bind looser than `&&` and `||`, each paired with
+    /// whether it ended in `&` to run in the background. Lists are trimmed
+    /// and a trailing `;` is allowed, as are blank lines; any other empty
+    /// list is a syntax error. The `&` of `>&` belongs to the redirection.
+    pub fn split_sequence(input: &str) -> Result<Vec<(String, bool)>> {
+        let mut lists = Vec::new();
+        let mut current = String::new();
+        let mut quoting = Quoting::default();
+        let mut chars = input.chars().peekable();
+
+        while let Some(ch) = chars.next() {
+            if quoting.unquoted(ch, chars.peek().copied()) {
+                match ch {
+                    '&' if chars.peek() == Some(&'&') => {
+                        chars.next();
+                        current.push_str("&&");
+                        continue;
                     }
+                    ';' | '&' if !(ch == '&' && current.ends_with(['>', '<'])) => {
+                        let list = Self::trim_line(&current);
+                        if list.is_empty() {
+                            return Err(ShellError::UnexpectedToken(ch.to_string()).into());
+                        }
+                        lists.push((list.to_string(), ch == '&'));
+                        current.clear();
+                        continue;
+                    }
+                    '\n' => {
+                        let list = Self::trim_line(&current);
+                        if !list.is_empty() {
+                            lists.push((list.to_string(), false));
+                        }
+                        current.clear();
+                        continue;
+                    }
+                    _ => {}
                 }
-                _ => current_token.push(ch),
             }
+            current.push(ch);
         }
 
-        if !current_token.is_empty() {
-            tokens.push(current_token);
+        let list = Self::trim_line(&current);
+        if !list.is_empty() {
+            lists.push((list.to_string(), false));
         }
+        Ok(lists)
+    }
 
-        tokens
+    /// Trim the whitespace around a command line, which is what's run and
+    /// what history records. An escaped trailing space (`echo a\\ `) is
+    /// part of the last word, so it stays; spacing inside the line is kept
+    /// as typed.
+    pub fn trim_line(line: &str) -> &str {
+        let line = line.trim_start();
+        let trimmed = line.trim_end();
+        let backslashes = trimmed.len() - trimmed.trim_end_matches('\\').len();
+        if backslashes.is_multiple_of(2) {
+            return trimmed;
+        }
+        match line[trimmed.len()..].chars().next() {
+            Some(ch) => &line[..trimmed.len() + ch.len_utf8()],
+            None => trimmed,
+        }
     }
 
-    /// Check if a command is a built-in command
-    pub fn is_builtin(command: &str) -> bool {
-        matches!(
-            command,
-            "cd" | "pwd" | "exit" | "help" | "alias" | "history"
-        )
+    /// Interpret backslash escapes for `echo -e`. Returns the expanded text
+    /// and whether `\c` asked for all further output to be suppressed.
+    pub fn interpret_escapes(text: &str) -> (String, bool) {
+        let mut result = String::new();
+        let mut chars = text.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '\\' {
+                result.push(ch);
+                continue;
+            }
+            match chars.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some('r') => result.push('\r'),
+                Some('a') => result.push('\x07'),
+                Some('b') => result.push('\x08'),
+                Some('e') => result.push('\x1b'),
+                Some('\\') => result.push('\\'),
+                Some('c') => return (result, true),
+                Some(other) => {
+                    result.push('\\');
+                    result.push(other);
+                }
+                None => result.push('\\'),
+            }
+        }
+        (result, false)
+    }
+
+    /// Byte offset of the grapheme boundary before `pos` in `text`
+    pub fn prev_grapheme(text: &str, pos: usize) -> usize {
+        text[..pos]
+            .grapheme_indices(true)
+            .next_back()
+            .map(|(i, _)| i)
+            .unwrap_or(0)
+    }
+
+    /// Byte offset of the grapheme boundary after `pos` in `text`
+    pub fn next_grapheme(text: &str, pos: usize) -> usize {
+        text[pos..]
+            .graphemes(true)
+            .next()
+            .map(|grapheme| pos + grapheme.len())
+            .unwrap_or(pos)
+    }
+
+    /// Byte offset of the start of the word before `pos`, skipping any
+    /// whitespace in between. Words are runs of non-whitespace.
+    pub fn prev_word_boundary(text: &str, pos: usize) -> usize {
+        let before = text[..pos].trim_end();
+        match before.char_indices().rfind(|(_, c)| c.is_whitespace()) {
+            Some((i, c)) => i + c.len_utf8(),
+            None => 0,
+        }
+    }
+
+    /// Byte offset of the end of the word after `pos`, skipping any
+    /// whitespace in between. Words are runs of non-whitespace.
+    pub fn next_word_boundary(text: &str, pos: usize) -> usize {
+        let after = &text[pos..];
+        let word = after.trim_start();
+        let start = pos + after.len() - word.len();
+        start + word.find(char::is_whitespace).unwrap_or(word.len())
+    }
+
+    /// Byte offset of the start of the next word after `pos`, as used by
+    /// vi's `w`: skip the rest of the current word, then any whitespace
+    pub fn next_word_start(text: &str, pos: usize) -> usize {
+        let after = &text[pos..];
+        let word_end = after.find(char::is_whitespace).unwrap_or(after.len());
+        let rest = &after[word_end..];
+        pos + word_end + rest.len() - rest.trim_start().len()
+    }
+
+    /// Number of terminal columns `text` occupies, counting wide (e.g. CJK)
+    /// characters as two and combining marks and ANSI escape sequences as
+    /// zero
+    pub fn display_width(text: &str) -> usize {
+        Self::strip_ansi(text).width()
+    }
+
+    /// `text` without ANSI escape sequences, like the colors a user may put
+    /// in their prompt, which the terminal acts on rather than shows
+    pub fn strip_ansi(text: &str) -> Cow<'_, str> {
+        if !text.contains('\x1b') {
+            return Cow::Borrowed(text);
+        }
+        let mut visible = String::with_capacity(text.len());
+        let mut chars = text.chars();
+        while let Some(ch) = chars.next() {
+            if ch != '\x1b' {
+                visible.push(ch);
+                continue;
+            }
+            match chars.next() {
+                // CSI, like `\e[1;32m`: parameters up to a final byte in `@..~`
+                Some('[') => {
+                    for ch in chars.by_ref() {
+                        if ('@'..='~').contains(&ch) {
+                            break;
+                        }
+                    }
+                }
+                // OSC, like a window title: up to BEL or ST (`\e\\`)
+                Some(']') => {
+                    while let Some(ch) = chars.next() {
+                        match ch {
+                            '\x07' => break,
+                            '\x1b' => {
+                                chars.next();
+                                break;
+                            }
+                            _ => {}
+                        }
+                    }
+                }
+                // Any other escape is ESC and one more character
+                _ => {}
+            }
+        }
+        Cow::Owned(visible)
+    }
+
+    /// Convert a child's exit status into a shell exit code, using the
+    /// 128+signal convention for processes killed by a signal
+    pub fn exit_code(status: ExitStatus) -> i32 {
+        #[cfg(unix)]
+        if let Some(signal) = status.signal() {
+            return 128 + signal;
+        }
+        status.code().unwrap_or(1)
+    }
+
+    /// Check if a string is a valid variable name
+    pub fn is_valid_identifier(name: &str) -> bool {
+        let mut chars = name.chars();
+        matches!(chars.next(), Some(c) if c.is_ascii_alphabetic() || c == '_')
+            && chars.all(|c| c.is_ascii_alphanumeric() || c == '_')
+    }
+
+    /// Check if a string can be an alias name: anything the tokenizer reads
+    /// back as the same single word, without `=` or `/`
+    pub fn is_valid_alias_name(name: &str) -> bool {
+        !name.is_empty()
+            && !name
+                .chars()
+                .any(|c| c.is_whitespace() || "=/\\'\"$`|&;<>()".contains(c))
     }
 
     /// Get the current working directory as a string
@@ -75,26 +1460,129 @@ impl Utils {
         Ok(current_dir.display().to_string())
     }
 
-    /// Change directory with proper error handling
-    pub fn change_directory(path: &str) -> Result<()> {
+    /// Change directory, expanding `~`. Like bash, a relative name that
+    /// isn't found under the current directory is looked for in each
+    /// directory of `$CDPATH`; the match is returned so `cd` can print it.
+    pub fn change_directory(path: &str) -> Result<Option<PathBuf>> {
         let expanded_path = Self::expand_path(path);
-        let target_path = if expanded_path.is_empty() {
-            std::env::var("HOME").unwrap_or_else(|_| "/".to_string())
-        } else {
-            expanded_path
+        if expanded_path.is_empty() {
+            let home = std::env::var("HOME").unwrap_or_else(|_| "/".to_string());
+            Self::enter_directory(Path::new(&home))?;
+            return Ok(None);
+        }
+
+        let target = Path::new(&expanded_path);
+        if !target.exists()
+            && let Some(found) = Self::find_in_cdpath(target)
+        {
+            Self::enter_directory(&found)?;
+            return Ok(Some(found));
+        }
+        Self::enter_directory(target)?;
+        Ok(None)
+    }
+
+    /// The first `$CDPATH` directory containing `target` as a directory.
+    /// Paths that are absolute or start with `.` or `..` aren't searched.
+    fn find_in_cdpath(target: &Path) -> Option<PathBuf> {
+        if !matches!(target.components().next(), Some(Component::Normal(_))) {
+            return None;
+        }
+        let cdpath = std::env::var_os("CDPATH")?;
+        std::env::split_paths(&cdpath)
+            // An empty entry means the current directory, already tried
+            .filter(|dir| !dir.as_os_str().is_empty())
+            .map(|dir| dir.join(target))
+            .find(|candidate| candidate.is_dir())
+    }
+
+    /// `set_current_dir` with errors that say why the directory can't be
+    /// entered
+    fn enter_directory(path: &Path) -> Result<()> {
+        std::env::set_current_dir(path).map_err(|e| {
+            let reason = match e.kind() {
+                ErrorKind::NotFound => "no such directory".to_string(),
+                ErrorKind::PermissionDenied => "permission denied".to_string(),
+                ErrorKind::NotADirectory => "not a directory".to_string(),
+                _ => e.to_string(),
+            };
+            anyhow!("{}: {}", path.display(), reason)
+        })
+    }
+
+    /// Format the prompt, replacing `{cwd}`, `{git}`, `{user}`, `{host}`,
+    /// `{time}`, `{exit}` and `{mode}` placeholders. Unknown placeholders
+    /// are kept as written.
+    pub fn format_prompt(config_prompt: &str, state: PromptState) -> String {
+        let mut prompt = String::new();
+        let mut rest = config_prompt;
+
+        while let Some(start) = rest.find('{') {
+            prompt.push_str(&rest[..start]);
+            rest = &rest[start..];
+            let Some(end) = rest.find('}') else {
+                break;
+            };
+            match Self::prompt_placeholder(&rest[1..end], state) {
+                Some(value) => prompt.push_str(&value),
+                None => prompt.push_str(&rest[..=end]),
+            }
+            rest = &rest[end + 1..];
+        }
+        prompt.push_str(rest);
+        prompt
+    }
+
+    /// Expand a single prompt placeholder name, or `None` if it's unknown
+    fn prompt_placeholder(name: &str, state: PromptState) -> Option<String> {
+        let value = match name {
+            "cwd" => Self::display_cwd(),
+            "git" => Self::git_prompt_segment(),
+            "user" => std::env::var("USER").unwrap_or_default(),
+            "host" => Self::hostname(),
+            "time" => Self::local_time(),
+            "exit" => state.last_exit_code.to_string(),
+            "duration" => state
+                .duration
+                .map(Self::format_duration)
+                .unwrap_or_default(),
+            "mode" => Self::vi_mode_indicator(state.vi_mode).to_string(),
+            _ => return None,
         };
+        Some(value)
+    }
+
+    /// Format a command's running time for the prompt, to a precision that
+    /// suits its size: `250ms`, `1.3s`, `2m5s` or `1h2m`
+    pub fn format_duration(duration: Duration) -> String {
+        let secs = duration.as_secs();
+        if secs >= 3600 {
+            format!("{}h{}m", secs / 3600, secs % 3600 / 60)
+        } else if secs >= 60 {
+            format!("{}m{}s", secs / 60, secs % 60)
+        } else if duration >= Duration::from_secs(1) {
+            format!("{:.1}s", duration.as_secs_f64())
+        } else {
+            format!("{}ms", duration.as_millis())
+        }
+    }
 
-        std::env::set_current_dir(Path::new(&target_path))?;
-        Ok(())
+    /// The `{mode}` prompt segment: `[I]` or `[N]` in vi mode, else empty
+    pub fn vi_mode_indicator(mode: Option<ViMode>) -> &'static str {
+        match mode {
+            Some(ViMode::Insert) => "[I]",
+            Some(ViMode::Normal) => "[N]",
+            None => "",
+        }
     }
 
-    /// Format the prompt with current directory and other info
-    pub fn format_prompt(config_prompt: &str) -> String {
+    /// The current directory with the home directory shown as `~`
+    fn display_cwd() -> String {
         let current_dir = Self::get_current_dir().unwrap_or_else(|_| "unknown".to_string());
         let home = std::env::var("HOME").unwrap_or_default();
 
         // Replace home directory with ~ and keep the full path from home
-        let display_dir = if current_dir.starts_with(&home) {
+        if current_dir.starts_with(&home) {
             let relative_path = current_dir.strip_prefix(&home).unwrap_or("");
             if relative_path.is_empty() {
                 "~".to_string()
@@ -103,19 +1591,919 @@ impl Utils {
             }
         } else {
             current_dir
+        }
+    }
+
+    /// The machine's hostname up to the first `.`, like bash's `\h`
+    #[cfg(unix)]
+    fn hostname() -> String {
+        let mut buf = [0u8; 256];
+        // SAFETY: the buffer is valid for `buf.len()` bytes; gethostname
+        // writes a NUL-terminated name into it (truncating if necessary)
+        let result = unsafe { libc::gethostname(buf.as_mut_ptr().cast(), buf.len()) };
+        if result != 0 {
+            return String::new();
+        }
+        let end = buf.iter().position(|&b| b == 0).unwrap_or(buf.len());
+        let name = String::from_utf8_lossy(&buf[..end]);
+        name.split('.').next().unwrap_or_default().to_string()
+    }
+
+    #[cfg(not(unix))]
+    fn hostname() -> String {
+        std::env::var("COMPUTERNAME").unwrap_or_default()
+    }
+
+    /// Seconds since the Unix epoch
+    pub fn now() -> u64 {
+        std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map(|d| d.as_secs())
+            .unwrap_or(0)
+    }
+
+    /// The current local time as `HH:MM:SS`
+    fn local_time() -> String {
+        Self::local_date_time(Self::now())
+            .map(|(.., hour, minute, second)| format!("{:02}:{:02}:{:02}", hour, minute, second))
+            .unwrap_or_default()
+    }
+
+    /// A Unix timestamp as local `YYYY-MM-DD HH:MM:SS`
+    pub fn format_timestamp(secs: u64) -> String {
+        Self::local_date_time(secs)
+            .map(|(year, month, day, hour, minute, second)| {
+                format!(
+                    "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+                    year, month, day, hour, minute, second
+                )
+            })
+            .unwrap_or_default()
+    }
+
+    /// Split a Unix timestamp into local year, month, day, hour, minute
+    /// and second
+    #[cfg(unix)]
+    fn local_date_time(secs: u64) -> Option<(i64, i64, i64, i64, i64, i64)> {
+        let time = secs as libc::time_t;
+        // SAFETY: localtime_r only writes to the `tm` we hand it, and a
+        // zeroed `tm` is a valid value to start from
+        let tm = unsafe {
+            let mut tm: libc::tm = std::mem::zeroed();
+            if libc::localtime_r(&time, &mut tm).is_null() {
+                return None;
+            }
+            tm
+        };
+        Some((
+            tm.tm_year as i64 + 1900,
+            tm.tm_mon as i64 + 1,
+            tm.tm_mday as i64,
+            tm.tm_hour as i64,
+            tm.tm_min as i64,
+            tm.tm_sec as i64,
+        ))
+    }
+
+    #[cfg(not(unix))]
+    fn local_date_time(secs: u64) -> Option<(i64, i64, i64, i64, i64, i64)> {
+        // Without a portable timezone lookup, fall back to UTC. The date is
+        // worked out with Howard Hinnant's days-to-civil algorithm.
+        let secs = secs as i64;
+        let days = secs.div_euclid(86400) + 719468;
+        let era = days.div_euclid(146097);
+        let day_of_era = days - era * 146097;
+        let year_of_era =
+            (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+        let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+        let mp = (5 * day_of_year + 2) / 153;
+        let day = day_of_year - (153 * mp + 2) / 5 + 1;
+        let month = if mp < 10 { mp + 3 } else { mp - 9 };
+        let year = year_of_era + era * 400 + i64::from(month <= 2);
+        Some((
+            year,
+            month,
+            day,
+            secs / 3600 % 24,
+            secs / 60 % 60,
+            secs % 60,
+        ))
+    }
+
+    /// The `{git}` prompt segment, e.g. `(main*)`, or empty outside a repo.
+    /// Prompt redraws happen on every keystroke, so the result is cached
+    /// for the current directory until `invalidate_git_prompt` is called.
+    fn git_prompt_segment() -> String {
+        let Ok(current_dir) = std::env::current_dir() else {
+            return String::new();
         };
+        GIT_PROMPT_CACHE.with(|cache| {
+            let mut cache = cache.borrow_mut();
+            if let Some((dir, segment)) = cache.as_ref()
+                && *dir == current_dir
+            {
+                return segment.clone();
+            }
+            let segment = match Self::git_branch(&current_dir) {
+                Some(branch) if Self::git_is_dirty() => format!("({}*)", branch),
+                Some(branch) => format!("({})", branch),
+                None => String::new(),
+            };
+            *cache = Some((current_dir, segment.clone()));
+            segment
+        })
+    }
+
+    /// Forget the cached `{git}` segment, e.g. after a command that may have
+    /// switched branches or touched the working tree
+    pub fn invalidate_git_prompt() {
+        GIT_PROMPT_CACHE.with(|cache| cache.borrow_mut().take());
+    }
+
+    /// Find the branch checked out in the repository containing `dir` by
+    /// reading `HEAD`, falling back to a short hash when it's detached
+    fn git_branch(dir: &Path) -> Option<String> {
+        let dot_git = dir
+            .ancestors()
+            .map(|d| d.join(".git"))
+            .find(|p| p.exists())?;
+        // In worktrees and submodules `.git` is a file pointing at the real one
+        let git_dir = if dot_git.is_file() {
+            let content = std::fs::read_to_string(&dot_git).ok()?;
+            let target = content.trim().strip_prefix("gitdir:")?.trim();
+            dot_git.parent()?.join(target)
+        } else {
+            dot_git
+        };
+
+        let head = std::fs::read_to_string(git_dir.join("HEAD")).ok()?;
+        let head = head.trim();
+        match head.strip_prefix("ref: ") {
+            Some(reference) => Some(
+                reference
+                    .strip_prefix("refs/heads/")
+                    .unwrap_or(reference)
+                    .to_string(),
+            ),
+            None => Some(head.chars().take(7).collect()),
+        }
+    }
+
+    /// Check whether tracked files in the current repository have
+    /// uncommitted changes
+    fn git_is_dirty() -> bool {
+        Command::new("git")
+            .args(["status", "--porcelain", "--untracked-files=no"])
+            .stdin(Stdio::null())
+            .stderr(Stdio::null())
+            .output()
+            .map(|output| output.status.success() && !output.stdout.is_empty())
+            .unwrap_or(false)
+    }
+
+    /// Resolve a command name to an executable, searching `$PATH` unless
+    /// the name already contains a path separator
+    pub fn find_in_path(command: &str) -> Option<PathBuf> {
+        Self::find_in_path_from(
+            command,
+            std::env::var("PATH").ok().as_deref(),
+            std::env::var("HOME").ok().as_deref(),
+        )
+    }
+
+    /// Like `find_in_path`, with `path_var` and `home` standing in for
+    /// `$PATH` and `$HOME`
+    pub fn find_in_path_from(
+        command: &str,
+        path_var: Option<&str>,
+        home: Option<&str>,
+    ) -> Option<PathBuf> {
+        let is_command = |path: &Path| path.is_file() && Self::is_executable(path);
+
+        if command.contains('/') {
+            let path = PathBuf::from(Self::expand_path_from(command, home));
+            return is_command(&path).then_some(path);
+        }
+
+        path_var?
+            .split(':')
+            .map(|dir| Path::new(dir).join(command))
+            .find(|path| is_command(path))
+    }
+
+    /// Keep the shell alive on SIGINT while its children still die from it.
+    ///
+    /// While the line editor reads input the terminal is in raw mode, which
+    /// turns off ISIG, so Ctrl+C arrives as a key event rather than a signal.
+    /// Raw mode is switched off while a foreground command runs, and Ctrl+C
+    /// then sends SIGINT to the terminal's whole foreground process group,
+    /// shell included. A no-op handler is used rather than SIG_IGN because
+    /// caught signals are reset to the default on exec, whereas an ignored
+    /// SIGINT would be inherited and the child couldn't be interrupted.
+    #[cfg(unix)]
+    pub fn catch_sigint() {
+        extern "C" fn on_sigint(_: libc::c_int) {}
+        let handler: extern "C" fn(libc::c_int) = on_sigint;
+        // SAFETY: the handler does nothing, so it's async-signal-safe
+        unsafe {
+            libc::signal(libc::SIGINT, handler as libc::sighandler_t);
+        }
+    }
+
+    #[cfg(not(unix))]
+    pub fn catch_sigint() {}
+
+    /// Put the terminal back the way it was found if the shell is sent
+    /// SIGTERM, then die of the signal as usual. Call before raw mode is
+    /// enabled, since the settings in effect now are the ones restored.
+    ///
+    /// crossterm can't be called from a signal handler, so the handler
+    /// restores a saved copy of the settings with `tcsetattr` and turns
+    /// bracketed paste off with a plain `write`, both async-signal-safe,
+    /// then re-raises with the default action.
+    #[cfg(unix)]
+    pub fn restore_terminal_on_sigterm() {
+        static SAVED: OnceLock<libc::termios> = OnceLock::new();
+
+        extern "C" fn on_sigterm(signal: libc::c_int) {
+            // SAFETY: only async-signal-safe calls, on a termios that was
+            // fully written before the handler was installed
+            unsafe {
+                if let Some(termios) = SAVED.get() {
+                    libc::tcsetattr(libc::STDIN_FILENO, libc::TCSANOW, termios);
+                }
+                let disable_paste = b"\x1b[?2004l";
+                libc::write(
+                    libc::STDOUT_FILENO,
+                    disable_paste.as_ptr().cast(),
+                    disable_paste.len(),
+                );
+                libc::signal(signal, libc::SIG_DFL);
+                libc::raise(signal);
+            }
+        }
 
-        config_prompt.replace("{cwd}", &display_dir)
+        // SAFETY: tcgetattr fills the zeroed struct, or fails when stdin
+        // isn't a terminal, in which case there's nothing to restore
+        let mut termios: libc::termios = unsafe { std::mem::zeroed() };
+        if unsafe { libc::tcgetattr(libc::STDIN_FILENO, &mut termios) } != 0 {
+            return;
+        }
+        if SAVED.set(termios).is_err() {
+            return;
+        }
+        let handler: extern "C" fn(libc::c_int) = on_sigterm;
+        // SAFETY: see on_sigterm
+        unsafe {
+            libc::signal(libc::SIGTERM, handler as libc::sighandler_t);
+        }
     }
 
+    #[cfg(not(unix))]
+    pub fn restore_terminal_on_sigterm() {}
+
     /// Check if a file is executable
     #[cfg(unix)]
     pub fn is_executable(path: &Path) -> bool {
-        if let Ok(metadata) = std::fs::metadata(path) {
-            let permissions = metadata.permissions();
-            permissions.mode() & 0o111 != 0
-        } else {
-            false
+        std::fs::metadata(path).is_ok_and(|metadata| Self::has_execute_bit(&metadata))
+    }
+
+    /// Check metadata already in hand for an execute permission bit
+    #[cfg(unix)]
+    pub fn has_execute_bit(metadata: &std::fs::Metadata) -> bool {
+        metadata.permissions().mode() & 0o111 != 0
+    }
+}
+
+/// Held by tests that change the environment or the working directory,
+/// which every test thread shares. The other tests only read the
+/// environment through std, which takes a lock of its own around each
+/// call, so a test holding this one can `set_var` and `remove_var` soundly.
+#[cfg(test)]
+pub fn lock_env() -> std::sync::MutexGuard<'static, ()> {
+    static ENV: std::sync::Mutex<()> = std::sync::Mutex::new(());
+    ENV.lock().unwrap_or_else(|e| e.into_inner())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Connector, Continuation, Expander, PromptState, Token, Utils};
+    use std::time::Duration;
+
+    #[test]
+    fn prompt_expands_exit_and_keeps_unknown_placeholders() {
+        let state = |last_exit_code| PromptState {
+            last_exit_code,
+            vi_mode: None,
+            continuation: false,
+            duration: None,
+        };
+        assert_eq!(Utils::format_prompt("[{exit}] $ ", state(127)), "[127] $ ");
+        assert_eq!(
+            Utils::format_prompt("{nope} {exit", state(0)),
+            "{nope} {exit"
+        );
+
+        // A duration too short to show leaves its placeholder empty
+        assert_eq!(Utils::format_prompt("{duration}$ ", state(0)), "$ ");
+        let slow = PromptState {
+            duration: Some(Duration::from_millis(1340)),
+            ..state(0)
+        };
+        assert_eq!(Utils::format_prompt("{duration}$ ", slow), "1.3s$ ");
+    }
+
+    #[test]
+    fn ansi_escapes_take_no_columns() {
+        assert_eq!(Utils::strip_ansi("\x1b[1;32mok\x1b[0m $ "), "ok $ ");
+        assert_eq!(Utils::strip_ansi("\x1b]0;title\x07a\x1b]8;;x\x1b\\b"), "ab");
+        assert_eq!(Utils::strip_ansi("\x1b7日本\x1b8"), "日本");
+        assert_eq!(Utils::display_width("\x1b[38;5;208m日本\x1b[m>"), 5);
+        // An escape cut short hides only what it started
+        assert_eq!(Utils::strip_ansi("a\x1b[1"), "a");
+    }
+
+    #[test]
+    fn durations_are_formatted_to_suit_their_size() {
+        for (millis, shown) in [
+            (0, "0ms"),
+            (250, "250ms"),
+            (1000, "1.0s"),
+            (59_940, "59.9s"),
+            (125_000, "2m5s"),
+            (3_725_000, "1h2m"),
+        ] {
+            assert_eq!(Utils::format_duration(Duration::from_millis(millis)), shown);
+        }
+    }
+
+    #[test]
+    fn word_boundaries_skip_whitespace_runs() {
+        let text = "git  commit -m";
+        assert_eq!(Utils::prev_word_boundary(text, text.len()), 12);
+        assert_eq!(Utils::prev_word_boundary(text, 12), 5);
+        assert_eq!(Utils::prev_word_boundary(text, 5), 0);
+        assert_eq!(Utils::prev_word_boundary(text, 0), 0);
+
+        assert_eq!(Utils::next_word_boundary(text, 0), 3);
+        assert_eq!(Utils::next_word_boundary(text, 3), 11);
+        assert_eq!(Utils::next_word_boundary(text, 12), text.len());
+        assert_eq!(Utils::next_word_boundary(text, text.len()), text.len());
+
+        assert_eq!(Utils::next_word_start(text, 0), 5);
+        assert_eq!(Utils::next_word_start(text, 5), 12);
+        assert_eq!(Utils::next_word_start(text, 12), text.len());
+    }
+
+    #[test]
+    fn continuation_detects_incomplete_input() {
+        assert_eq!(Utils::continuation("echo hi"), None);
+        assert_eq!(
+            Utils::continuation("echo \"hello"),
+            Some(Continuation::Unclosed)
+        );
+        assert_eq!(Utils::continuation("echo 'it\\'"), None);
+        assert_eq!(
+            Utils::continuation("echo a \\"),
+            Some(Continuation::Escaped)
+        );
+        assert_eq!(Utils::continuation("echo a \\\\"), None);
+        assert_eq!(
+            Utils::continuation("echo $(date"),
+            Some(Continuation::Unclosed)
+        );
+        assert_eq!(Utils::continuation("echo ${HOME}"), None);
+        assert_eq!(Utils::continuation("f() {"), Some(Continuation::Unclosed));
+        assert_eq!(Utils::continuation("echo '{'"), None);
+        assert_eq!(
+            Utils::continuation("echo \"x\n"),
+            Some(Continuation::Unclosed)
+        );
+    }
+
+    #[test]
+    fn command_words_find_each_segment_command() {
+        fn words(input: &str) -> Vec<&str> {
+            Utils::command_words(input)
+                .into_iter()
+                .map(|range| &input[range])
+                .collect()
+        }
+        assert_eq!(words("ls -la | grep foo"), ["ls", "grep"]);
+        assert_eq!(
+            words("make && ./run || echo 'a | b'"),
+            ["make", "./run", "echo"]
+        );
+        assert_eq!(words("  FOO=1 env; sleep 1 &"), ["env", "sleep"]);
+        assert_eq!(words("'my cmd' arg # ls"), ["'my cmd'"]);
+        assert_eq!(words("ls |"), ["ls"]);
+    }
+
+    #[test]
+    fn heredoc_body_ends_at_exact_delimiter_line() {
+        let heredocs = Utils::heredocs("cat <<EOF | wc -l\none\n EOF\nEOFX\nEOF");
+        assert_eq!(heredocs.command, "cat <<EOF | wc -l");
+        assert_eq!(heredocs.docs.len(), 1);
+        let doc = &heredocs.docs[0];
+        assert_eq!(&heredocs.command[doc.range.clone()], "<<EOF");
+        assert_eq!(doc.body.as_deref(), Some("one\n EOF\nEOFX\n"));
+
+        assert!(Utils::heredocs("cat <<EOF\nstill typing").pending());
+        assert!(!Utils::heredocs("cat <<EOF\nEOF").pending());
+        assert!(
+            Utils::heredocs("echo '<<EOF' \"<<X\" # <<Y")
+                .docs
+                .is_empty()
+        );
+
+        // `<<-` strips leading tabs from body lines and the delimiter line
+        let heredocs = Utils::heredocs("cat <<-END\n\t\tindented\n\tEND");
+        assert_eq!(heredocs.docs[0].body.as_deref(), Some("indented\n"));
+    }
+
+    #[test]
+    fn quoted_heredoc_delimiter_disables_expansion() {
+        for (line, expand) in [
+            ("cat <<EOF", true),
+            ("cat <<'EOF'", false),
+            ("cat << \"EOF\"", false),
+            ("cat <<\\EOF", false),
+        ] {
+            let heredocs = Utils::heredocs(&format!("{}\n$HOME\nEOF", line));
+            let doc = &heredocs.docs[0];
+            assert_eq!(doc.delimiter, "EOF", "{}", line);
+            assert_eq!(doc.expand, expand, "{}", line);
+        }
+
+        let mut lookup = |name: &str| (name == "USER").then(|| "ada".to_string());
+        assert_eq!(
+            Utils::expand_variables("hi $USER, ${USER} \\$USER $\n", &mut lookup),
+            "hi ada, ada $USER $\n"
+        );
+    }
+
+    #[test]
+    fn tokenizer_marks_operators_and_quoted_words() {
+        fn word(text: &str, quoted: bool) -> Token {
+            Token::Word(text.to_string(), quoted)
+        }
+
+        assert_eq!(
+            Utils::tokenize_command("sort<in|uniq -c>>out 2>err"),
+            [
+                word("sort", false),
+                Token::Op("<"),
+                word("in", false),
+                Token::Op("|"),
+                word("uniq", false),
+                word("-c", false),
+                Token::Op(">>"),
+                word("out", false),
+                Token::Op("2>"),
+                word("err", false),
+            ]
+        );
+        // Quoted or escaped operators are words
+        assert_eq!(
+            Utils::tokenize_command(r#"echo ">" '|' \< a"|"b"#),
+            [
+                word("echo", false),
+                word(">", true),
+                word("|", true),
+                word("<", true),
+                word("a|b", true),
+            ]
+        );
+        // Only a bare, unquoted 2 before `>` means stderr
+        assert_eq!(
+            Utils::tokenize_command("echo 12>f \"2\">g 2>>h"),
+            [
+                word("echo", false),
+                word("12", false),
+                Token::Op(">"),
+                word("f", false),
+                word("2", true),
+                Token::Op(">"),
+                word("g", false),
+                Token::Op("2>>"),
+                word("h", false),
+            ]
+        );
+        assert_eq!(
+            Utils::tokenize_command("cat <<'EOF' '' x"),
+            [
+                word("cat", false),
+                Token::Op("<<"),
+                word("EOF", true),
+                word("", true),
+                word("x", false),
+            ]
+        );
+        assert!(Utils::tokenize_command("  \t").is_empty());
+        assert_eq!(
+            Utils::tokenize_command("cmd 1>out 2>&1 >&2 x1>y"),
+            [
+                word("cmd", false),
+                Token::Op(">"),
+                word("out", false),
+                Token::Op("2>&"),
+                word("1", false),
+                Token::Op(">&"),
+                word("2", false),
+                word("x1", false),
+                Token::Op(">"),
+                word("y", false),
+            ]
+        );
+
+        // The string form still spells operators out
+        assert_eq!(Utils::parse_command("a '>' > b"), ["a", ">", ">", "b"]);
+        assert_eq!(
+            Utils::tokenize_command_with("echo $X\">\"", &mut |name: &str| {
+                (name == "X").then(|| "1".into())
+            }),
+            [word("echo", false), word("1>", true)]
+        );
+
+        // Only a digit typed as is picks a stream; one from an expansion
+        // is an argument, as in bash
+        let mut lookup = |name: &str| match name {
+            "one" => Some("1".to_string()),
+            "two" => Some("2".to_string()),
+            _ => None,
+        };
+        for (input, expanded) in [("$two>out", "2"), ("$one>out", "1"), ("${two}>out", "2")] {
+            assert_eq!(
+                Utils::tokenize_command_with(input, &mut lookup),
+                [word(expanded, false), Token::Op(">"), word("out", false)]
+            );
+        }
+    }
+
+    #[test]
+    fn variable_names_end_where_they_should() {
+        let mut lookup = |name: &str| match name {
+            "A" => Some("1".to_string()),
+            "B" => Some("2".to_string()),
+            "VAR" => Some("v".to_string()),
+            _ => None,
+        };
+        let mut words = |input: &str| -> Vec<String> {
+            Utils::tokenize_command_with(input, &mut lookup)
+                .iter()
+                .map(|token| token.text().to_string())
+                .collect()
+        };
+
+        assert_eq!(words("echo $A$B"), ["echo", "12"]);
+        assert_eq!(words("echo ${VAR}abc $VARabc"), ["echo", "vabc"]);
+        assert_eq!(words("echo \"${A}${B}\"x"), ["echo", "12x"]);
+        // A `$` with no name after it is literal
+        assert_eq!(words("echo $"), ["echo", "$"]);
+        assert_eq!(words("echo a$ $A$"), ["echo", "a$", "1$"]);
+        assert_eq!(words("echo '$A$B'"), ["echo", "$A$B"]);
+
+        // Here-document bodies follow the same rules
+        let mut lookup = |name: &str| (name == "A").then(|| "1".to_string());
+        assert_eq!(
+            Utils::expand_variables("$A$A ${A}b $Ab $", &mut lookup),
+            "11 1b  $"
+        );
+    }
+
+    #[test]
+    fn unquoted_expansions_are_split_into_fields() {
+        let words = |input: &str, ifs: Option<&str>| -> Vec<String> {
+            let mut lookup = |name: &str| match name {
+                "FLAGS" => Some("-a -l".to_string()),
+                "SPACED" => Some("  x \t y  ".to_string()),
+                "PATHS" => Some("/bin::/usr/bin: ".to_string()),
+                "EMPTY" => Some(String::new()),
+                "IFS" => ifs.map(str::to_string),
+                _ => None,
+            };
+            Utils::tokenize_command_with(input, &mut lookup)
+                .iter()
+                .map(|token| token.text().to_string())
+                .collect()
+        };
+
+        assert_eq!(words("ls $FLAGS", None), ["ls", "-a", "-l"]);
+        assert_eq!(words("ls \"$FLAGS\"", None), ["ls", "-a -l"]);
+        assert_eq!(words("echo $SPACED", None), ["echo", "x", "y"]);
+        assert_eq!(
+            words("echo a$SPACED.b", None),
+            ["echo", "a", "x", "y", ".b"]
+        );
+
+        // An unquoted empty expansion disappears; a quoted one is an argument
+        assert_eq!(words("echo $EMPTY $UNSET x", None), ["echo", "x"]);
+        assert_eq!(words("echo \"$EMPTY\" ''$UNSET", None), ["echo", "", ""]);
+
+        // Other IFS characters end a field each, with whitespace around
+        // them folded in. Only characters in IFS separate, so an empty IFS
+        // turns splitting off.
+        assert_eq!(
+            words("echo $PATHS", Some(": ")),
+            ["echo", "/bin", "", "/usr/bin"]
+        );
+        assert_eq!(words("echo $SPACED", Some(" ")), ["echo", "x", "\t", "y"]);
+        assert_eq!(words("echo $FLAGS", Some("")), ["echo", "-a -l"]);
+    }
+
+    #[test]
+    fn expansions_follow_the_quoting_they_appear_in() {
+        /// Expands `$V` to `v w` and `$(cmd)` to `<cmd>`, noting what ran
+        struct Recorder(Vec<String>);
+        impl Expander for Recorder {
+            fn variable(&self, name: &str) -> Option<String> {
+                (name == "V").then(|| "v w".to_string())
+            }
+            fn command_output(&mut self, command: &str) -> Option<String> {
+                self.0.push(command.to_string());
+                Some(format!("<{}>", command))
+            }
+        }
+
+        let home = std::env::var("HOME").unwrap();
+        let dir = std::env::temp_dir().join(format!("wsh-quoting-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        std::fs::write(dir.join("a.txt"), "").unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        let glob = format!("{}/*.txt", dir);
+
+        // Each expansion as written and backslash-escaped, then the words
+        // it becomes unquoted and inside double quotes. Single quotes and
+        // the backslash always keep it as written.
+        let cases = [
+            (
+                "~",
+                "\\~".to_string(),
+                vec![home.clone()],
+                vec!["~".to_string()],
+            ),
+            (
+                "$V",
+                "\\$V".into(),
+                vec!["v".into(), "w".into()],
+                vec!["v w".into()],
+            ),
+            (
+                "${V}",
+                "\\${V}".into(),
+                vec!["v".into(), "w".into()],
+                vec!["v w".into()],
+            ),
+            (
+                "$(c d)",
+                "\\$(c\\ d)".into(),
+                vec!["<c".into(), "d>".into()],
+                vec!["<c d>".into()],
+            ),
+            (
+                glob.as_str(),
+                format!("{}/\\*.txt", dir),
+                vec![format!("{}/a.txt", dir)],
+                vec![glob.clone()],
+            ),
+        ];
+
+        let mut recorder = Recorder(Vec::new());
+        let mut words = |input: &str| -> Vec<String> {
+            Utils::tokenize_command_with(input, &mut recorder)
+                .iter()
+                .map(|token| token.text().to_string())
+                .collect()
+        };
+        for (text, escaped, unquoted, double_quoted) in cases {
+            assert_eq!(words(text), unquoted, "{}", text);
+            assert_eq!(words(&format!("\"{}\"", text)), double_quoted, "{}", text);
+            assert_eq!(words(&format!("'{}'", text)), [text], "{}", text);
+            assert_eq!(words(&escaped), [text], "{}", text);
+        }
+        // Substitutions only ran where they were expanded
+        assert_eq!(recorder.0, ["c d", "c d"]);
+
+        // Quotes and parentheses inside a substitution belong to it
+        let mut recorder = Recorder(Vec::new());
+        let tokens = Utils::tokenize_command_with("echo \"$(a ')' \"b\")\"x", &mut recorder);
+        assert_eq!(
+            tokens,
+            [
+                Token::Word("echo".into(), false),
+                Token::Word("<a ')' \"b\">x".into(), true)
+            ]
+        );
+        // Without an expander it stays one word, as written
+        assert_eq!(
+            Utils::parse_command("echo $(a b | c)"),
+            ["echo", "$(a b | c)"]
+        );
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+
+    #[test]
+    fn leading_assignments_split_off_unexpanded() {
+        let (assignments, rest) = Utils::split_assignments(" A=1 B='x y'$(c d) C= cmd D=2");
+        assert_eq!(
+            assignments,
+            [
+                ("A".to_string(), "1".to_string()),
+                ("B".to_string(), "'x y'$(c d)".to_string()),
+                ("C".to_string(), String::new())
+            ]
+        );
+        assert_eq!(rest, "cmd D=2");
+        for input in ["'A'=1", "1A=x", "echo A=1", "=x"] {
+            assert!(Utils::split_assignments(input).0.is_empty(), "{}", input);
+        }
+        assert_eq!(Utils::split_assignments("A=1>out").1, ">out");
+
+        // Values are expanded whole: no field splitting or globbing
+        let mut lookup = |name: &str| (name == "V").then(|| "a  *".to_string());
+        assert_eq!(Utils::expand_value("$V", &mut lookup), "a  *");
+        assert_eq!(Utils::expand_value("*", &mut lookup), "*");
+        assert_eq!(Utils::expand_value("'$V'", &mut lookup), "$V");
+        assert_eq!(Utils::expand_value("", &mut lookup), "");
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            Utils::expand_value("~/x", &mut lookup),
+            format!("{}/x", home)
+        );
+    }
+
+    #[test]
+    fn expansion_replaces_unquoted_leading_tilde() {
+        let home = std::env::var("HOME").unwrap();
+        assert_eq!(
+            Utils::tokenize_command_with("ls ~ ~/src '~/x' a~b", &mut |_: &str| None),
+            [
+                Token::Word("ls".into(), false),
+                Token::Word(home.clone(), false),
+                Token::Word(format!("{}/src", home), false),
+                Token::Word("~/x".into(), true),
+                Token::Word("a~b".into(), false)
+            ]
+        );
+        // Without expansion the tilde is kept for completion and display
+        assert_eq!(Utils::parse_command("ls ~/src"), ["ls", "~/src"]);
+
+        // Every unquoted argument is expanded, but not a `~` that comes
+        // out of a variable, as in bash
+        let mut lookup = |name: &str| (name == "T").then(|| "~/t".to_string());
+        assert_eq!(
+            Utils::tokenize_command_with("cat ~/x ~/ $T \"~\" '~' \\~", &mut lookup)
+                .iter()
+                .map(|token| token.text().to_string())
+                .collect::<Vec<_>>(),
+            [
+                "cat".to_string(),
+                format!("{}/x", home),
+                format!("{}/", home),
+                "~/t".to_string(),
+                "~".to_string(),
+                "~".to_string(),
+                "~".to_string(),
+            ]
+        );
+    }
+
+    #[test]
+    fn semicolons_split_lists_outside_quotes() {
+        let lists = |input| -> Vec<String> {
+            let lists = Utils::split_sequence(input).unwrap();
+            lists.into_iter().map(|(list, _)| list).collect()
+        };
+        assert_eq!(lists("a && b; c || d"), ["a && b", "c || d"]);
+        assert_eq!(
+            lists(r#"echo 'x;y' "z;" a\;b;"#),
+            [r#"echo 'x;y' "z;" a\;b"#]
+        );
+        assert_eq!(lists("cd /tmp;ls ;  pwd"), ["cd /tmp", "ls", "pwd"]);
+        assert!(lists("").is_empty());
+        assert!(Utils::split_sequence("a;; b").is_err());
+        assert!(Utils::split_sequence("; a").is_err());
+        assert_eq!(
+            lists("cd /tmp\n\nls;\necho 'a\nb'\n"),
+            ["cd /tmp", "ls", "echo 'a\nb'"]
+        );
+
+        // Within a list `&&`/`||` still chain as before
+        let chain = Utils::split_chain("c || d").unwrap();
+        assert_eq!(chain[1], (Some(Connector::Or), "d".to_string()));
+
+        // Operators inside a substitution are the inner command's
+        assert_eq!(
+            lists("echo $(a; b \"$(c;)\"); d"),
+            ["echo $(a; b \"$(c;)\")", "d"]
+        );
+        assert_eq!(Utils::split_chain("echo \"$(a && b)\"").unwrap().len(), 1);
+
+        // An escaped trailing space belongs to the word before it
+        assert_eq!(Utils::trim_line(" \tls    -l \n"), "ls    -l");
+        assert_eq!(Utils::trim_line("echo a\\  "), "echo a\\ ");
+        assert_eq!(Utils::trim_line("echo a\\\\  "), "echo a\\\\");
+        assert_eq!(lists("echo a\\ ; b"), ["echo a\\ ", "b"]);
+    }
+
+    #[test]
+    fn ampersands_end_lists_to_run_in_the_background() {
+        assert_eq!(
+            Utils::split_sequence("ls /x & echo after").unwrap(),
+            [
+                ("ls /x".to_string(), true),
+                ("echo after".to_string(), false)
+            ]
+        );
+        assert_eq!(
+            Utils::split_sequence("a && b & c >&2; d 2>&1 &").unwrap(),
+            [
+                ("a && b".to_string(), true),
+                ("c >&2".to_string(), false),
+                ("d 2>&1".to_string(), true),
+            ]
+        );
+        assert_eq!(
+            Utils::split_sequence("echo 'a & b' \\& \"&\"").unwrap(),
+            [("echo 'a & b' \\& \"&\"".to_string(), false)]
+        );
+        assert!(Utils::split_sequence("& a").is_err());
+        assert!(Utils::split_sequence("a & & b").is_err());
+
+        // One that isn't split off is an operator, never an argument
+        assert_eq!(
+            Utils::tokenize_command("a & b"),
+            [
+                Token::Word("a".to_string(), false),
+                Token::Op("&"),
+                Token::Word("b".to_string(), false),
+            ]
+        );
+    }
+
+    #[test]
+    fn strip_comments_at_word_start_only() {
+        assert_eq!(Utils::strip_comments("# just a comment"), "");
+        assert_eq!(Utils::strip_comments("echo hi # greeting"), "echo hi ");
+        assert_eq!(Utils::strip_comments("echo hi;# x"), "echo hi;");
+        assert_eq!(Utils::strip_comments("echo a#b"), "echo a#b");
+        assert_eq!(
+            Utils::strip_comments("echo \"a # b\" 'c # d'"),
+            "echo \"a # b\" 'c # d'"
+        );
+        assert_eq!(Utils::strip_comments("echo \\# not"), "echo \\# not");
+        assert_eq!(Utils::strip_comments("echo { # open\n}"), "echo { \n}");
+    }
+
+    #[test]
+    fn glob_patterns_match_names() {
+        assert!(Utils::glob_match("*", "anything"));
+        assert!(Utils::glob_match("*.rs", "main.rs"));
+        assert!(!Utils::glob_match("*.rs", "main.rsx"));
+        assert!(Utils::glob_match("a*b*c", "aXbYbZc"));
+        assert!(Utils::glob_match("?.txt", "a.txt"));
+        assert!(!Utils::glob_match("?.txt", "ab.txt"));
+        assert!(Utils::glob_match("[abc].txt", "b.txt"));
+        assert!(!Utils::glob_match("[abc].txt", "d.txt"));
+        assert!(Utils::glob_match("[a-c]x", "cx"));
+        assert!(Utils::glob_match("[!a]x", "bx"));
+        assert!(!Utils::glob_match("[!a]x", "ax"));
+        assert!(Utils::glob_match("[]]", "]"));
+        assert!(Utils::glob_match("\\*", "*"));
+        assert!(!Utils::glob_match("\\*", "x"));
+        assert!(Utils::glob_match("[x", "[x"));
+
+        // Backtracking over every star would take exponential time here
+        let name = "a".repeat(64);
+        assert!(!Utils::glob_match("*a*a*a*a*a*a*a*a*b", &name));
+        assert!(Utils::glob_match("*a*a*a*a*a*a*a*a*", &name));
+    }
+
+    #[test]
+    fn expand_globs_walks_the_filesystem() {
+        let dir = std::env::temp_dir().join(format!("wsh-glob-{}", std::process::id()));
+        std::fs::create_dir_all(dir.join("src/nested")).unwrap();
+        for file in ["a.txt", "b.txt", "ab.txt", "c.log", ".hidden.txt"] {
+            std::fs::write(dir.join(file), "").unwrap();
         }
+        std::fs::write(dir.join("src/x.rs"), "").unwrap();
+        std::fs::write(dir.join("src/nested/x.rs"), "").unwrap();
+        let dir = dir.to_string_lossy().into_owned();
+        let glob = |pattern: &str| -> Vec<String> {
+            Utils::expand_globs(&format!("{}/{}", dir, pattern))
+                .iter()
+                .map(|path| path[dir.len() + 1..].to_string())
+                .collect()
+        };
+
+        assert_eq!(glob("*.txt"), ["a.txt", "ab.txt", "b.txt"]);
+        assert_eq!(glob("*.log"), ["c.log"]);
+        assert_eq!(glob("?.txt"), ["a.txt", "b.txt"]);
+        assert_eq!(glob("[ab].txt"), ["a.txt", "b.txt"]);
+        assert_eq!(glob(".*.txt"), [".hidden.txt"]);
+        assert_eq!(glob("**/x.rs"), ["src/nested/x.rs", "src/x.rs"]);
+        assert!(glob("*.md").is_empty());
+
+        std::fs::remove_dir_all(&dir).unwrap();
     }
 }